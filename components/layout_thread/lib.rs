@@ -696,11 +696,17 @@ impl LayoutThread {
                 self.handle_add_stylesheet(&stylesheet, &guard);
 
                 match before_stylesheet {
-                    Some(insertion_point) => self.stylist.insert_stylesheet_before(
-                        DocumentStyleSheet(stylesheet.clone()),
-                        DocumentStyleSheet(insertion_point),
-                        &guard,
-                    ),
+                    Some(insertion_point) => {
+                        let result = self.stylist.insert_stylesheet_before(
+                            DocumentStyleSheet(stylesheet.clone()),
+                            DocumentStyleSheet(insertion_point),
+                            &guard,
+                        );
+                        if let Err(error) = result {
+                            warn!("Couldn't insert stylesheet ({:?}), appending it", error);
+                            self.stylist.append_stylesheet(error.into_sheet(), &guard);
+                        }
+                    },
                     None => self
                         .stylist
                         .append_stylesheet(DocumentStyleSheet(stylesheet.clone()), &guard),
@@ -260,6 +260,10 @@ impl ::style::stylesheets::StylesheetInDocument for StyleSheetInDocument {
     fn rules<'a, 'b: 'a>(&'a self, guard: &'b SharedRwLockReadGuard) -> &'a [CssRule] {
         self.sheet.rules(guard)
     }
+
+    fn identity(&self) -> usize {
+        self.sheet.identity()
+    }
 }
 
 /// <https://dom.spec.whatwg.org/#document>
@@ -2955,7 +2959,11 @@ impl Document {
 
         match insertion_point {
             Some(ip) => {
-                stylesheets.insert_stylesheet_before(None, sheet, ip, &guard);
+                // Keep the sheet around even if the insertion point went away
+                // in the meantime, like layout does.
+                if let Err(error) = stylesheets.insert_stylesheet_before(None, sheet, ip, &guard) {
+                    stylesheets.append_stylesheet(None, error.into_sheet(), &guard);
+                }
             },
             None => {
                 stylesheets.append_stylesheet(None, sheet, &guard);
@@ -60,18 +60,22 @@ where
         E: TElement,
         S: ToMediaListKey,
     {
-        let flusher = self
+        let mut flusher = self
             .stylesheets
             .flush::<E>(/* host = */ None, /* snapshot_map = */ None);
 
-        if flusher.sheets.dirty() {
+        if !flusher.dirty_origins().is_empty() {
             self.quirks_mode = quirks_mode;
         }
 
-        // Ignore OOM.
-        let _ = self
+        // Ignore OOM, the sheets are flushed again next time.
+        if self
             .data
-            .rebuild(device, quirks_mode, flusher.sheets, guard);
+            .rebuild(device, quirks_mode, flusher.sheets(), guard)
+            .is_ok()
+        {
+            flusher.complete();
+        }
     }
 }
 
@@ -128,6 +128,11 @@ impl StylesheetInDocument for GeckoStyleSheet {
     fn rules<'a, 'b: 'a>(&'a self, guard: &'b SharedRwLockReadGuard) -> &'a [CssRule] {
         self.contents().rules(guard)
     }
+
+    #[inline]
+    fn identity(&self) -> usize {
+        self.0 as usize
+    }
 }
 
 /// The container for data that a Servo-backed Gecko document needs to style
@@ -7,19 +7,26 @@
 
 #![deny(unsafe_code)]
 
+use crate::data::ElementStyles;
 use crate::dom::{TDocument, TElement, TNode};
+use crate::hash::FxHashSet;
 use crate::invalidation::element::element_wrapper::{ElementSnapshot, ElementWrapper};
 use crate::invalidation::element::restyle_hints::RestyleHint;
 use crate::media_queries::Device;
+use crate::properties::ComputedValues;
 use crate::selector_parser::{SelectorImpl, Snapshot, SnapshotMap};
 use crate::shared_lock::SharedRwLockReadGuard;
 use crate::stylesheets::{CssRule, StylesheetInDocument};
 use crate::Atom;
 use crate::CaseSensitivityExt;
 use crate::LocalName as SelectorLocalName;
-use fxhash::FxHashSet;
-use selectors::attr::CaseSensitivity;
+use crate::Namespace;
+use atomic_refcell::{AtomicRef, AtomicRefCell, AtomicRefMut};
+use selectors::attr::{AttrSelectorOperation, CaseSensitivity, NamespaceConstraint};
 use selectors::parser::{Component, LocalName, Selector};
+use servo_arc::Arc;
+use std::borrow::Borrow;
+use std::hash::Hash;
 
 /// A style sheet invalidation represents a kind of element or subtree that may
 /// need to be restyled. Whether it represents a whole subtree or just a single
@@ -27,14 +34,28 @@ use selectors::parser::{Component, LocalName, Selector};
 /// StylesheetInvalidationSet's invalid_scopes or invalid_elements table.
 #[derive(Debug, Eq, Hash, MallocSizeOf, PartialEq)]
 enum Invalidation {
+    /// The root element of the document.
+    Root,
+    /// The shadow host the invalidations are flushed for, from `:host` rules.
+    Host,
+    /// An element assigned to a slot, and matching the given invalidation if
+    /// any, from `::slotted()` rules.
+    Slotted(Option<Box<Invalidation>>),
     /// An element with a given id.
     ID(Atom),
     /// An element with a given class name.
     Class(Atom),
-    /// An element with a given local name.
+    /// An element with a given local name, and in a given namespace if the
+    /// selector constrained it.
     LocalName {
         name: SelectorLocalName,
         lower_name: SelectorLocalName,
+        namespace: Option<Namespace>,
+    },
+    /// An element with a given attribute, in any namespace.
+    Attribute {
+        name: SelectorLocalName,
+        lower_name: SelectorLocalName,
     },
 }
 
@@ -43,20 +64,60 @@ impl Invalidation {
         matches!(*self, Invalidation::ID(..))
     }
 
+    /// Whether this invalidation matches a single element, in which case
+    /// restyling its subtree is as expensive as restyling everything.
+    fn is_root_or_host(&self) -> bool {
+        matches!(*self, Invalidation::Root | Invalidation::Host)
+    }
+
     fn is_id_or_class(&self) -> bool {
         matches!(*self, Invalidation::ID(..) | Invalidation::Class(..))
     }
 
+    fn is_local_name(&self) -> bool {
+        matches!(*self, Invalidation::LocalName { .. })
+    }
+
+    fn has_attribute<E>(
+        element: &E,
+        name: &SelectorLocalName,
+        lower_name: &SelectorLocalName,
+    ) -> bool
+    where
+        E: selectors::Element<Impl = SelectorImpl>,
+    {
+        let namespace = NamespaceConstraint::Any;
+        let operation = AttrSelectorOperation::Exists;
+        element.attr_matches(&namespace, name, &operation) ||
+            element.attr_matches(&namespace, lower_name, &operation)
+    }
+
     fn matches<E>(
         &self,
         element: E,
-        snapshot: Option<&Snapshot>,
+        host: Option<E>,
+        element_wrapper: Option<&ElementWrapper<E>>,
         case_sensitivity: CaseSensitivity,
     ) -> bool
     where
         E: TElement,
     {
+        let snapshot: Option<&Snapshot> = element_wrapper.and_then(|e| e.snapshot());
         match *self {
+            Invalidation::Root => {
+                return element.is_root();
+            },
+            Invalidation::Host => {
+                return host == Some(element);
+            },
+            Invalidation::Slotted(ref inner) => {
+                if element.assigned_slot().is_none() {
+                    return false;
+                }
+                return inner.as_ref().map_or(true, |inner| {
+                    inner.matches(element, host, element_wrapper, case_sensitivity)
+                });
+            },
             Invalidation::Class(ref class) => {
                 if element.has_class(class, case_sensitivity) {
                     return true;
@@ -86,19 +147,120 @@ impl Invalidation {
             Invalidation::LocalName {
                 ref name,
                 ref lower_name,
+                ref namespace,
             } => {
+                // Local names and namespaces can't change, so there's no need
+                // to look at the snapshot.
+                if let Some(ref namespace) = *namespace {
+                    if element.namespace() != namespace.borrow() {
+                        return false;
+                    }
+                }
+
                 // This could look at the quirks mode of the document, instead
                 // of testing against both names, but it's probably not worth
                 // it.
                 let local_name = element.local_name();
                 return *local_name == **name || *local_name == **lower_name;
             },
+            Invalidation::Attribute {
+                ref name,
+                ref lower_name,
+            } => {
+                if Self::has_attribute(&element, name, lower_name) {
+                    return true;
+                }
+
+                // The wrapper looks at the attributes the element had before
+                // any pending attribute change.
+                if let Some(wrapper) = element_wrapper {
+                    if Self::has_attribute(wrapper, name, lower_name) {
+                        return true;
+                    }
+                }
+            },
         }
 
         false
     }
 }
 
+/// The default maximum number of scoped invalidations a
+/// `StylesheetInvalidationSet` holds before giving up and restyling the whole
+/// document.
+///
+/// Past this point, matching every element against the invalidations at flush
+/// time is likely to be slower than just restyling everything.
+pub const DEFAULT_MAX_SCOPED_INVALIDATIONS: usize = 10000;
+
+/// The maximum capacity of each of the buffers of a
+/// `StylesheetInvalidationSet` that is kept around when clearing it.
+///
+/// Pages that keep mutating their stylesheets tend to collect a similar
+/// amount of invalidations between flushes, so reusing the buffers avoids
+/// allocating them again each time, but a one-off burst of invalidations
+/// shouldn't keep a large allocation alive.
+const MAX_RETAINED_CAPACITY: usize = 256;
+
+/// Empties `set`, keeping its buffer for reuse unless it's larger than
+/// `MAX_RETAINED_CAPACITY`.
+fn clear_for_reuse<T>(set: &mut FxHashSet<T>)
+where
+    T: Eq + Hash,
+{
+    if set.capacity() > MAX_RETAINED_CAPACITY {
+        *set = FxHashSet::default();
+    } else {
+        set.clear();
+    }
+}
+
+/// The reason why a `StylesheetInvalidationSet` restyles the whole document,
+/// instead of a set of elements and subtrees.
+#[derive(Clone, Copy, Debug, Eq, MallocSizeOf, PartialEq)]
+pub enum FullInvalidationReason {
+    /// Someone called `invalidate_fully`.
+    Explicit,
+    /// A selector had no ID, class, attribute or local name we could restyle
+    /// based on.
+    Selector,
+    /// A rule could affect any element, like a rule we don't analyze.
+    Rule,
+    /// We failed to allocate memory for a scoped invalidation.
+    OutOfMemory,
+    /// There were more scoped invalidations than the configured maximum.
+    TooManyInvalidations,
+}
+
+/// Statistics about the elements invalidated when flushing a
+/// `StylesheetInvalidationSet`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct InvalidationStats {
+    /// The number of elements that were marked to restyle themselves.
+    pub elements_invalidated: usize,
+    /// The number of elements whose whole subtree was marked for restyling.
+    pub subtrees_invalidated: usize,
+    /// Whether the whole tree was marked for restyling, because the set was
+    /// fully invalid.
+    pub fully_invalidated: bool,
+}
+
+impl InvalidationStats {
+    /// Returns whether any element was invalidated.
+    #[inline]
+    pub fn had_invalidations(&self) -> bool {
+        self.fully_invalidated || self.elements_invalidated != 0 || self.subtrees_invalidated != 0
+    }
+
+    /// Adds the statistics of another invalidation pass to these.
+    #[inline]
+    pub fn accumulate(&mut self, other: InvalidationStats) {
+        self.elements_invalidated += other.elements_invalidated;
+        self.subtrees_invalidated += other.subtrees_invalidated;
+        self.fully_invalidated |= other.fully_invalidated;
+    }
+}
+
 /// A set of invalidations due to stylesheet additions.
 ///
 /// TODO(emilio): We might be able to do the same analysis for media query
@@ -109,8 +271,14 @@ pub struct StylesheetInvalidationSet {
     invalid_scopes: FxHashSet<Invalidation>,
     /// The elements we know we have to restyle so far.
     invalid_elements: FxHashSet<Invalidation>,
-    /// Whether the whole document should be restyled.
-    fully_invalid: bool,
+    /// The names of the `@keyframes` rules we've seen so far, whose elements
+    /// referencing them from `animation-name` need to be restyled.
+    invalid_animation_names: FxHashSet<Atom>,
+    /// Why the whole document should be restyled, if it should.
+    full_invalidation_reason: Option<FullInvalidationReason>,
+    /// The maximum number of scoped invalidations we keep, see
+    /// `DEFAULT_MAX_SCOPED_INVALIDATIONS`.
+    max_scoped_invalidations: usize,
 }
 
 impl StylesheetInvalidationSet {
@@ -119,16 +287,62 @@ impl StylesheetInvalidationSet {
         Self {
             invalid_scopes: FxHashSet::default(),
             invalid_elements: FxHashSet::default(),
-            fully_invalid: false,
+            invalid_animation_names: FxHashSet::default(),
+            full_invalidation_reason: None,
+            max_scoped_invalidations: DEFAULT_MAX_SCOPED_INVALIDATIONS,
         }
     }
 
+    /// Sets the maximum number of scoped invalidations this set holds before
+    /// it restyles the whole document instead.
+    ///
+    /// This doesn't affect the invalidations already collected until more are
+    /// added.
+    pub fn set_max_scoped_invalidations(&mut self, max: usize) {
+        self.max_scoped_invalidations = max;
+    }
+
+    /// Returns whether there are no invalidations in this set.
+    pub fn is_empty(&self) -> bool {
+        !self.is_fully_invalid() &&
+            self.invalid_scopes.is_empty() &&
+            self.invalid_elements.is_empty() &&
+            self.invalid_animation_names.is_empty()
+    }
+
+    /// Returns the number of scoped invalidations in this set, which is what
+    /// `max_scoped_invalidations` limits.
+    fn scoped_invalidation_count(&self) -> usize {
+        self.invalid_scopes.len() + self.invalid_elements.len() + self.invalid_animation_names.len()
+    }
+
+    /// Returns whether the whole document needs to be restyled.
+    pub fn is_fully_invalid(&self) -> bool {
+        self.full_invalidation_reason.is_some()
+    }
+
+    /// Returns why the whole document needs to be restyled, if it does.
+    pub fn full_invalidation_reason(&self) -> Option<FullInvalidationReason> {
+        self.full_invalidation_reason
+    }
+
     /// Mark the DOM tree styles' as fully invalid.
     pub fn invalidate_fully(&mut self) {
         debug!("StylesheetInvalidationSet::invalidate_fully");
-        self.invalid_scopes.clear();
-        self.invalid_elements.clear();
-        self.fully_invalid = true;
+        self.invalidate_fully_because(FullInvalidationReason::Explicit);
+    }
+
+    /// Mark the DOM tree styles' as fully invalid, and drop the scoped
+    /// invalidations, which are useless from now on.
+    ///
+    /// The first reason is kept if the set was already fully invalid.
+    fn invalidate_fully_because(&mut self, reason: FullInvalidationReason) {
+        clear_for_reuse(&mut self.invalid_scopes);
+        clear_for_reuse(&mut self.invalid_elements);
+        clear_for_reuse(&mut self.invalid_animation_names);
+        if self.full_invalidation_reason.is_none() {
+            self.full_invalidation_reason = Some(reason);
+        }
     }
 
     /// Analyze the given stylesheet, and collect invalidations from their
@@ -143,7 +357,7 @@ impl StylesheetInvalidationSet {
         S: StylesheetInDocument,
     {
         debug!("StylesheetInvalidationSet::collect_invalidations_for");
-        if self.fully_invalid {
+        if self.is_fully_invalid() {
             debug!(" > Fully invalid already");
             return;
         }
@@ -153,11 +367,29 @@ impl StylesheetInvalidationSet {
             return; // Nothing to do here.
         }
 
-        for rule in stylesheet.effective_rules(device, guard) {
+        // The rules of imported sheets are visited as part of the iteration,
+        // as long as the media list of the import matches. Skip the sheets
+        // we've already visited, so that import cycles don't make us loop
+        // forever. Most sheets have no imports, so the set is only created
+        // once we find one.
+        let mut visited_imports = None;
+        let mut iter = stylesheet.effective_rules(device, guard);
+        while let Some(rule) = iter.next() {
+            if let CssRule::Import(ref lock) = *rule {
+                let import_rule = lock.read_with(guard);
+                let identity = import_rule.stylesheet.identity();
+                let first_visit = identity != stylesheet.identity() &&
+                    visited_imports
+                        .get_or_insert_with(FxHashSet::default)
+                        .insert(identity);
+                if !first_visit {
+                    debug!(" > Skipping already visited import");
+                    iter.skip_children();
+                    continue;
+                }
+            }
             self.collect_invalidations_for_rule(rule, guard, device);
-            if self.fully_invalid {
-                self.invalid_scopes.clear();
-                self.invalid_elements.clear();
+            if self.is_fully_invalid() {
                 break;
             }
         }
@@ -170,14 +402,79 @@ impl StylesheetInvalidationSet {
             " > resulting self invalidations: {:?}",
             self.invalid_elements
         );
-        debug!(" > fully_invalid: {}", self.fully_invalid);
+        debug!(
+            " > resulting animation name invalidations: {:?}",
+            self.invalid_animation_names
+        );
+        debug!(" > full invalidation: {:?}", self.full_invalidation_reason);
+    }
+
+    /// Analyze the given stylesheet, which is being removed, and collect
+    /// invalidations from its rules.
+    ///
+    /// The elements that may have matched the rules of a removed sheet are the
+    /// same that would match them if the sheet was added, so this records the
+    /// same scoped invalidations as `collect_invalidations_for`, instead of
+    /// restyling the whole document.
+    pub fn collect_invalidations_for_removal<S>(
+        &mut self,
+        device: &Device,
+        stylesheet: &S,
+        guard: &SharedRwLockReadGuard,
+    ) where
+        S: StylesheetInDocument,
+    {
+        debug!("StylesheetInvalidationSet::collect_invalidations_for_removal");
+        self.collect_invalidations_for(device, stylesheet, guard)
+    }
+
+    /// Collect invalidations for `rule`, which was inserted into, removed
+    /// from, or changed in place in `stylesheet`.
+    pub fn rule_changed<S>(
+        &mut self,
+        device: &Device,
+        stylesheet: &S,
+        rule: &CssRule,
+        guard: &SharedRwLockReadGuard,
+    ) where
+        S: StylesheetInDocument,
+    {
+        debug!("StylesheetInvalidationSet::rule_changed");
+        if self.is_fully_invalid() {
+            debug!(" > Fully invalid already");
+            return;
+        }
+
+        if !stylesheet.enabled() || !stylesheet.is_effective_for_device(device, guard) {
+            debug!(" > Stylesheet was not effective");
+            return; // Nothing to do here.
+        }
+
+        match *rule {
+            CssRule::Document(..) |
+            CssRule::Import(..) |
+            CssRule::Media(..) |
+            CssRule::Supports(..) => {
+                // TODO: We could look at the effective nested rules instead.
+                debug!(" > Found rule with nested rules, marking the whole subtree invalid.");
+                self.invalidate_fully_because(FullInvalidationReason::Rule);
+            },
+            _ => self.collect_invalidations_for_rule(rule, guard, device),
+        }
     }
 
-    /// Clears the invalidation set, invalidating elements as needed if
-    /// `document_element` is provided.
+    /// Clears the invalidation set, invalidating elements as needed.
+    ///
+    /// If there's no `document_element` yet, the invalidation set is kept
+    /// around instead, so that the invalidations get processed once the
+    /// document gets a root element.
     ///
-    /// Returns true if any invalidations ocurred.
-    pub fn flush<E>(&mut self, document_element: Option<E>, snapshots: Option<&SnapshotMap>) -> bool
+    /// Returns statistics about the elements that were invalidated.
+    pub fn flush<E>(
+        &mut self,
+        document_element: Option<E>,
+        snapshots: Option<&SnapshotMap>,
+    ) -> InvalidationStats
     where
         E: TElement,
     {
@@ -186,22 +483,95 @@ impl StylesheetInvalidationSet {
             document_element,
             snapshots.is_some()
         );
-        let have_invalidations = match document_element {
-            Some(e) => self.process_invalidations(e, snapshots),
-            None => false,
+        let document_element = match document_element {
+            Some(e) => e,
+            None => return InvalidationStats::default(),
+        };
+        let stats = self.process_invalidations(document_element, None, snapshots);
+        self.clear();
+        stats
+    }
+
+    /// Clears the invalidation set, invalidating elements in the shadow tree of
+    /// `host` as needed if it's provided.
+    ///
+    /// This is meant to be used for the invalidations collected from the
+    /// stylesheets of a shadow root, which can only affect the host itself
+    /// (via `:host` rules), its shadow tree, and the elements slotted into it,
+    /// all of which are reached by traversing the flat tree from the host.
+    /// Thus, unlike `flush`, this doesn't restyle the rest of the document.
+    ///
+    /// Returns statistics about the elements that were invalidated.
+    pub fn flush_for_shadow_host<E>(
+        &mut self,
+        host: Option<E>,
+        snapshots: Option<&SnapshotMap>,
+    ) -> InvalidationStats
+    where
+        E: TElement,
+    {
+        debug!(
+            "StylesheetInvalidationSet::flush_for_shadow_host({:?}, snapshots: {})",
+            host,
+            snapshots.is_some()
+        );
+        let stats = match host {
+            Some(host) => {
+                // Invalidations from `:host` rules match the host itself, and
+                // the ones from `::slotted()` rules the elements assigned to
+                // its slots, which are part of its flat tree.
+                self.process_invalidations(host, Some(host), snapshots)
+            },
+            None => InvalidationStats::default(),
         };
         self.clear();
-        have_invalidations
+        stats
     }
 
     /// Clears the invalidation set without processing.
+    ///
+    /// This keeps the buffers around so that collecting invalidations again
+    /// doesn't need to allocate them, see `MAX_RETAINED_CAPACITY`.
     pub fn clear(&mut self) {
-        self.invalid_scopes.clear();
-        self.invalid_elements.clear();
-        self.fully_invalid = false;
+        clear_for_reuse(&mut self.invalid_scopes);
+        clear_for_reuse(&mut self.invalid_elements);
+        clear_for_reuse(&mut self.invalid_animation_names);
+        self.full_invalidation_reason = None;
+    }
+
+    /// Clears the invalidation set without processing, and releases the
+    /// memory of its buffers, for when memory is tight.
+    pub fn clear_and_release(&mut self) {
+        self.clear();
+        self.shrink_to_fit();
+    }
+
+    /// Returns the number of scoped invalidations the buffers of this set can
+    /// hold without growing.
+    pub fn capacity(&self) -> usize {
+        self.invalid_scopes.capacity() +
+            self.invalid_elements.capacity() +
+            self.invalid_animation_names.capacity()
     }
 
-    fn process_invalidations<E>(&self, element: E, snapshots: Option<&SnapshotMap>) -> bool
+    /// Releases the unused capacity of the buffers used to collect
+    /// invalidations, keeping the invalidations currently in them.
+    ///
+    /// `clear` keeps the buffers around, since they're usually reused on the
+    /// next stylesheet change, so this is only worth calling under memory
+    /// pressure.
+    pub fn shrink_to_fit(&mut self) {
+        self.invalid_scopes.shrink_to_fit();
+        self.invalid_elements.shrink_to_fit();
+        self.invalid_animation_names.shrink_to_fit();
+    }
+
+    fn process_invalidations<E>(
+        &self,
+        element: E,
+        host: Option<E>,
+        snapshots: Option<&SnapshotMap>,
+    ) -> InvalidationStats
     where
         E: TElement,
     {
@@ -210,22 +580,27 @@ impl StylesheetInvalidationSet {
             element, self.invalid_scopes, self.invalid_elements,
         );
 
+        let mut stats = InvalidationStats::default();
         {
             let mut data = match element.mutate_data() {
                 Some(data) => data,
-                None => return false,
+                None => return stats,
             };
 
-            if self.fully_invalid {
-                debug!("process_invalidations: fully_invalid({:?})", element);
+            if let Some(reason) = self.full_invalidation_reason {
+                debug!(
+                    "process_invalidations: fully_invalid({:?}, {:?})",
+                    element, reason
+                );
                 data.hint.insert(RestyleHint::restyle_subtree());
-                return true;
+                stats.fully_invalidated = true;
+                return stats;
             }
         }
 
-        if self.invalid_scopes.is_empty() && self.invalid_elements.is_empty() {
+        if self.is_empty() {
             debug!("process_invalidations: empty invalidation set");
-            return false;
+            return stats;
         }
 
         let case_sensitivity = element
@@ -233,20 +608,30 @@ impl StylesheetInvalidationSet {
             .owner_doc()
             .quirks_mode()
             .classes_and_ids_case_sensitivity();
-        self.process_invalidations_in_subtree(element, snapshots, case_sensitivity)
+        self.process_invalidations_in_subtree(
+            element,
+            host,
+            snapshots,
+            case_sensitivity,
+            &mut stats,
+        );
+        stats
     }
 
     /// Process style invalidations in a given subtree. This traverses the
     /// subtree looking for elements that match the invalidations in
     /// invalid_scopes and invalid_elements.
     ///
-    /// Returns whether it invalidated at least one element's style.
+    /// Returns whether it invalidated at least one element's style, and counts
+    /// the invalidated elements in `stats`.
     #[allow(unsafe_code)]
     fn process_invalidations_in_subtree<E>(
         &self,
         element: E,
+        host: Option<E>,
         snapshots: Option<&SnapshotMap>,
         case_sensitivity: CaseSensitivity,
+        stats: &mut InvalidationStats,
     ) -> bool
     where
         E: TElement,
@@ -270,14 +655,14 @@ impl StylesheetInvalidationSet {
         }
 
         let element_wrapper = snapshots.map(|s| ElementWrapper::new(element, s));
-        let snapshot = element_wrapper.as_ref().and_then(|e| e.snapshot());
         for invalidation in &self.invalid_scopes {
-            if invalidation.matches(element, snapshot, case_sensitivity) {
+            if invalidation.matches(element, host, element_wrapper.as_ref(), case_sensitivity) {
                 debug!(
                     "process_invalidations_in_subtree: {:?} matched subtree {:?}",
                     element, invalidation
                 );
                 data.hint.insert(RestyleHint::restyle_subtree());
+                stats.subtrees_invalidated += 1;
                 return true;
             }
         }
@@ -286,18 +671,32 @@ impl StylesheetInvalidationSet {
 
         if !data.hint.contains(RestyleHint::RESTYLE_SELF) {
             for invalidation in &self.invalid_elements {
-                if invalidation.matches(element, snapshot, case_sensitivity) {
+                if invalidation.matches(element, host, element_wrapper.as_ref(), case_sensitivity) {
                     debug!(
                         "process_invalidations_in_subtree: {:?} matched self {:?}",
                         element, invalidation
                     );
                     data.hint.insert(RestyleHint::RESTYLE_SELF);
+                    stats.elements_invalidated += 1;
                     self_invalid = true;
                     break;
                 }
             }
         }
 
+        if !self_invalid &&
+            !data.hint.contains(RestyleHint::RESTYLE_SELF) &&
+            self.references_invalid_animation_name(&data.styles)
+        {
+            debug!(
+                "process_invalidations_in_subtree: {:?} references an invalid animation name",
+                element
+            );
+            data.hint.insert(RestyleHint::RESTYLE_SELF);
+            stats.elements_invalidated += 1;
+            self_invalid = true;
+        }
+
         let mut any_children_invalid = false;
 
         for child in element.traversal_children() {
@@ -306,8 +705,13 @@ impl StylesheetInvalidationSet {
                 None => continue,
             };
 
-            any_children_invalid |=
-                self.process_invalidations_in_subtree(child, snapshots, case_sensitivity);
+            any_children_invalid |= self.process_invalidations_in_subtree(
+                child,
+                host,
+                snapshots,
+                case_sensitivity,
+                stats,
+            );
         }
 
         if any_children_invalid {
@@ -321,24 +725,95 @@ impl StylesheetInvalidationSet {
         return self_invalid || any_children_invalid;
     }
 
+    /// Returns whether the element with the given styles, or any of its eager
+    /// pseudo-elements, has an `animation-name` we collected from a
+    /// `@keyframes` rule.
+    ///
+    /// Animation names can only be found in computed styles, so unlike other
+    /// invalidations this doesn't look at the snapshot of the element.
+    fn references_invalid_animation_name(&self, styles: &ElementStyles) -> bool {
+        if self.invalid_animation_names.is_empty() {
+            return false;
+        }
+
+        let references = |style: &ComputedValues| {
+            style.get_box().animation_name_iter().any(|name| {
+                name.0.as_ref().map_or(false, |name| {
+                    self.invalid_animation_names.contains(name.as_atom())
+                })
+            })
+        };
+
+        references(styles.primary()) ||
+            styles
+                .pseudos
+                .as_array()
+                .iter()
+                .any(|style| style.as_ref().map_or(false, |style| references(style)))
+    }
+
     fn scan_component(
         component: &Component<SelectorImpl>,
+        namespace: &mut Option<Namespace>,
         invalidation: &mut Option<Invalidation>,
     ) {
         match *component {
+            Component::DefaultNamespace(ref url) | Component::Namespace(_, ref url) => {
+                *namespace = Some(url.clone());
+            },
+            Component::ExplicitNoNamespace => {
+                *namespace = Some(Namespace::default());
+            },
             Component::LocalName(LocalName {
                 ref name,
                 ref lower_name,
             }) => {
-                if invalidation.as_ref().map_or(true, |s| !s.is_id_or_class()) {
+                if invalidation.as_ref().map_or(true, |s| s.is_local_name()) {
                     *invalidation = Some(Invalidation::LocalName {
                         name: name.clone(),
                         lower_name: lower_name.clone(),
+                        namespace: namespace.clone(),
                     });
                 }
             },
+            Component::AttributeInNoNamespaceExists {
+                ref local_name,
+                ref local_name_lower,
+            } => {
+                Self::scan_attribute(local_name, local_name_lower, invalidation);
+            },
+            Component::AttributeInNoNamespace { ref local_name, .. } => {
+                Self::scan_attribute(local_name, local_name, invalidation);
+            },
+            Component::AttributeOther(ref attr_selector) => {
+                Self::scan_attribute(
+                    &attr_selector.local_name,
+                    &attr_selector.local_name_lower,
+                    invalidation,
+                );
+            },
+            Component::Root => {
+                // The root element is the most specific thing we can find.
+                *invalidation = Some(Invalidation::Root);
+            },
+            Component::Host(..) => {
+                // `:host` is featureless, so whatever it's given as an argument
+                // can only match the shadow host.
+                *invalidation = Some(Invalidation::Host);
+            },
+            Component::Slotted(ref selector) => {
+                let mut namespace = None;
+                let mut inner = None;
+                for component in selector.iter() {
+                    Self::scan_component(component, &mut namespace, &mut inner);
+                }
+                *invalidation = Some(Invalidation::Slotted(inner.map(Box::new)));
+            },
             Component::Class(ref class) => {
-                if invalidation.as_ref().map_or(true, |s| !s.is_id()) {
+                if invalidation
+                    .as_ref()
+                    .map_or(true, |s| !s.is_id() && !s.is_root_or_host())
+                {
                     *invalidation = Some(Invalidation::Class(class.clone()));
                 }
             },
@@ -353,6 +828,19 @@ impl StylesheetInvalidationSet {
         }
     }
 
+    fn scan_attribute(
+        name: &SelectorLocalName,
+        lower_name: &SelectorLocalName,
+        invalidation: &mut Option<Invalidation>,
+    ) {
+        if invalidation.as_ref().map_or(true, |s| s.is_local_name()) {
+            *invalidation = Some(Invalidation::Attribute {
+                name: name.clone(),
+                lower_name: lower_name.clone(),
+            });
+        }
+    }
+
     /// Collect invalidations for a given selector.
     ///
     /// We look at the outermost local name, class, or ID selector to the left
@@ -362,8 +850,9 @@ impl StylesheetInvalidationSet {
     /// the only sequence it has, but record it as an element invalidation
     /// instead of a subtree invalidation.
     ///
-    /// We prefer IDs to classs, and classes to local names, on the basis
-    /// that the former should be more specific than the latter. We also
+    /// We prefer `:root` to IDs, IDs to classs, classes to attribute names,
+    /// and attribute names to local names, on the basis that the former
+    /// should be more specific than the latter. We also
     /// prefer to generate subtree invalidations for the outermost part
     /// of the selector, to reduce the amount of traversal we need to do
     /// when flushing invalidations.
@@ -382,33 +871,68 @@ impl StylesheetInvalidationSet {
         let mut iter = selector.iter();
 
         loop {
+            // Namespace components precede the local name of their compound
+            // selector, so this is all we need to scope it.
+            let mut namespace = None;
             for component in &mut iter {
                 if scan_for_element_invalidation {
-                    Self::scan_component(component, &mut element_invalidation);
+                    Self::scan_component(component, &mut namespace, &mut element_invalidation);
                 } else if scan_for_subtree_invalidation {
-                    Self::scan_component(component, &mut subtree_invalidation);
+                    Self::scan_component(component, &mut namespace, &mut subtree_invalidation);
                 }
             }
             match iter.next_sequence() {
                 None => break,
                 Some(combinator) => {
+                    // Pseudo-elements are restyled along with their
+                    // originating element, so restyling that element is
+                    // enough, and there's no need to restyle its subtree.
+                    if scan_for_element_invalidation && combinator.is_pseudo_element() {
+                        continue;
+                    }
                     scan_for_subtree_invalidation = combinator.is_ancestor();
                 },
             }
             scan_for_element_invalidation = false;
         }
 
+        // Restyling the subtree of the root element or the shadow host is as
+        // expensive as a full invalidation, so if we have something better for
+        // the subject of the selector, like for `:root .foo`, use that
+        // instead.
+        if subtree_invalidation
+            .as_ref()
+            .map_or(false, |s| s.is_root_or_host()) &&
+            element_invalidation.is_some()
+        {
+            subtree_invalidation = None;
+        }
+
+        // If we fail to grow the invalidation set, degrade to a full
+        // invalidation instead.
         if let Some(s) = subtree_invalidation {
             debug!(" > Found subtree invalidation: {:?}", s);
-            self.invalid_scopes.insert(s);
+            if self.invalid_scopes.try_insert(s).is_err() {
+                debug!(" > OOM, marking fully invalid");
+                self.invalidate_fully_because(FullInvalidationReason::OutOfMemory);
+            }
         } else if let Some(s) = element_invalidation {
             debug!(" > Found element invalidation: {:?}", s);
-            self.invalid_elements.insert(s);
+            if self.invalid_elements.try_insert(s).is_err() {
+                debug!(" > OOM, marking fully invalid");
+                self.invalidate_fully_because(FullInvalidationReason::OutOfMemory);
+            }
         } else {
             // The selector was of a form that we can't handle. Any element
             // could match it, so let's just bail out.
             debug!(" > Can't handle selector, marking fully invalid");
-            self.fully_invalid = true;
+            self.invalidate_fully_because(FullInvalidationReason::Selector);
+            return;
+        }
+
+        if self.scoped_invalidation_count() > self.max_scoped_invalidations {
+            debug!(" > Too many invalidations, marking fully invalid");
+            self.invalidate_fully_because(FullInvalidationReason::TooManyInvalidations);
         }
     }
 
@@ -421,14 +945,14 @@ impl StylesheetInvalidationSet {
     ) {
         use crate::stylesheets::CssRule::*;
         debug!("StylesheetInvalidationSet::collect_invalidations_for_rule");
-        debug_assert!(!self.fully_invalid, "Not worth to be here!");
+        debug_assert!(!self.is_fully_invalid(), "Not worth to be here!");
 
         match *rule {
             Style(ref lock) => {
                 let style_rule = lock.read_with(guard);
                 for selector in &style_rule.selectors.0 {
                     self.collect_invalidations(selector);
-                    if self.fully_invalid {
+                    if self.is_fully_invalid() {
                         return;
                     }
                 }
@@ -445,11 +969,21 @@ impl StylesheetInvalidationSet {
             Keyframes(ref lock) => {
                 let keyframes_rule = lock.read_with(guard);
                 if device.animation_name_may_be_referenced(&keyframes_rule.name) {
+                    // Only the elements whose animation-name references the
+                    // rule can be affected by it, and we can find them by
+                    // looking at their computed styles when flushing.
                     debug!(
                         " > Found @keyframes rule potentially referenced \
-                         from the page, marking the whole tree invalid."
+                         from the page, invalidating its animation name."
                     );
-                    self.fully_invalid = true;
+                    let name = keyframes_rule.name.as_atom().clone();
+                    if self.invalid_animation_names.try_insert(name).is_err() {
+                        debug!(" > OOM, marking fully invalid");
+                        self.invalidate_fully_because(FullInvalidationReason::OutOfMemory);
+                    } else if self.scoped_invalidation_count() > self.max_scoped_invalidations {
+                        debug!(" > Too many invalidations, marking fully invalid");
+                        self.invalidate_fully_because(FullInvalidationReason::TooManyInvalidations);
+                    }
                 } else {
                     // Do nothing, this animation can't affect the style of
                     // existing elements.
@@ -465,8 +999,36 @@ impl StylesheetInvalidationSet {
                 //
                 // At least in `@page`, we could check the relevant media, I
                 // guess.
-                self.fully_invalid = true;
+                self.invalidate_fully_because(FullInvalidationReason::Rule);
             },
         }
     }
 }
+
+/// A `StylesheetInvalidationSet` shared by several stylesheet sets, like the
+/// ones of a document and its shadow roots, so that their invalidations are
+/// coalesced and processed by a single flush.
+///
+/// Like the stylesheet sets themselves, this is only meant to be mutated from
+/// a single thread at a time.
+#[derive(Clone)]
+pub struct SharedStylesheetInvalidationSet(Arc<AtomicRefCell<StylesheetInvalidationSet>>);
+
+impl SharedStylesheetInvalidationSet {
+    /// Create an empty shared invalidation set.
+    pub fn new() -> Self {
+        SharedStylesheetInvalidationSet(Arc::new(AtomicRefCell::new(
+            StylesheetInvalidationSet::new(),
+        )))
+    }
+
+    /// Immutably borrows the invalidation set.
+    pub fn borrow(&self) -> AtomicRef<StylesheetInvalidationSet> {
+        self.0.borrow()
+    }
+
+    /// Mutably borrows the invalidation set.
+    pub fn borrow_mut(&self) -> AtomicRefMut<StylesheetInvalidationSet> {
+        self.0.borrow_mut()
+    }
+}
@@ -5,12 +5,25 @@
 //! A centralized set of stylesheets for a document.
 
 use crate::dom::TElement;
+use crate::hash::{FxHashMap, FxHashSet};
+use crate::invalidation::stylesheets::SharedStylesheetInvalidationSet;
 use crate::invalidation::stylesheets::StylesheetInvalidationSet;
+use crate::invalidation::stylesheets::{FullInvalidationReason, InvalidationStats};
 use crate::media_queries::Device;
 use crate::selector_parser::SnapshotMap;
-use crate::shared_lock::SharedRwLockReadGuard;
-use crate::stylesheets::{Origin, OriginSet, OriginSetIterator, PerOrigin, StylesheetInDocument};
-use std::{mem, slice};
+use crate::shared_lock::{SharedRwLockReadGuard, StylesheetGuards};
+use crate::stylesheets::{CssRule, Origin, OriginSet, OriginSetIterator, PerOrigin};
+use crate::stylesheets::{RuleChangeKind, StylesheetInDocument};
+use atomic_refcell::{AtomicRef, AtomicRefCell, AtomicRefMut};
+#[cfg(feature = "fallible_allocation")]
+use fallible::FallibleVec;
+use hashglobe::FailedAllocationError;
+use malloc_size_of::{MallocShallowSizeOf, MallocSizeOf, MallocSizeOfOps};
+use smallvec::SmallVec;
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "profiling")]
+use std::time::{Duration, Instant};
+use std::{cmp, mem, slice};
 
 /// Entry for a StylesheetSet.
 #[derive(MallocSizeOf)]
@@ -21,8 +34,31 @@ where
     /// The sheet.
     sheet: S,
 
+    /// The identity of the sheet, cached so that keeping the index of the
+    /// collection up to date doesn't need to call into the sheet.
+    key: usize,
+
     /// Whether this sheet has been part of at least one flush.
     committed: bool,
+
+    /// Whether this sheet has been disabled, in which case it keeps its
+    /// position, but is skipped by iteration and flushing.
+    disabled: bool,
+
+    /// Whether the sheet applied to the device as of the last flush, see
+    /// `DocumentStylesheetSet::update_applicable_sheets`.
+    ///
+    /// Sheets that don't apply are skipped by flushing, and thus don't get
+    /// committed, since they contribute nothing to the cascade.
+    applies: bool,
+
+    /// Whether the media list of the sheet matches the device of the set,
+    /// cached along with the device generation it was computed for.
+    ///
+    /// This is zero if unknown, and `(generation + 1) << 1 | matches`
+    /// otherwise. It's atomic so that it can be lazily populated through a
+    /// shared reference.
+    matches_device: AtomicUsize,
 }
 
 impl<S> StylesheetSetEntry<S>
@@ -31,23 +67,72 @@ where
 {
     fn new(sheet: S) -> Self {
         Self {
+            key: sheet.identity(),
             sheet,
             committed: false,
+            disabled: false,
+            applies: true,
+            matches_device: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns whether the media list of the sheet matches `device`, which is
+    /// assumed to be the device of the set as of `device_generation`.
+    fn matches_device(
+        &self,
+        device_generation: usize,
+        device: &Device,
+        guard: &SharedRwLockReadGuard,
+    ) -> bool {
+        let cached = self.matches_device.load(Ordering::Relaxed);
+        if cached >> 1 == device_generation.wrapping_add(1) {
+            return cached & 1 != 0;
         }
+        let matches = self.sheet.is_effective_for_device(device, guard);
+        self.cache_matches_device(device_generation, matches);
+        matches
+    }
+
+    fn cache_matches_device(&self, device_generation: usize, matches: bool) {
+        let cached = device_generation.wrapping_add(1) << 1 | matches as usize;
+        self.matches_device.store(cached, Ordering::Relaxed);
     }
 }
 
 /// A iterator over the stylesheets of a list of entries in the StylesheetSet.
-pub struct StylesheetCollectionIterator<'a, S>(slice::Iter<'a, StylesheetSetEntry<S>>)
+pub struct StylesheetCollectionIterator<'a, S>
+where
+    S: StylesheetInDocument + PartialEq + 'static,
+{
+    iter: slice::Iter<'a, StylesheetSetEntry<S>>,
+    /// The number of enabled sheets left to yield.
+    len: usize,
+}
+
+impl<'a, S> StylesheetCollectionIterator<'a, S>
 where
-    S: StylesheetInDocument + PartialEq + 'static;
+    S: StylesheetInDocument + PartialEq + 'static,
+{
+    /// Returns an iterator over no stylesheets, for an origin without a
+    /// collection.
+    fn empty() -> Self {
+        let entries: &'a [StylesheetSetEntry<S>] = &[];
+        StylesheetCollectionIterator {
+            iter: entries.iter(),
+            len: 0,
+        }
+    }
+}
 
 impl<'a, S> Clone for StylesheetCollectionIterator<'a, S>
 where
     S: StylesheetInDocument + PartialEq + 'static,
 {
     fn clone(&self) -> Self {
-        StylesheetCollectionIterator(self.0.clone())
+        StylesheetCollectionIterator {
+            iter: self.iter.clone(),
+            len: self.len,
+        }
     }
 }
 
@@ -58,23 +143,79 @@ where
     type Item = &'a S;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.0.next().map(|entry| &entry.sheet)
+        loop {
+            let entry = self.iter.next()?;
+            if !entry.disabled {
+                self.len -= 1;
+                return Some(&entry.sheet);
+            }
+        }
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        self.0.size_hint()
+        (self.len, Some(self.len))
+    }
+}
+
+impl<'a, S> DoubleEndedIterator for StylesheetCollectionIterator<'a, S>
+where
+    S: StylesheetInDocument + PartialEq + 'static,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = self.iter.next_back()?;
+            if !entry.disabled {
+                self.len -= 1;
+                return Some(&entry.sheet);
+            }
+        }
     }
 }
 
+impl<'a, S> ExactSizeIterator for StylesheetCollectionIterator<'a, S> where
+    S: StylesheetInDocument + PartialEq + 'static
+{
+}
+
 /// An iterator over the flattened view of the stylesheet collections.
 #[derive(Clone)]
 pub struct StylesheetIterator<'a, S>
 where
     S: StylesheetInDocument + PartialEq + 'static,
 {
-    origins: OriginSetIterator,
-    collections: &'a PerOrigin<SheetCollection<S>>,
-    current: Option<(Origin, StylesheetCollectionIterator<'a, S>)>,
+    /// The origins with enabled sheets, and an iterator over them, in order.
+    ///
+    /// These are computed upfront so that iterating doesn't need to look up
+    /// each origin, nor go through the empty ones. Only the origins in
+    /// `front..back` may have sheets left.
+    origins: [(Origin, StylesheetCollectionIterator<'a, S>); 3],
+    /// The index of the origin we're iterating from the front.
+    front: usize,
+    /// One past the index of the origin we're iterating from the back.
+    back: usize,
+}
+
+impl<'a, S> StylesheetIterator<'a, S>
+where
+    S: StylesheetInDocument + PartialEq + 'static,
+{
+    fn new(collections: &'a SheetCollections<S>, origins: OriginSet) -> Self {
+        let unused = || (Origin::UserAgent, StylesheetCollectionIterator::empty());
+        let mut iter = StylesheetIterator {
+            origins: [unused(), unused(), unused()],
+            front: 0,
+            back: 0,
+        };
+        for origin in origins.iter() {
+            let collection = match collections.get(origin) {
+                Some(collection) if collection.enabled_len() != 0 => collection,
+                _ => continue,
+            };
+            iter.origins[iter.back] = (origin, collection.iter());
+            iter.back += 1;
+        }
+        iter
+    }
 }
 
 impl<'a, S> Iterator for StylesheetIterator<'a, S>
@@ -84,30 +225,53 @@ where
     type Item = (&'a S, Origin);
 
     fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            if self.current.is_none() {
-                let next_origin = self.origins.next()?;
-
-                self.current = Some((
-                    next_origin,
-                    self.collections.borrow_for_origin(&next_origin).iter(),
-                ));
+        while self.front < self.back {
+            // If `front` meets `back`, both ends share the same iterator, so
+            // this doesn't yield the sheets `next_back` already did.
+            let (origin, ref mut iter) = self.origins[self.front];
+            if let Some(s) = iter.next() {
+                return Some((s, origin));
             }
+            self.front += 1;
+        }
+        None
+    }
 
-            {
-                let (origin, ref mut iter) = *self.current.as_mut().unwrap();
-                if let Some(s) = iter.next() {
-                    return Some((s, origin));
-                }
-            }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.origins[self.front..self.back]
+            .iter()
+            .map(|&(_, ref iter)| iter.len())
+            .sum();
+        (len, Some(len))
+    }
+}
 
-            self.current = None;
+impl<'a, S> DoubleEndedIterator for StylesheetIterator<'a, S>
+where
+    S: StylesheetInDocument + PartialEq + 'static,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while self.front < self.back {
+            let (origin, ref mut iter) = self.origins[self.back - 1];
+            if let Some(s) = iter.next_back() {
+                return Some((s, origin));
+            }
+            self.back -= 1;
         }
+        None
     }
 }
 
+impl<'a, S> ExactSizeIterator for StylesheetIterator<'a, S> where
+    S: StylesheetInDocument + PartialEq + 'static
+{
+}
+
 /// The validity of the data in a given cascade origin.
-#[derive(Clone, Copy, Debug, Eq, MallocSizeOf, Ord, PartialEq, PartialOrd)]
+///
+/// The cascade data and the invalidation data can be invalid independently of
+/// each other, so these aren't totally ordered: use `merge` to combine them.
+#[derive(Clone, Copy, Debug, Eq, MallocSizeOf, PartialEq)]
 pub enum DataValidity {
     /// The origin is clean, all the data already there is valid, though we may
     /// have new sheets at the end.
@@ -117,8 +281,76 @@ pub enum DataValidity {
     /// order-independent), and thus only the cascade data should be inserted.
     CascadeInvalid = 1,
 
+    /// The invalidation data is invalid, but not the cascade data, because the
+    /// order of the sheets didn't change, and thus only the invalidation data
+    /// should be inserted.
+    InvalidationInvalid = 2,
+
     /// Everything needs to be rebuilt.
-    FullyInvalid = 2,
+    FullyInvalid = 3,
+}
+
+impl DataValidity {
+    /// Whether some of the existing data is invalid, and thus needs to be
+    /// cleared before rebuilding it.
+    #[inline]
+    pub fn needs_clear(self) -> bool {
+        self != DataValidity::Valid
+    }
+
+    /// Returns the validity given whether the cascade data and the
+    /// invalidation data need to be rebuilt.
+    #[inline]
+    pub fn from_rebuilds(cascade: bool, invalidation: bool) -> DataValidity {
+        match (cascade, invalidation) {
+            (false, false) => DataValidity::Valid,
+            (true, false) => DataValidity::CascadeInvalid,
+            (false, true) => DataValidity::InvalidationInvalid,
+            (true, true) => DataValidity::FullyInvalid,
+        }
+    }
+
+    /// Returns the validity of data that has been invalidated both as `self`
+    /// and as `other` describe.
+    #[inline]
+    pub fn merge(self, other: DataValidity) -> DataValidity {
+        DataValidity::from_rebuilds(
+            self.needs_cascade_rebuild() || other.needs_cascade_rebuild(),
+            self.needs_invalidation_rebuild() || other.needs_invalidation_rebuild(),
+        )
+    }
+
+    /// Whether the cascade data of the sheets that were already flushed needs
+    /// to be rebuilt.
+    #[inline]
+    pub fn needs_cascade_rebuild(self) -> bool {
+        matches!(
+            self,
+            DataValidity::CascadeInvalid | DataValidity::FullyInvalid
+        )
+    }
+
+    /// Whether the invalidation data of the sheets that were already flushed
+    /// needs to be rebuilt.
+    #[inline]
+    pub fn needs_invalidation_rebuild(self) -> bool {
+        matches!(
+            self,
+            DataValidity::InvalidationInvalid | DataValidity::FullyInvalid
+        )
+    }
+
+    /// Returns how the data of a sheet that was already flushed needs to be
+    /// rebuilt, if at all.
+    #[inline]
+    fn rebuild_kind(self) -> Option<SheetRebuildKind> {
+        Some(match self {
+            DataValidity::Valid => return None,
+            DataValidity::CascadeInvalid => SheetRebuildKind::CascadeOnly,
+            DataValidity::InvalidationInvalid => SheetRebuildKind::InvalidationOnly,
+            DataValidity::FullyInvalid => SheetRebuildKind::Full,
+        })
+    }
 }
 
 impl Default for DataValidity {
@@ -128,27 +360,87 @@ impl Default for DataValidity {
 }
 
 /// A struct to iterate over the different stylesheets to be flushed.
+///
+/// Once the caller is done rebuilding its data from the sheets, it must call
+/// `complete`. If the flusher is dropped without that (because the caller ran
+/// out of memory, or gave up on the flush), the origins that were dirty, and
+/// the validity of their data, are restored on the set, so that the next flush
+/// redoes the work.
 pub struct DocumentStylesheetFlusher<'a, S>
 where
     S: StylesheetInDocument + PartialEq + 'static,
 {
-    collections: &'a mut PerOrigin<SheetCollection<S>>,
-    had_invalidations: bool,
+    collections: &'a mut SheetCollections<S>,
+    dirty_origins: &'a mut OriginSet,
+    /// The origins that are being flushed. The rest are reported as clean,
+    /// and left untouched.
+    origins: OriginSet,
+    /// The origins being flushed that were dirty when the flush started.
+    origins_dirty_at_start: OriginSet,
+    /// The origins whose sheets were handed out, which are committed all at
+    /// once by `complete`.
+    origins_to_commit: OriginSet,
+    completed: bool,
+    origin_data_validity: PerOrigin<DataValidity>,
+    removed_sheets: PerOrigin<Vec<S>>,
+    invalidation_stats: InvalidationStats,
+    #[cfg(feature = "profiling")]
+    timings: Option<FlushTimings>,
 }
 
-/// The type of rebuild that we need to do for a given stylesheet.
+/// The points in time at which the different phases of a
+/// `DocumentStylesheetSet::flush` finished, to attribute its cost between
+/// invalidation and the scan of the sheet collections.
+#[cfg(feature = "profiling")]
 #[derive(Clone, Copy, Debug)]
+pub struct FlushTimings {
+    /// When the flush started processing invalidations.
+    pub start: Instant,
+    /// When the invalidations were processed.
+    pub invalidated: Instant,
+    /// When the sheet collections were scanned, and the flusher was ready.
+    pub scanned: Instant,
+}
+
+#[cfg(feature = "profiling")]
+impl FlushTimings {
+    /// Returns how long processing the invalidations took.
+    pub fn invalidation_time(&self) -> Duration {
+        self.invalidated - self.start
+    }
+
+    /// Returns how long scanning the sheet collections took.
+    pub fn collection_scan_time(&self) -> Duration {
+        self.scanned - self.invalidated
+    }
+}
+
+/// The type of rebuild that we need to do for a given stylesheet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum SheetRebuildKind {
     /// A full rebuild, of both cascade data and invalidation data.
     Full,
     /// A partial rebuild, of only the cascade data.
     CascadeOnly,
+    /// A partial rebuild, of only the invalidation data.
+    InvalidationOnly,
 }
 
 impl SheetRebuildKind {
+    /// Whether the stylesheet cascade data should be rebuilt.
+    pub fn should_rebuild_cascade(&self) -> bool {
+        matches!(
+            *self,
+            SheetRebuildKind::Full | SheetRebuildKind::CascadeOnly
+        )
+    }
+
     /// Whether the stylesheet invalidation data should be rebuilt.
     pub fn should_rebuild_invalidation(&self) -> bool {
-        matches!(*self, SheetRebuildKind::Full)
+        matches!(
+            *self,
+            SheetRebuildKind::Full | SheetRebuildKind::InvalidationOnly
+        )
     }
 }
 
@@ -156,33 +448,226 @@ impl<'a, S> DocumentStylesheetFlusher<'a, S>
 where
     S: StylesheetInDocument + PartialEq + 'static,
 {
+    /// Returns the validity of the existing data for `origin`, as it was when
+    /// the flush started.
+    #[inline]
+    pub fn origin_data_validity(&self, origin: Origin) -> DataValidity {
+        *self.origin_data_validity.borrow_for_origin(&origin)
+    }
+
+    /// Returns whether the existing data for `origin` needs to be cleared
+    /// before rebuilding it, as opposed to just appending the data for the new
+    /// sheets to it.
+    #[inline]
+    pub fn origin_needs_clear(&self, origin: Origin) -> bool {
+        self.origin_data_validity(origin).needs_clear()
+    }
+
+    /// Returns the sheets that were removed from `origin` since the last
+    /// flush, in removal order.
+    ///
+    /// These are dropped along with the flusher.
+    #[inline]
+    pub fn removed_sheets(&self, origin: Origin) -> slice::Iter<S> {
+        self.removed_sheets.borrow_for_origin(&origin).iter()
+    }
+
+    /// Returns the origins that are dirty and haven't been flushed yet.
+    #[inline]
+    pub fn dirty_origins(&self) -> OriginSet {
+        *self.dirty_origins & self.origins
+    }
+
+    /// Returns the number of origins that are dirty and haven't been flushed
+    /// yet.
+    #[inline]
+    pub fn dirty_origin_count(&self) -> usize {
+        self.dirty_origins().bits().count_ones() as usize
+    }
+
+    /// Returns an iterator over the origins that are dirty and haven't been
+    /// flushed yet.
+    #[inline]
+    pub fn iter_dirty_origins(&self) -> OriginSetIterator {
+        self.dirty_origins().iter()
+    }
+
+    /// Leaves `origin` out of this flush, as if it was clean, so that its
+    /// sheets are flushed by the next flush instead.
+    ///
+    /// This must be called before flushing `origin`.
+    pub fn skip_origin(&mut self, origin: Origin) {
+        if !self.origins.contains(origin.into()) {
+            return;
+        }
+        debug_assert!(
+            !self.origins_dirty_at_start.contains(origin.into()) ||
+                self.dirty_origins.contains(origin.into()),
+            "Skipping an origin that was already flushed"
+        );
+        self.origins.remove(origin.into());
+        self.origins_dirty_at_start.remove(origin.into());
+        self.origins_to_commit.remove(origin.into());
+        *self.origin_data_validity.borrow_mut_for_origin(&origin) = DataValidity::Valid;
+        let removed = mem::replace(self.removed_sheets.borrow_mut_for_origin(&origin), vec![]);
+        if let Some(collection) = self.collections.get_mut(origin) {
+            debug_assert!(collection.removed.is_empty());
+            collection.removed = removed;
+        }
+    }
+
     /// Returns a flusher for `origin`.
+    ///
+    /// If `origin` isn't one of the origins being flushed, the flusher is
+    /// empty and not dirty. Otherwise, its sheets are committed by `complete`.
     pub fn flush_origin(&mut self, origin: Origin) -> SheetCollectionFlusher<S> {
-        self.collections.borrow_mut_for_origin(&origin).flush()
+        if !self.origins.contains(origin.into()) {
+            return SheetCollectionFlusher::empty();
+        }
+        self.dirty_origins.remove(origin.into());
+        self.origins_to_commit.insert(origin.into());
+        match self.collections.get_mut(origin) {
+            Some(collection) => collection.flush(),
+            None => SheetCollectionFlusher::empty(),
+        }
+    }
+
+    /// Returns the stylesheets of `origin` that need a full rebuild, flushing
+    /// the origin like `flush_origin`.
+    ///
+    /// See `SheetCollectionFlusher::full_rebuild_sheets`.
+    pub fn full_rebuild_sheets(&mut self, origin: Origin) -> impl Iterator<Item = &S> {
+        self.flush_origin(origin).full_rebuild_sheets()
     }
 
-    /// Returns the list of stylesheets for `origin`.
+    /// Returns the list of stylesheets for `origin`, for callers that rebuild
+    /// the data for the whole origin at once.
+    ///
+    /// The sheets are marked as committed by `complete`, like the ones
+    /// returned by `flush_origin`, so that removing one of them later
+    /// correctly invalidates the data built from it.
     ///
     /// Only used for UA sheets.
     pub fn origin_sheets(&mut self, origin: Origin) -> StylesheetCollectionIterator<S> {
-        self.collections.borrow_mut_for_origin(&origin).iter()
+        if !self.origins.contains(origin.into()) {
+            return StylesheetCollectionIterator::empty();
+        }
+        match self.collections.get(origin) {
+            Some(collection) => {
+                self.origins_to_commit.insert(origin.into());
+                collection.iter()
+            },
+            None => StylesheetCollectionIterator::empty(),
+        }
+    }
+
+    /// Returns the stylesheets of all the origins that haven't been flushed
+    /// yet, in cascade order, along with their origin and the kind of rebuild
+    /// they need.
+    ///
+    /// This is equivalent to calling `flush_origin` for each of the dirty
+    /// origins and chaining the results, so each origin is only flushed when
+    /// the iterator reaches it.
+    pub fn sheets<'b>(
+        &'b mut self,
+    ) -> impl Iterator<Item = (&'b S, Origin, SheetRebuildKind)> + 'b {
+        let dirty_origins = &mut *self.dirty_origins;
+        let origins_to_commit = &mut self.origins_to_commit;
+        let origins = self.origins;
+        self.collections
+            .iter_mut_origins()
+            .filter(move |&(ref collection, origin)| {
+                collection.dirty && origins.contains(origin.into())
+            })
+            .flat_map(move |(collection, origin)| {
+                dirty_origins.remove(origin.into());
+                origins_to_commit.insert(origin.into());
+                collection
+                    .flush()
+                    .map(move |(sheet, rebuild_kind)| (sheet, origin, rebuild_kind))
+            })
     }
 
     /// Returns whether any DOM invalidations were processed as a result of the
     /// stylesheet flush.
     #[inline]
     pub fn had_invalidations(&self) -> bool {
-        self.had_invalidations
+        self.invalidation_stats.had_invalidations()
+    }
+
+    /// Returns statistics about the DOM invalidations processed as a result of
+    /// the stylesheet flush.
+    #[inline]
+    pub fn invalidation_stats(&self) -> InvalidationStats {
+        self.invalidation_stats
+    }
+
+    /// Returns how long the different phases of the flush took, if this
+    /// flusher comes from `DocumentStylesheetSet::flush`.
+    #[cfg(feature = "profiling")]
+    #[inline]
+    pub fn timings(&self) -> Option<FlushTimings> {
+        self.timings
+    }
+
+    /// Finishes the flush, once the caller has rebuilt its data from the
+    /// sheets it got.
+    ///
+    /// The origins that weren't flushed stay dirty for the next flush.
+    pub fn complete(mut self) {
+        for origin in self.origins_to_commit.iter() {
+            if let Some(collection) = self.collections.get_mut(origin) {
+                collection.commit_all();
+            }
+        }
+        self.completed = true;
+    }
+}
+
+impl<'a, S> Drop for DocumentStylesheetFlusher<'a, S>
+where
+    S: StylesheetInDocument + PartialEq + 'static,
+{
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+
+        debug!("DocumentStylesheetFlusher dropped without completing the flush");
+        for origin in self.origins_dirty_at_start.iter() {
+            let removed = mem::replace(self.removed_sheets.borrow_mut_for_origin(&origin), vec![]);
+            if let Some(collection) = self.collections.get_mut(origin) {
+                collection.restore_after_abandoned_flush(
+                    *self.origin_data_validity.borrow_for_origin(&origin),
+                    removed,
+                );
+            }
+        }
+        *self.dirty_origins |= self.origins_dirty_at_start;
     }
 }
 
 /// A flusher struct for a given collection, that takes care of returning the
 /// appropriate stylesheets that need work.
+///
+/// Iterating the flusher doesn't commit anything: the sheets it yields are
+/// only marked as committed once the caller says it's done with them, via
+/// `sheet_done` or `finish`, or once the flush they're part of completes. That
+/// way, if the caller bails out in the middle of the flush (because of an
+/// allocation failure, for example), the sheets it didn't get to are yielded
+/// again by the next flush.
 pub struct SheetCollectionFlusher<'a, S>
 where
     S: StylesheetInDocument + PartialEq + 'static,
 {
     iter: slice::IterMut<'a, StylesheetSetEntry<S>>,
+    /// The commit flags of the uncommitted sheets yielded since the last call
+    /// to `sheet_done`.
+    ///
+    /// Callers usually call `sheet_done` after each sheet, so this doesn't
+    /// need to allocate.
+    pending_commit: SmallVec<[&'a mut bool; INLINE_SHEET_COUNT]>,
+    removed: Vec<S>,
     validity: DataValidity,
     dirty: bool,
 }
@@ -191,6 +676,19 @@ impl<'a, S> SheetCollectionFlusher<'a, S>
 where
     S: StylesheetInDocument + PartialEq + 'static,
 {
+    /// Returns a flusher for an origin without a collection, which is never
+    /// dirty.
+    fn empty() -> Self {
+        let entries: &'a mut [StylesheetSetEntry<S>] = &mut [];
+        SheetCollectionFlusher {
+            iter: entries.iter_mut(),
+            pending_commit: SmallVec::new(),
+            removed: vec![],
+            validity: DataValidity::Valid,
+            dirty: false,
+        }
+    }
+
     /// Whether the collection was originally dirty.
     #[inline]
     pub fn dirty(&self) -> bool {
@@ -202,6 +700,50 @@ where
     pub fn data_validity(&self) -> DataValidity {
         self.validity
     }
+
+    /// Returns the sheets that were removed from the collection since the last
+    /// flush, in removal order.
+    #[inline]
+    pub fn removed_sheets(&self) -> slice::Iter<S> {
+        self.removed.iter()
+    }
+
+    /// Whether the existing data needs to be cleared before rebuilding it.
+    ///
+    /// If this returns false, the sheets yielded by this flusher are all new,
+    /// and their data can be appended to the existing one.
+    #[inline]
+    pub fn needs_clear(&self) -> bool {
+        self.dirty && self.validity.needs_clear()
+    }
+
+    /// Returns the sheets that need a full rebuild, that is, the new ones, or
+    /// all of them if the data is fully invalid.
+    ///
+    /// The sheets are committed when the flush this flusher is part of
+    /// completes.
+    pub fn full_rebuild_sheets(self) -> impl Iterator<Item = &'a S> {
+        self.filter(|&(_, rebuild_kind)| rebuild_kind == SheetRebuildKind::Full)
+            .map(|(sheet, _)| sheet)
+    }
+
+    /// Marks the sheets yielded so far as committed, once the caller is done
+    /// with them, so that an abandoned flush doesn't yield them again for a
+    /// full rebuild.
+    pub fn sheet_done(&mut self) {
+        for committed in self.pending_commit.drain(..) {
+            *committed = true;
+        }
+    }
+
+    /// Finishes flushing the collection, committing all the sheets yielded by
+    /// this flusher.
+    ///
+    /// Dropping the flusher instead leaves the sheets that weren't passed to
+    /// `sheet_done` uncommitted.
+    pub fn finish(mut self) {
+        self.sheet_done();
+    }
 }
 
 impl<'a, S> Iterator for SheetCollectionFlusher<'a, S>
@@ -211,29 +753,47 @@ where
     type Item = (&'a S, SheetRebuildKind);
 
     fn next(&mut self) -> Option<Self::Item> {
-        use std::mem;
-
         loop {
             let potential_sheet = self.iter.next()?;
+            if potential_sheet.disabled || !potential_sheet.applies {
+                continue;
+            }
 
-            let committed = mem::replace(&mut potential_sheet.committed, true);
-            if !committed {
+            let StylesheetSetEntry {
+                ref sheet,
+                ref mut committed,
+                ..
+            } = *potential_sheet;
+            if !*committed {
                 // If the sheet was uncommitted, we need to do a full rebuild
                 // anyway.
-                return Some((&potential_sheet.sheet, SheetRebuildKind::Full));
+                self.pending_commit.push(committed);
+                return Some((sheet, SheetRebuildKind::Full));
             }
 
-            let rebuild_kind = match self.validity {
-                DataValidity::Valid => continue,
-                DataValidity::CascadeInvalid => SheetRebuildKind::CascadeOnly,
-                DataValidity::FullyInvalid => SheetRebuildKind::Full,
-            };
-
-            return Some((&potential_sheet.sheet, rebuild_kind));
+            match self.validity.rebuild_kind() {
+                Some(rebuild_kind) => return Some((sheet, rebuild_kind)),
+                None => continue,
+            }
         }
     }
 }
 
+/// The number of stylesheets a `SheetCollection` stores without allocating.
+///
+/// Most documents have no user or user agent sheets added on top of the
+/// default ones, a handful of author sheets, and most shadow roots have one or
+/// two sheets, so this is kept small, since every collection pays for it.
+const INLINE_SHEET_COUNT: usize = 2;
+
+/// How many times larger than its length the capacity of a collection can get
+/// before removing sheets from it gives the memory back.
+///
+/// Pages that inject lots of `<style>` elements and remove them all at once
+/// (like some CSS-in-JS libraries do on navigation) would otherwise keep the
+/// peak capacity around for the lifetime of the document.
+const SHRINK_THRESHOLD_FACTOR: usize = 4;
+
 #[derive(MallocSizeOf)]
 struct SheetCollection<S>
 where
@@ -243,7 +803,22 @@ where
     ///
     /// This is only a list of top-level stylesheets, and as such it doesn't
     /// include recursive `@import` rules.
-    entries: Vec<StylesheetSetEntry<S>>,
+    entries: SmallVec<[StylesheetSetEntry<S>; INLINE_SHEET_COUNT]>,
+
+    /// A map from the identity of each sheet in `entries` to its index, so
+    /// that looking up a sheet doesn't need to scan the whole list.
+    ///
+    /// This needs to be kept in sync with `entries` on every mutation.
+    index: FxHashMap<usize, usize>,
+
+    /// The number of disabled entries in `entries`.
+    disabled_count: usize,
+
+    /// The sheets removed from the collection since the last flush, in
+    /// removal order.
+    ///
+    /// Sheets removed via `clear` are returned to the caller instead.
+    removed: Vec<S>,
 
     /// The validity of the data that was already there for a given origin.
     ///
@@ -257,6 +832,16 @@ where
     /// different from `data_validity`, in the sense that after a sheet append,
     /// the data validity is still `Valid`, but we need to be marked as dirty.
     dirty: bool,
+
+    /// Whether the collection changed since the last flush in other ways than
+    /// appending sheets, or removing sheets that hadn't been flushed yet.
+    changed_since_flush: bool,
+
+    /// The number of times the collection has been marked as dirty.
+    ///
+    /// Unlike `dirty`, this isn't reset on flush, so it can be used to tell
+    /// whether the collection changed since an arbitrary point in time.
+    generation: u64,
 }
 
 impl<S> Default for SheetCollection<S>
@@ -265,9 +850,14 @@ where
 {
     fn default() -> Self {
         Self {
-            entries: vec![],
+            entries: SmallVec::new(),
+            index: FxHashMap::default(),
+            disabled_count: 0,
+            removed: vec![],
             data_validity: DataValidity::Valid,
             dirty: false,
+            changed_since_flush: false,
+            generation: 0,
         }
     }
 }
@@ -281,18 +871,126 @@ where
         self.entries.len()
     }
 
+    /// Measures the heap usage of the collection, including the unused
+    /// capacity of its lists, but not the sheets themselves, which are
+    /// measured by their owners.
+    ///
+    /// Entries stored inline are part of the collection, and thus not
+    /// counted here.
+    fn size_of_excluding_sheets(&self, ops: &mut MallocSizeOfOps) -> usize {
+        self.entries.shallow_size_of(ops) +
+            self.index.shallow_size_of(ops) +
+            self.removed.shallow_size_of(ops)
+    }
+
+    /// Returns the number of enabled stylesheets in the set.
+    fn enabled_len(&self) -> usize {
+        self.entries.len() - self.disabled_count
+    }
+
+    /// Returns the number of stylesheets that have been part of a flush.
+    fn committed_count(&self) -> usize {
+        self.entries.iter().filter(|entry| entry.committed).count()
+    }
+
     /// Returns the `index`th stylesheet in the set if present.
     fn get(&self, index: usize) -> Option<&S> {
         self.entries.get(index).map(|e| &e.sheet)
     }
 
-    fn remove(&mut self, sheet: &S) {
-        let index = self.entries.iter().position(|entry| entry.sheet == *sheet);
-        if cfg!(feature = "gecko") && index.is_none() {
-            // FIXME(emilio): Make Gecko's PresShell::AddUserSheet not suck.
+    /// Returns the first stylesheet in the set, if any.
+    fn first(&self) -> Option<&S> {
+        self.entries.first().map(|e| &e.sheet)
+    }
+
+    /// Returns the last stylesheet in the set, if any.
+    fn last(&self) -> Option<&S> {
+        self.entries.last().map(|e| &e.sheet)
+    }
+
+    /// Returns the stylesheet whose identity is `key`, if present.
+    fn find_by_key(&self, key: usize) -> Option<&S> {
+        let index = *self.index.get(&key)?;
+        Some(&self.entries[index].sheet)
+    }
+
+    /// Returns the index of `sheet` in the collection, if present.
+    fn index_of(&self, sheet: &S) -> Option<usize> {
+        let index = *self.index.get(&sheet.identity())?;
+        debug_assert!(self.entries[index].sheet == *sheet);
+        Some(index)
+    }
+
+    /// Updates the index of all the entries from `start` onwards, after they
+    /// have been shifted by an insertion or removal.
+    ///
+    /// This doesn't call into the sheets, and thus can't panic.
+    fn reindex_from(&mut self, start: usize) {
+        for (i, entry) in self.entries.iter().enumerate().skip(start) {
+            self.index.insert(entry.key, i);
+        }
+    }
+
+    /// Returns whether the index of the collection is in sync with the list
+    /// of entries.
+    fn is_consistent(&self) -> bool {
+        self.index.len() == self.entries.len() &&
+            self.entries
+                .iter()
+                .enumerate()
+                .all(|(i, entry)| self.index.get(&entry.key) == Some(&i)) &&
+            self.entries.iter().filter(|entry| entry.disabled).count() == self.disabled_count
+    }
+
+    /// Asserts that the bookkeeping of the collection is in sync with its
+    /// entries, which also means that no sheet is in it twice.
+    ///
+    /// This is checked after every mutation of the sets in debug builds, so
+    /// the checks that need to go through all the entries are skipped for
+    /// large collections, which would otherwise make mutating them quadratic.
+    #[cfg(debug_assertions)]
+    fn assert_ordering_consistent(&self) {
+        const MAX_FULLY_CHECKED_LEN: usize = 1000;
+
+        assert_eq!(
+            self.index.len(),
+            self.entries.len(),
+            "Stylesheet index out of sync with the entries, or duplicate sheets"
+        );
+        if self.entries.len() > MAX_FULLY_CHECKED_LEN {
             return;
         }
-        let sheet = self.entries.remove(index.unwrap());
+        for (i, entry) in self.entries.iter().enumerate() {
+            assert_eq!(
+                self.index.get(&entry.key),
+                Some(&i),
+                "Stylesheet at position {} missing from the index, or duplicated",
+                i
+            );
+        }
+        assert_eq!(
+            self.entries.iter().filter(|entry| entry.disabled).count(),
+            self.disabled_count,
+            "Wrong count of disabled stylesheets"
+        );
+    }
+
+    // NOTE(emilio): The mutation methods below do all the work that may call
+    // into the sheets (and thus may panic) before touching the collection, so
+    // that a panic never leaves it in an inconsistent state.
+
+    /// Removes `sheet` from the collection, returning whether it was present.
+    fn remove(&mut self, sheet: &S) -> bool {
+        let index = match self.index_of(sheet) {
+            Some(index) => index,
+            None => return false,
+        };
+        let sheet = self.entries.remove(index);
+        if sheet.disabled {
+            self.disabled_count -= 1;
+        }
+        self.index.remove(&sheet.key);
+        self.reindex_from(index);
         // Removing sheets makes us tear down the whole cascade and invalidation
         // data, but only if the sheet has been involved in at least one flush.
         // Checking whether the sheet has been committed allows us to avoid
@@ -301,67 +999,904 @@ where
         if sheet.committed {
             self.set_data_validity_at_least(DataValidity::FullyInvalid);
         } else {
-            self.dirty = true;
+            self.mark_dirty_for_unflushed_sheet();
+            // If nothing else happened since the last flush, this undid the
+            // only pending change, so there's nothing to flush anymore.
+            if self.is_unchanged_since_flush() {
+                self.dirty = false;
+            }
         }
+        self.removed.push(sheet.sheet);
+        self.maybe_shrink();
+        true
     }
 
-    fn contains(&self, sheet: &S) -> bool {
-        self.entries.iter().any(|e| e.sheet == *sheet)
+    /// Reserves space for at least `additional` more sheets.
+    fn reserve(&mut self, additional: usize) {
+        self.entries.reserve(additional);
+        self.index.reserve(additional);
     }
 
-    /// Appends a given sheet into the collection.
-    fn append(&mut self, sheet: S) {
-        debug_assert!(!self.contains(&sheet));
-        self.entries.push(StylesheetSetEntry::new(sheet));
-        // Appending sheets doesn't alter the validity of the existing data, so
-        // we don't need to change `data_validity` here.
-        //
-        // But we need to be marked as dirty, otherwise we'll never add the new
-        // sheet!
-        self.dirty = true;
+    /// Releases the unused capacity of the collection.
+    fn shrink_to_fit(&mut self) {
+        self.entries.shrink_to_fit();
+        self.index.shrink_to_fit();
+        self.removed.shrink_to_fit();
     }
 
-    fn insert_before(&mut self, sheet: S, before_sheet: &S) {
-        debug_assert!(!self.contains(&sheet));
-
-        let index = self
-            .entries
-            .iter()
-            .position(|entry| entry.sheet == *before_sheet)
-            .expect("`before_sheet` stylesheet not found");
+    /// Releases the unused capacity of the entries of the collection if it's
+    /// much larger than needed, see `SHRINK_THRESHOLD_FACTOR`.
+    ///
+    /// Only the lists that are proportional to the number of sheets are
+    /// shrunk, since `removed` is emptied on the next flush anyway.
+    fn maybe_shrink(&mut self) {
+        if !self.entries.spilled() ||
+            self.entries.capacity() <= self.entries.len() * SHRINK_THRESHOLD_FACTOR
+        {
+            return;
+        }
+        self.entries.shrink_to_fit();
+        self.index.shrink_to_fit();
+    }
 
-        // Inserting stylesheets somewhere but at the end changes the validity
-        // of the cascade data, but not the invalidation data.
-        self.set_data_validity_at_least(DataValidity::CascadeInvalid);
-        self.entries.insert(index, StylesheetSetEntry::new(sheet));
+    /// Returns whether flushing the collection now would produce the same
+    /// data as the last flush, that is, whether the only changes since then
+    /// are sheets that got appended and removed, and none of them is left.
+    fn is_unchanged_since_flush(&self) -> bool {
+        !self.changed_since_flush &&
+            self.entries
+                .iter()
+                .all(|entry| entry.committed || entry.disabled || !entry.applies)
     }
 
-    fn set_data_validity_at_least(&mut self, validity: DataValidity) {
-        use std::cmp;
+    /// Removes the sheets whose corresponding entry in `keep` is false, in a
+    /// single pass.
+    ///
+    /// This doesn't call into the sheets, and thus can't panic.
+    fn retain(&mut self, keep: &[bool]) {
+        let removed = self.take_entries(keep);
+        if removed.is_empty() {
+            return;
+        }
 
-        debug_assert_ne!(validity, DataValidity::Valid);
+        let removed_committed_sheet = removed.iter().any(|entry| entry.committed);
+        self.removed
+            .extend(removed.into_iter().map(|entry| entry.sheet));
 
-        self.dirty = true;
-        self.data_validity = cmp::max(validity, self.data_validity);
+        // See the comment in `remove` about why we check for committed sheets.
+        if removed_committed_sheet {
+            self.set_data_validity_at_least(DataValidity::FullyInvalid);
+        } else {
+            self.mark_dirty();
+        }
     }
 
-    /// Returns an iterator over the current list of stylesheets.
-    fn iter(&self) -> StylesheetCollectionIterator<S> {
-        StylesheetCollectionIterator(self.entries.iter())
+    /// Removes the entries whose corresponding entry in `keep` is false, and
+    /// returns them in order, keeping the index in sync.
+    ///
+    /// This doesn't touch the dirty state of the collection, that's up to the
+    /// caller.
+    fn take_entries(&mut self, keep: &[bool]) -> Vec<StylesheetSetEntry<S>> {
+        debug_assert_eq!(keep.len(), self.entries.len());
+        if keep.iter().all(|keep| *keep) {
+            return vec![];
+        }
+
+        let mut removed = vec![];
+        let entries = mem::replace(&mut self.entries, SmallVec::with_capacity(keep.len()));
+        for (entry, keep) in entries.into_iter().zip(keep) {
+            if *keep {
+                self.entries.push(entry);
+            } else {
+                if entry.disabled {
+                    self.disabled_count -= 1;
+                }
+                removed.push(entry);
+            }
+        }
+
+        self.index.clear();
+        self.reindex_from(0);
+        removed
+    }
+
+    /// Removes the sheets that haven't been part of a flush yet, and returns
+    /// them in order.
+    ///
+    /// These never contributed to the existing data, so this only marks the
+    /// collection as dirty, without downgrading its data validity.
+    fn drain_uncommitted(&mut self) -> Vec<S> {
+        let keep = self
+            .entries
+            .iter()
+            .map(|entry| entry.committed)
+            .collect::<Vec<_>>();
+        let drained = self.take_entries(&keep);
+        if drained.is_empty() {
+            return vec![];
+        }
+
+        self.mark_dirty_for_unflushed_sheet();
+        self.maybe_shrink();
+        drained.into_iter().map(|entry| entry.sheet).collect()
+    }
+
+    /// Replaces the list of sheets in the collection with `sheets`, where
+    /// `old_indices` holds the index in the current list of each of them, if
+    /// they're already in the collection.
+    ///
+    /// Sheets that are in both lists keep their entry, and thus whether they
+    /// were committed or disabled. The data validity only gets downgraded as
+    /// much as the difference between both lists requires.
+    fn replace(&mut self, sheets: Vec<S>, old_indices: &[Option<usize>]) {
+        debug_assert_eq!(sheets.len(), old_indices.len());
+
+        let mut kept = vec![false; self.entries.len()];
+        for index in old_indices.iter().filter_map(|index| *index) {
+            debug_assert!(!kept[index], "Duplicated sheet in the new list");
+            kept[index] = true;
+        }
+
+        let in_order = old_indices
+            .iter()
+            .filter_map(|index| *index)
+            .zip(0..)
+            .all(|(old_index, new_index)| old_index == new_index);
+        let changed = !in_order ||
+            kept.iter().any(|kept| !*kept) ||
+            old_indices.iter().any(|index| index.is_none());
+        if !changed {
+            return;
+        }
+
+        // The existing cascade data is still valid if the sheets that
+        // contributed to it are in the same relative order, and no other
+        // enabled sheet goes before any of them.
+        let mut needs_cascade_rebuild = false;
+        let mut last_committed_index = None;
+        let mut seen_uncommitted = false;
+        for old_index in old_indices {
+            let index = match *old_index {
+                Some(index) => index,
+                None => {
+                    seen_uncommitted = true;
+                    continue;
+                },
+            };
+            let entry = &self.entries[index];
+            if entry.disabled {
+                continue;
+            }
+            if !entry.committed {
+                seen_uncommitted = true;
+                continue;
+            }
+            if seen_uncommitted || last_committed_index.map_or(false, |last| last > index) {
+                needs_cascade_rebuild = true;
+            }
+            last_committed_index = Some(index);
+        }
+
+        // Create the new entries before touching the collection, since that
+        // calls into the sheets.
+        let mut new_entries = sheets
+            .into_iter()
+            .zip(old_indices)
+            .map(|(sheet, old_index)| match *old_index {
+                Some(_) => None,
+                None => Some(StylesheetSetEntry::new(sheet)),
+            })
+            .collect::<Vec<_>>();
+
+        let mut old_entries = mem::replace(
+            &mut self.entries,
+            SmallVec::with_capacity(new_entries.len()),
+        )
+        .into_iter()
+        .map(Some)
+        .collect::<Vec<_>>();
+        for (new_entry, old_index) in new_entries.iter_mut().zip(old_indices) {
+            let entry = match *old_index {
+                Some(index) => old_entries[index].take(),
+                None => new_entry.take(),
+            };
+            self.entries.push(entry.unwrap());
+        }
+
+        let mut removed_committed_sheet = false;
+        for entry in old_entries.into_iter().filter_map(|entry| entry) {
+            removed_committed_sheet |= entry.committed;
+            if entry.disabled {
+                self.disabled_count -= 1;
+            }
+            self.removed.push(entry.sheet);
+        }
+
+        self.index.clear();
+        self.reindex_from(0);
+
+        // See the comment in `remove` about why we check for committed sheets.
+        if removed_committed_sheet {
+            self.set_data_validity_at_least(DataValidity::FullyInvalid);
+        } else if needs_cascade_rebuild {
+            self.set_data_validity_at_least(DataValidity::CascadeInvalid);
+        } else {
+            self.mark_dirty();
+        }
+    }
+
+    fn contains(&self, sheet: &S) -> bool {
+        self.index_of(sheet).is_some()
+    }
+
+    /// Appends a given sheet into the collection.
+    ///
+    /// This is a no-op if the sheet is already in the collection, since a
+    /// second entry would be left behind when removing it.
+    fn append(&mut self, sheet: S) {
+        if self.contains(&sheet) {
+            return;
+        }
+        let entry = StylesheetSetEntry::new(sheet);
+        self.index.insert(entry.key, self.entries.len());
+        self.entries.push(entry);
+        // Appending sheets doesn't alter the validity of the existing data, so
+        // we don't need to change `data_validity` here.
+        //
+        // But we need to be marked as dirty, otherwise we'll never add the new
+        // sheet!
+        self.mark_dirty_for_unflushed_sheet();
+    }
+
+    /// Fallible version of `append`.
+    ///
+    /// If we fail to allocate, the collection is left untouched, and the sheet
+    /// is dropped.
+    #[cfg(feature = "fallible_allocation")]
+    fn try_append(&mut self, sheet: S) -> Result<(), FailedAllocationError> {
+        if self.contains(&sheet) {
+            return Ok(());
+        }
+        let entry = StylesheetSetEntry::new(sheet);
+        let key = entry.key;
+        self.entries.try_push(entry)?;
+        if let Err(error) = self.index.try_insert(key, self.entries.len() - 1) {
+            self.entries.pop();
+            return Err(error);
+        }
+        self.mark_dirty_for_unflushed_sheet();
+        Ok(())
+    }
+
+    /// Appends a list of sheets into the collection, in order.
+    fn extend<I>(&mut self, sheets: I)
+    where
+        I: IntoIterator<Item = S>,
+    {
+        let sheets = sheets.into_iter();
+        self.entries.reserve(sheets.size_hint().0);
+        for sheet in sheets {
+            self.append(sheet);
+        }
+    }
+
+    /// Inserts `sheet` right before `before_sheet`, which must be in the
+    /// collection, unless `sheet` already is.
+    fn insert_before(&mut self, sheet: S, before_sheet: &S) {
+        if self.contains(&sheet) {
+            return;
+        }
+
+        let index = self
+            .index_of(before_sheet)
+            .expect("`before_sheet` stylesheet not found");
+        self.insert_at(index, sheet);
+    }
+
+    /// Inserts `sheet` at position `index` in the collection.
+    ///
+    /// Inserting at or past the end of the collection is equivalent to
+    /// appending the sheet. Like `append`, this is a no-op if the sheet is
+    /// already in the collection.
+    fn insert_at(&mut self, index: usize, sheet: S) {
+        if self.contains(&sheet) {
+            return;
+        }
+
+        if index >= self.entries.len() {
+            return self.append(sheet);
+        }
+
+        let entry = StylesheetSetEntry::new(sheet);
+
+        // Inserting stylesheets before some that have been flushed changes the
+        // validity of the cascade data, but not the invalidation data.
+        // Otherwise, this is equivalent to an append.
+        if self.entries[index..].iter().any(|entry| entry.committed) {
+            self.set_data_validity_at_least(DataValidity::CascadeInvalid);
+        } else {
+            self.mark_dirty_for_unflushed_sheet();
+        }
+        self.entries.insert(index, entry);
+        self.reindex_from(index);
+    }
+
+    /// Moves `sheet` so that it ends up at position `new_index`, returning
+    /// whether it was in the collection.
+    ///
+    /// Indices past the end of the collection move the sheet to the end.
+    fn move_to(&mut self, sheet: &S, new_index: usize) -> bool {
+        let index = match self.index_of(sheet) {
+            Some(index) => index,
+            None => return false,
+        };
+        let new_index = cmp::min(new_index, self.entries.len() - 1);
+        if index == new_index {
+            return true;
+        }
+
+        let entry = self.entries.remove(index);
+        // A sheet that doesn't contribute to the cascade can move freely.
+        if entry.disabled {
+            self.mark_dirty();
+        } else {
+            self.set_data_validity_at_least(DataValidity::CascadeInvalid);
+        }
+        self.entries.insert(new_index, entry);
+        self.reindex_from(cmp::min(index, new_index));
+        true
+    }
+
+    /// Exchanges the positions of `a` and `b`, returning whether both were in
+    /// the collection.
+    ///
+    /// Each entry keeps its state, so this only invalidates the cascade data,
+    /// like `move_to`.
+    fn swap(&mut self, a: &S, b: &S) -> bool {
+        let (a_index, b_index) = match (self.index_of(a), self.index_of(b)) {
+            (Some(a_index), Some(b_index)) => (a_index, b_index),
+            _ => return false,
+        };
+        if a_index == b_index {
+            return true;
+        }
+
+        // Sheets that don't contribute to the cascade can swap freely.
+        if self.entries[a_index].disabled && self.entries[b_index].disabled {
+            self.mark_dirty();
+        } else {
+            self.set_data_validity_at_least(DataValidity::CascadeInvalid);
+        }
+        self.entries.swap(a_index, b_index);
+        self.index.insert(self.entries[a_index].key, a_index);
+        self.index.insert(self.entries[b_index].key, b_index);
+        true
+    }
+
+    /// Notes that the rules of `sheet` changed in place.
+    fn rule_changed(&mut self, sheet: &S, change: RuleChangeKind) {
+        let index = match self.index_of(sheet) {
+            Some(index) => index,
+            None => return,
+        };
+
+        let entry = &mut self.entries[index];
+        if !entry.committed || entry.disabled {
+            // There's no data built from the sheet.
+            return self.mark_dirty();
+        }
+
+        match change {
+            RuleChangeKind::Insert => {
+                // The new rules need to make it into the invalidation data too,
+                // see `replace_sheet`.
+                entry.committed = false;
+                self.set_data_validity_at_least(DataValidity::CascadeInvalid);
+            },
+            RuleChangeKind::Remove => {
+                // Stale invalidation data only makes us invalidate more than
+                // needed.
+                self.set_data_validity_at_least(DataValidity::CascadeInvalid);
+            },
+            RuleChangeKind::StyleDeclarationChange => {
+                // The cascade data references the rules, not their
+                // declarations, so only the elements need restyling.
+                self.mark_dirty();
+            },
+        }
+    }
+
+    /// Replaces the sheet at `index` with `sheet`, keeping its position and
+    /// whether it's disabled.
+    ///
+    /// The entry is no longer committed, so that the data for the new sheet
+    /// gets fully built on the next flush. The data for the old sheet only
+    /// needs to be dropped from the cascade data, since stale invalidation
+    /// data just makes us invalidate more than needed.
+    fn replace_sheet(&mut self, index: usize, sheet: S) {
+        let new_entry = StylesheetSetEntry::new(sheet);
+        debug_assert!(
+            new_entry.key == self.entries[index].key || !self.index.contains_key(&new_entry.key),
+            "Replacing a sheet with another sheet in the collection"
+        );
+
+        let old_entry = mem::replace(&mut self.entries[index], new_entry);
+        let new_key = self.entries[index].key;
+        self.entries[index].disabled = old_entry.disabled;
+        if old_entry.key != new_key {
+            self.index.remove(&old_entry.key);
+            self.index.insert(new_key, index);
+            self.removed.push(old_entry.sheet);
+        }
+
+        if old_entry.committed && !old_entry.disabled {
+            self.set_data_validity_at_least(DataValidity::CascadeInvalid);
+        } else {
+            self.mark_dirty();
+        }
+    }
+
+    /// Returns whether `sheet` is disabled, or `None` if it's not in the
+    /// collection.
+    fn is_disabled(&self, sheet: &S) -> Option<bool> {
+        let index = self.index_of(sheet)?;
+        Some(self.entries[index].disabled)
+    }
+
+    /// Returns whether `sheet` has been part of at least one flush, or `None`
+    /// if it's not in the collection.
+    fn is_committed(&self, sheet: &S) -> Option<bool> {
+        let index = self.index_of(sheet)?;
+        Some(self.entries[index].committed)
+    }
+
+    /// Disables or re-enables `sheet`, keeping its position.
+    fn set_disabled(&mut self, sheet: &S, disabled: bool) {
+        let index = match self.index_of(sheet) {
+            Some(index) => index,
+            None => return,
+        };
+
+        if self.entries[index].disabled == disabled {
+            return;
+        }
+
+        let needs_cascade_rebuild = if disabled {
+            // Dropping the rules of a sheet that contributed to the cascade
+            // data requires rebuilding it, but doesn't make the invalidation
+            // data invalid.
+            self.entries[index].committed
+        } else {
+            // Re-enabling a sheet is like appending it, unless data for sheets
+            // after it has already been built.
+            self.entries[index + 1..]
+                .iter()
+                .any(|entry| entry.committed && !entry.disabled)
+        };
+
+        {
+            let entry = &mut self.entries[index];
+            entry.disabled = disabled;
+            if disabled {
+                self.disabled_count += 1;
+            } else {
+                self.disabled_count -= 1;
+            }
+            // The data of a re-enabled sheet may have been dropped from the
+            // invalidation data while it was disabled, so rebuild it fully.
+            if !disabled {
+                entry.committed = false;
+            }
+        }
+
+        if needs_cascade_rebuild {
+            self.set_data_validity_at_least(DataValidity::CascadeInvalid);
+        } else {
+            self.mark_dirty();
+        }
+    }
+
+    /// Returns whether any of the sheets in the collection has been part of a
+    /// flush.
+    fn has_committed_sheets(&self) -> bool {
+        self.entries.iter().any(|entry| entry.committed)
+    }
+
+    /// Marks all the enabled sheets in the collection that apply as
+    /// committed.
+    ///
+    /// The sheets that don't apply aren't flushed, so they stay uncommitted
+    /// until a flush rebuilds their data.
+    fn commit_all(&mut self) {
+        for entry in &mut self.entries {
+            if !entry.disabled && entry.applies {
+                entry.committed = true;
+            }
+        }
+    }
+
+    /// Marks all the sheets in the collection as uncommitted, so that the next
+    /// flush does a full rebuild for each of them, and escalates the validity
+    /// accordingly.
+    fn uncommit_all(&mut self) {
+        for entry in &mut self.entries {
+            entry.committed = false;
+        }
+        self.set_data_validity_at_least(DataValidity::FullyInvalid);
+    }
+
+    /// Removes all the sheets from the collection, returning them in order,
+    /// and whether the DOM needs to be invalidated fully, because some of them
+    /// had been part of a flush.
+    fn clear(&mut self) -> (Vec<S>, bool) {
+        if self.entries.is_empty() {
+            return (vec![], false);
+        }
+
+        // See the comment in `remove` about why we check for committed sheets.
+        let had_committed_sheets = self.has_committed_sheets();
+        if had_committed_sheets {
+            self.set_data_validity_at_least(DataValidity::FullyInvalid);
+        } else {
+            self.mark_dirty();
+        }
+
+        self.index.clear();
+        self.disabled_count = 0;
+        let sheets = self.entries.drain().map(|entry| entry.sheet).collect();
+        self.maybe_shrink();
+        (sheets, had_committed_sheets)
+    }
+
+    /// Marks the collection as dirty, because something external may have
+    /// invalidated the data built from it, see
+    /// `DocumentStylesheetSet::force_dirty_with_validity`.
+    ///
+    /// Returns whether the DOM needs to be invalidated fully.
+    fn force_dirty(&mut self, validity: DataValidity) -> bool {
+        match validity {
+            DataValidity::Valid => self.mark_dirty(),
+            _ => self.set_data_validity_at_least(validity),
+        }
+        validity == DataValidity::FullyInvalid && self.len() != 0
+    }
+
+    /// Marks the collection as changed.
+    fn mark_dirty(&mut self) {
+        self.mark_dirty_for_unflushed_sheet();
+        self.changed_since_flush = true;
+    }
+
+    /// Marks the collection as changed because a sheet that hasn't been part
+    /// of a flush was appended or removed.
+    fn mark_dirty_for_unflushed_sheet(&mut self) {
+        self.dirty = true;
+        self.generation += 1;
+    }
+
+    fn set_data_validity_at_least(&mut self, validity: DataValidity) {
+        debug_assert_ne!(validity, DataValidity::Valid);
+
+        self.mark_dirty();
+        self.data_validity = self.data_validity.merge(validity);
+    }
+
+    /// Returns an iterator over the current list of stylesheets.
+    fn iter(&self) -> StylesheetCollectionIterator<S> {
+        StylesheetCollectionIterator {
+            iter: self.entries.iter(),
+            len: self.enabled_len(),
+        }
+    }
+
+    /// Undoes the reset done by `flush`, when the flush it was part of is
+    /// abandoned before completing.
+    ///
+    /// `validity` and `removed` are the data validity and the removed sheets
+    /// the collection had when the flush started. The sheets that were
+    /// processed before the flush was abandoned stay committed.
+    fn restore_after_abandoned_flush(&mut self, validity: DataValidity, removed: Vec<S>) {
+        debug_assert!(self.removed.is_empty());
+        self.dirty = true;
+        self.changed_since_flush = true;
+        self.data_validity = self.data_validity.merge(validity);
+        self.removed = removed;
     }
 
     fn flush(&mut self) -> SheetCollectionFlusher<S> {
         let dirty = mem::replace(&mut self.dirty, false);
+        self.changed_since_flush = false;
         let validity = mem::replace(&mut self.data_validity, DataValidity::Valid);
 
         SheetCollectionFlusher {
             iter: self.entries.iter_mut(),
+            pending_commit: SmallVec::new(),
+            removed: mem::replace(&mut self.removed, vec![]),
             dirty,
             validity,
         }
     }
 }
 
+/// The sheet collections of each origin of a `DocumentStylesheetSet`.
+///
+/// Most documents never get sheets in some of the origins, like the user
+/// origin, so each collection is only allocated the first time something is
+/// added to it. A missing collection behaves like an empty, clean one.
+struct SheetCollections<S>
+where
+    S: StylesheetInDocument + PartialEq + 'static,
+{
+    collections: PerOrigin<Option<Box<SheetCollection<S>>>>,
+}
+
+impl<S> SheetCollections<S>
+where
+    S: StylesheetInDocument + PartialEq + 'static,
+{
+    fn new() -> Self {
+        Self {
+            collections: PerOrigin {
+                user_agent: None,
+                user: None,
+                author: None,
+            },
+        }
+    }
+
+    /// Returns the collection for `origin`, if it was ever created.
+    #[inline]
+    fn get(&self, origin: Origin) -> Option<&SheetCollection<S>> {
+        self.collections
+            .borrow_for_origin(&origin)
+            .as_ref()
+            .map(|collection| &**collection)
+    }
+
+    /// Returns the collection for `origin` mutably, if it was ever created.
+    #[inline]
+    fn get_mut(&mut self, origin: Origin) -> Option<&mut SheetCollection<S>> {
+        self.collections
+            .borrow_mut_for_origin(&origin)
+            .as_mut()
+            .map(|collection| &mut **collection)
+    }
+
+    /// Returns the collection for `origin`, creating it if needed.
+    fn get_or_create(&mut self, origin: Origin) -> &mut SheetCollection<S> {
+        self.collections
+            .borrow_mut_for_origin(&origin)
+            .get_or_insert_with(Default::default)
+    }
+
+    /// Returns an iterator over the enabled stylesheets of `origin`.
+    fn iter_for_origin(&self, origin: Origin) -> StylesheetCollectionIterator<S> {
+        match self.get(origin) {
+            Some(collection) => collection.iter(),
+            None => StylesheetCollectionIterator::empty(),
+        }
+    }
+
+    /// Iterates over the existing collections, in the same order as
+    /// `PerOrigin::iter_origins`.
+    fn iter_origins(&self) -> impl Iterator<Item = (&SheetCollection<S>, Origin)> {
+        self.collections
+            .iter_origins()
+            .filter_map(|(collection, origin)| Some((&**collection.as_ref()?, origin)))
+    }
+
+    /// Like `iter_origins`, but iterates over mutable references.
+    fn iter_mut_origins(&mut self) -> impl Iterator<Item = (&mut SheetCollection<S>, Origin)> {
+        self.collections
+            .iter_mut_origins()
+            .filter_map(|(collection, origin)| Some((&mut **collection.as_mut()?, origin)))
+    }
+
+    fn shrink_to_fit(&mut self) {
+        for (collection, _) in self.iter_mut_origins() {
+            collection.shrink_to_fit();
+        }
+    }
+
+    /// Measures the heap usage of the collections, see
+    /// `SheetCollection::size_of_excluding_sheets`.
+    fn size_of_excluding_sheets(&self, ops: &mut MallocSizeOfOps) -> usize {
+        let mut n = 0;
+        for (collection, _) in self.collections.iter_origins() {
+            if let Some(ref collection) = *collection {
+                n += collection.shallow_size_of(ops) + collection.size_of_excluding_sheets(ops);
+            }
+        }
+        n
+    }
+}
+
+impl<S> MallocSizeOf for SheetCollections<S>
+where
+    S: StylesheetInDocument + PartialEq + MallocSizeOf + 'static,
+{
+    fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
+        self.collections.size_of(ops)
+    }
+}
+
+/// An error returned by the fallible operations of a stylesheet set.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StylesheetSetError {
+    /// We failed to allocate memory to grow the set.
+    OutOfMemory,
+    /// A stylesheet the operation refers to isn't in the set.
+    NotFound,
+    /// The stylesheets the operation refers to are from different origins.
+    OriginMismatch,
+}
+
+impl From<FailedAllocationError> for StylesheetSetError {
+    fn from(_: FailedAllocationError) -> Self {
+        StylesheetSetError::OutOfMemory
+    }
+}
+
+/// An error returned by `insert_stylesheet_before` and `replace_stylesheet`,
+/// carrying back the sheet that wasn't inserted so that the caller can decide
+/// what to do with it.
+#[derive(Debug, PartialEq)]
+pub enum InsertError<S> {
+    /// The sheet to insert before, or to replace, isn't in the set.
+    ReferenceNotFound(S),
+    /// The sheet to insert before is from a different origin than the sheet
+    /// being inserted.
+    OriginMismatch(S),
+    /// The sheet being inserted is already somewhere else in the set.
+    AlreadyInSet(S),
+}
+
+impl<S> InsertError<S> {
+    /// Returns the sheet that wasn't inserted.
+    pub fn into_sheet(self) -> S {
+        match self {
+            InsertError::ReferenceNotFound(sheet) |
+            InsertError::OriginMismatch(sheet) |
+            InsertError::AlreadyInSet(sheet) => sheet,
+        }
+    }
+}
+
+/// The invalidations collected by a `DocumentStylesheetSet`, which are either
+/// owned by the set, or shared with other sets.
+enum DocumentInvalidations {
+    /// An invalidation set per origin, so that a change in one origin, like a
+    /// full invalidation, doesn't make the invalidations of the other origins
+    /// less precise.
+    Owned(PerOrigin<AtomicRefCell<StylesheetInvalidationSet>>),
+    /// A single invalidation set for all the origins, shared with other sets.
+    Shared(SharedStylesheetInvalidationSet),
+}
+
+impl DocumentInvalidations {
+    fn new_owned() -> Self {
+        DocumentInvalidations::Owned(PerOrigin {
+            user_agent: AtomicRefCell::new(StylesheetInvalidationSet::new()),
+            user: AtomicRefCell::new(StylesheetInvalidationSet::new()),
+            author: AtomicRefCell::new(StylesheetInvalidationSet::new()),
+        })
+    }
+
+    /// Returns the invalidation set for the sheets of `origin`.
+    fn borrow_for_origin(&self, origin: Origin) -> AtomicRef<StylesheetInvalidationSet> {
+        match *self {
+            DocumentInvalidations::Owned(ref invalidations) => {
+                invalidations.borrow_for_origin(&origin).borrow()
+            },
+            DocumentInvalidations::Shared(ref invalidations) => invalidations.borrow(),
+        }
+    }
+
+    /// Mutably borrows the invalidation set for the sheets of `origin`.
+    fn borrow_mut_for_origin(&self, origin: Origin) -> AtomicRefMut<StylesheetInvalidationSet> {
+        match *self {
+            DocumentInvalidations::Owned(ref invalidations) => {
+                invalidations.borrow_for_origin(&origin).borrow_mut()
+            },
+            DocumentInvalidations::Shared(ref invalidations) => invalidations.borrow_mut(),
+        }
+    }
+
+    /// Calls `f` with each of the distinct invalidation sets.
+    fn for_each_mut<F>(&self, mut f: F)
+    where
+        F: FnMut(&mut StylesheetInvalidationSet),
+    {
+        match *self {
+            DocumentInvalidations::Owned(ref invalidations) => {
+                for (invalidations, _) in invalidations.iter_origins() {
+                    f(&mut invalidations.borrow_mut());
+                }
+            },
+            DocumentInvalidations::Shared(ref invalidations) => f(&mut invalidations.borrow_mut()),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match *self {
+            DocumentInvalidations::Owned(ref invalidations) => invalidations
+                .iter_origins()
+                .all(|(invalidations, _)| invalidations.borrow().is_empty()),
+            DocumentInvalidations::Shared(ref invalidations) => invalidations.borrow().is_empty(),
+        }
+    }
+
+    fn clear(&self) {
+        self.for_each_mut(|invalidations| invalidations.clear());
+    }
+
+    /// Drops the invalidations collected by this set, for when it went back
+    /// to the state of the last flush.
+    ///
+    /// A shared set may also hold the invalidations collected by other sets,
+    /// which we can't tell apart from ours, so it's left alone.
+    fn clear_owned(&self) {
+        if let DocumentInvalidations::Owned(..) = *self {
+            self.clear();
+        }
+    }
+
+    fn invalidate_fully(&self) {
+        self.for_each_mut(|invalidations| invalidations.invalidate_fully());
+    }
+
+    /// Processes the invalidations of `origins`, see
+    /// `StylesheetInvalidationSet::flush`, and returns the combined stats.
+    ///
+    /// A shared set can't tell the origins apart, so it's only processed if
+    /// `origins` contains all of `pending_origins`, the origins it may hold
+    /// invalidations for.
+    fn flush<E>(
+        &self,
+        origins: OriginSet,
+        pending_origins: OriginSet,
+        document_element: Option<E>,
+        snapshots: Option<&SnapshotMap>,
+    ) -> InvalidationStats
+    where
+        E: TElement,
+    {
+        match *self {
+            DocumentInvalidations::Owned(ref invalidations) => {
+                let mut stats = InvalidationStats::default();
+                for origin in origins.iter() {
+                    let mut invalidations = invalidations.borrow_for_origin(&origin).borrow_mut();
+                    if !invalidations.is_empty() {
+                        stats.accumulate(invalidations.flush(document_element, snapshots));
+                    }
+                }
+                stats
+            },
+            DocumentInvalidations::Shared(ref invalidations) => {
+                if !origins.contains(pending_origins) {
+                    return InvalidationStats::default();
+                }
+                invalidations
+                    .borrow_mut()
+                    .flush(document_element, snapshots)
+            },
+        }
+    }
+}
+
+impl MallocSizeOf for DocumentInvalidations {
+    fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
+        match *self {
+            DocumentInvalidations::Owned(ref invalidations) => invalidations
+                .iter_origins()
+                .map(|(invalidations, _)| invalidations.borrow().size_of(ops))
+                .sum(),
+            // Like the sheets, it's measured by whoever owns it.
+            DocumentInvalidations::Shared(..) => 0,
+        }
+    }
+}
+
 /// The set of stylesheets effective for a given document.
 #[cfg_attr(feature = "servo", derive(MallocSizeOf))]
 pub struct DocumentStylesheetSet<S>
@@ -369,73 +1904,327 @@ where
     S: StylesheetInDocument + PartialEq + 'static,
 {
     /// The collections of sheets per each origin.
-    collections: PerOrigin<SheetCollection<S>>,
+    collections: SheetCollections<S>,
 
-    /// The invalidations for stylesheets added or removed from this document.
-    invalidations: StylesheetInvalidationSet,
+    /// The invalidations for stylesheets added or removed from this document,
+    /// which may be shared with other sets.
+    invalidations: DocumentInvalidations,
+
+    /// The origins whose collection is dirty, cached so that `has_changed`
+    /// doesn't need to look at every collection.
+    ///
+    /// This needs to be kept in sync with the `dirty` flag of each collection
+    /// on every mutation, and on flush.
+    dirty_origins: OriginSet,
+
+    /// Whether the last flush kept the collected invalidations around, because
+    /// there was no document element to process them on.
+    invalidations_outlived_flush: bool,
+
+    /// Whether we're in a batch started by `begin_batch`, and thus the
+    /// invalidation collection for mutations is deferred.
+    defer_invalidations: bool,
+
+    /// The origin and identity of the sheets whose invalidations have been
+    /// deferred, in mutation order.
+    deferred_invalidations: Vec<(Origin, usize)>,
+
+    /// A counter bumped every time the device may have changed, used to tell
+    /// whether the cached media query results of the sheets are up to date.
+    device_generation: usize,
+
+    /// The `device_generation` as of the last `update_applicable_sheets`, if
+    /// any, so that it only looks at every sheet after a device change.
+    applicable_sheets_generation: Option<usize>,
 }
 
 /// This macro defines methods common to DocumentStylesheetSet and
 /// AuthorStylesheetSet.
 ///
 /// We could simplify the setup moving invalidations to SheetCollection, but
-/// that would imply not sharing invalidations across origins of the same
-/// documents, which is slightly annoying.
+/// a `DocumentStylesheetSet` may share its invalidations with other sets, in
+/// which case they aren't split per origin.
 macro_rules! sheet_set_methods {
     ($set_name:expr) => {
-        fn collect_invalidations_for(
+        /// Returns whether `sheet` is in the set, without creating a
+        /// collection for its origin if there's none.
+        #[inline]
+        fn has_sheet(&self, sheet: &S, guard: &SharedRwLockReadGuard) -> bool {
+            self.existing_collection_for(sheet, guard)
+                .map_or(false, |collection| collection.contains(sheet))
+        }
+
+        /// Returns whether `sheet` is already in the set, in which case adding
+        /// it again is a no-op.
+        fn is_duplicate(&self, sheet: &S, guard: &SharedRwLockReadGuard) -> bool {
+            if !self.has_sheet(sheet, guard) {
+                return false;
+            }
+            if cfg!(debug_assertions) {
+                warn!(concat!($set_name, ": sheet already present"));
+            }
+            true
+        }
+
+        /// Sets the maximum number of scoped invalidations collected before
+        /// the next flush restyles the whole tree instead.
+        ///
+        /// See `StylesheetInvalidationSet::set_max_scoped_invalidations`.
+        pub fn set_max_scoped_invalidations(&mut self, max: usize) {
+            self.for_each_invalidation_set(|invalidations| {
+                invalidations.set_max_scoped_invalidations(max)
+            });
+        }
+
+        /// Appends a new stylesheet to the current set, returning the
+        /// validity of the data of its origin afterwards.
+        ///
+        /// Appending never invalidates existing data, so this is `Valid`
+        /// unless the origin was already invalidated by some other change
+        /// since the last flush, which callers can use to tell how much
+        /// restyling the append needs.
+        ///
+        /// Appending a sheet that's already in the set is a no-op.
+        ///
+        /// No device implies not computing invalidations.
+        pub fn append_stylesheet(
+            &mut self,
+            device: Option<&Device>,
+            sheet: S,
+            guard: &SharedRwLockReadGuard,
+        ) -> DataValidity {
+            debug!(concat!($set_name, "::append_stylesheet"));
+            if self.is_duplicate(&sheet, guard) {
+                return self.collection_for(&sheet, guard).data_validity;
+            }
+            self.collect_invalidations_for(device, &sheet, guard);
+            let collection = self.collection_for(&sheet, guard);
+            collection.append(sheet);
+            let validity = collection.data_validity;
+            self.update_dirty_origins();
+            validity
+        }
+
+        /// Appends a list of stylesheets to the current set.
+        ///
+        /// This is equivalent to calling `append_stylesheet` for each of the
+        /// sheets, but borrows the collection of each origin only once. The
+        /// order of the sheets in the iterator is preserved within each
+        /// origin. Sheets already in the set, or repeated in `sheets`, are
+        /// skipped.
+        ///
+        /// No device implies not computing invalidations.
+        pub fn append_stylesheets<I>(
+            &mut self,
+            device: Option<&Device>,
+            sheets: I,
+            guard: &SharedRwLockReadGuard,
+        ) where
+            I: IntoIterator<Item = S>,
+        {
+            debug!(concat!($set_name, "::append_stylesheets"));
+            let mut new_keys = FxHashSet::default();
+            let mut sheets_per_origin = PerOrigin::<Vec<S>>::default();
+            for sheet in sheets {
+                if self.is_duplicate(&sheet, guard) || !new_keys.insert(sheet.identity()) {
+                    continue;
+                }
+                self.collect_invalidations_for(device, &sheet, guard);
+                let origin = sheet.origin(guard);
+                sheets_per_origin.borrow_mut_for_origin(&origin).push(sheet);
+            }
+
+            for (sheets, _) in sheets_per_origin.iter_mut_origins() {
+                let collection = match sheets.first() {
+                    Some(sheet) => self.collection_for(sheet, guard),
+                    None => continue,
+                };
+                collection.extend(sheets.drain(..));
+            }
+            self.update_dirty_origins();
+        }
+
+        /// Fallible version of `append_stylesheets`.
+        ///
+        /// If we fail to allocate, the sheets appended so far stay in the set,
+        /// and the rest are dropped. Either way the set is left consistent.
+        /// Invalidations degrade to a full invalidation rather than failing.
+        #[cfg(feature = "fallible_allocation")]
+        pub fn try_append_stylesheets<I>(
+            &mut self,
+            device: Option<&Device>,
+            sheets: I,
+            guard: &SharedRwLockReadGuard,
+        ) -> Result<(), StylesheetSetError>
+        where
+            I: IntoIterator<Item = S>,
+        {
+            debug!(concat!($set_name, "::try_append_stylesheets"));
+            let mut result = Ok(());
+            for sheet in sheets {
+                if self.is_duplicate(&sheet, guard) {
+                    continue;
+                }
+                self.collect_invalidations_for(device, &sheet, guard);
+                if let Err(error) = self.collection_for(&sheet, guard).try_append(sheet) {
+                    result = Err(error.into());
+                    break;
+                }
+            }
+            self.update_dirty_origins();
+            result
+        }
+
+        /// Insert a given stylesheet before another stylesheet in the document.
+        ///
+        /// If `before_sheet` isn't in the set, or is from a different origin,
+        /// the sheet is handed back in the error, and the set is left
+        /// untouched.
+        pub fn insert_stylesheet_before(
+            &mut self,
+            device: Option<&Device>,
+            sheet: S,
+            before_sheet: S,
+            guard: &SharedRwLockReadGuard,
+        ) -> Result<(), InsertError<S>> {
+            debug!(concat!($set_name, "::insert_stylesheet_before"));
+            if self.is_duplicate(&sheet, guard) {
+                return Ok(());
+            }
+            // Sheets are only ordered relative to other sheets of their own
+            // origin, so there's no sensible position for a sheet before one
+            // from another origin.
+            if before_sheet.origin(guard) != sheet.origin(guard) {
+                return Err(InsertError::OriginMismatch(sheet));
+            }
+            if !self.has_sheet(&before_sheet, guard) {
+                return Err(InsertError::ReferenceNotFound(sheet));
+            }
+            self.collect_invalidations_for(device, &sheet, guard);
+
+            let collection = self.collection_for(&sheet, guard);
+            collection.insert_before(sheet, &before_sheet);
+            self.update_dirty_origins();
+            Ok(())
+        }
+
+        /// Insert a given stylesheet at position `index` among the sheets of
+        /// its origin.
+        ///
+        /// If `index` is out of bounds, the sheet is appended, and the
+        /// existing data remains valid.
+        pub fn insert_stylesheet_at(
             &mut self,
             device: Option<&Device>,
-            sheet: &S,
+            index: usize,
+            sheet: S,
             guard: &SharedRwLockReadGuard,
         ) {
-            if let Some(device) = device {
-                self.invalidations.collect_invalidations_for(device, sheet, guard);
+            debug!(concat!($set_name, "::insert_stylesheet_at"));
+            if self.is_duplicate(&sheet, guard) {
+                return;
             }
+            self.collect_invalidations_for(device, &sheet, guard);
+
+            let collection = self.collection_for(&sheet, guard);
+            collection.insert_at(index, sheet);
+            self.update_dirty_origins();
         }
 
-        /// Appends a new stylesheet to the current set.
+        /// Enables or disables a given stylesheet in the set, keeping its
+        /// position in the list.
+        ///
+        /// Disabled sheets are skipped when iterating or flushing the set, but
+        /// are still returned by `get`.
         ///
         /// No device implies not computing invalidations.
-        pub fn append_stylesheet(
+        pub fn set_stylesheet_enabled(
             &mut self,
             device: Option<&Device>,
-            sheet: S,
+            sheet: &S,
+            enabled: bool,
             guard: &SharedRwLockReadGuard,
         ) {
-            debug!(concat!($set_name, "::append_stylesheet"));
-            self.collect_invalidations_for(device, &sheet, guard);
-            let collection = self.collection_for(&sheet, guard);
-            collection.append(sheet);
+            debug!(concat!($set_name, "::set_stylesheet_enabled"));
+            let disabled = self
+                .existing_collection_for(sheet, guard)
+                .and_then(|collection| collection.is_disabled(sheet));
+            match disabled {
+                Some(disabled) if disabled == enabled => {},
+                _ => return,
+            }
+
+            self.collect_invalidations_for(device, sheet, guard);
+            let collection = self.collection_for(sheet, guard);
+            collection.set_disabled(sheet, !enabled);
+            self.update_dirty_origins();
         }
 
-        /// Insert a given stylesheet before another stylesheet in the document.
-        pub fn insert_stylesheet_before(
+        /// Disables or re-enables a given stylesheet in the set, like
+        /// `set_stylesheet_enabled`.
+        #[inline]
+        pub fn set_disabled(
             &mut self,
             device: Option<&Device>,
-            sheet: S,
-            before_sheet: S,
+            sheet: &S,
+            disabled: bool,
             guard: &SharedRwLockReadGuard,
         ) {
-            debug!(concat!($set_name, "::insert_stylesheet_before"));
-            self.collect_invalidations_for(device, &sheet, guard);
+            self.set_stylesheet_enabled(device, sheet, !disabled, guard)
+        }
 
-            let collection = self.collection_for(&sheet, guard);
-            collection.insert_before(sheet, &before_sheet);
+        /// Notes that `rule` was inserted into, removed from, or changed in
+        /// place in `sheet`, and invalidates as needed.
+        ///
+        /// For removals, `rule` is the rule that was removed.
+        ///
+        /// No device implies not computing invalidations.
+        pub fn rule_changed(
+            &mut self,
+            device: Option<&Device>,
+            sheet: &S,
+            rule: &CssRule,
+            guard: &SharedRwLockReadGuard,
+            change: RuleChangeKind,
+        ) {
+            debug!(concat!($set_name, "::rule_changed({:?})"), change);
+            if !self.has_sheet(sheet, guard) {
+                return;
+            }
+            if let Some(device) = device {
+                self.invalidations_for(sheet, guard)
+                    .rule_changed(device, sheet, rule, guard);
+            }
+            self.collection_for(sheet, guard)
+                .rule_changed(sheet, change);
+            self.update_dirty_origins();
         }
 
         /// Remove a given stylesheet from the set.
+        ///
+        /// Returns whether the sheet was in the set. Removing a sheet that
+        /// isn't there (because it was already removed, for example) is not an
+        /// error, and callers can assert on the result if they want to.
         pub fn remove_stylesheet(
             &mut self,
             device: Option<&Device>,
             sheet: S,
             guard: &SharedRwLockReadGuard,
-        ) {
+        ) -> bool {
             debug!(concat!($set_name, "::remove_stylesheet"));
-            self.collect_invalidations_for(device, &sheet, guard);
+            if !self.has_sheet(&sheet, guard) {
+                if cfg!(debug_assertions) {
+                    warn!(concat!($set_name, "::remove_stylesheet: sheet not found"));
+                }
+                return false;
+            }
+
+            self.collect_invalidations_for_removal(device, &sheet, guard);
 
             let collection = self.collection_for(&sheet, guard);
-            collection.remove(&sheet)
+            let removed = collection.remove(&sheet);
+            self.update_dirty_origins();
+            removed
         }
     }
 }
@@ -446,19 +2235,233 @@ where
 {
     /// Create a new empty DocumentStylesheetSet.
     pub fn new() -> Self {
+        Self::with_invalidations(DocumentInvalidations::new_owned())
+    }
+
+    /// Create a new empty DocumentStylesheetSet that collects its
+    /// invalidations into `invalidations`, along with the other sets sharing
+    /// it.
+    ///
+    /// Flushing any of those sets processes the invalidations collected by all
+    /// of them, and `flush_without_invalidation` drops them all.
+    pub fn new_with_shared_invalidations(invalidations: SharedStylesheetInvalidationSet) -> Self {
+        Self::with_invalidations(DocumentInvalidations::Shared(invalidations))
+    }
+
+    fn with_invalidations(invalidations: DocumentInvalidations) -> Self {
         Self {
-            collections: Default::default(),
-            invalidations: StylesheetInvalidationSet::new(),
+            collections: SheetCollections::new(),
+            invalidations,
+            dirty_origins: OriginSet::empty(),
+            invalidations_outlived_flush: false,
+            defer_invalidations: false,
+            deferred_invalidations: vec![],
+            device_generation: 0,
+            applicable_sheets_generation: None,
         }
     }
 
+    /// Returns a read-only view of this set, that can be shared across
+    /// threads.
+    #[inline]
+    pub fn read_view(&self) -> StylesheetSetReadView<S> {
+        StylesheetSetReadView { set: self }
+    }
+
     fn collection_for(
         &mut self,
         sheet: &S,
         guard: &SharedRwLockReadGuard,
     ) -> &mut SheetCollection<S> {
         let origin = sheet.origin(guard);
-        self.collections.borrow_mut_for_origin(&origin)
+        self.collections.get_or_create(origin)
+    }
+
+    /// Like `collection_for`, but doesn't create the collection if there's
+    /// none for the origin of `sheet` yet, so it can be used for lookups.
+    fn existing_collection_for(
+        &self,
+        sheet: &S,
+        guard: &SharedRwLockReadGuard,
+    ) -> Option<&SheetCollection<S>> {
+        self.collections.get(sheet.origin(guard))
+    }
+
+    fn invalidations_for(
+        &mut self,
+        sheet: &S,
+        guard: &SharedRwLockReadGuard,
+    ) -> AtomicRefMut<StylesheetInvalidationSet> {
+        self.invalidations
+            .borrow_mut_for_origin(sheet.origin(guard))
+    }
+
+    fn for_each_invalidation_set<F>(&mut self, f: F)
+    where
+        F: FnMut(&mut StylesheetInvalidationSet),
+    {
+        self.invalidations.for_each_mut(f)
+    }
+
+    /// Returns the device to collect the invalidations for `sheet` against
+    /// right away, if any, or defers them if we're in a batch.
+    ///
+    /// Sheets whose media doesn't match `device` can't change the style of
+    /// anything, so there's nothing to collect for them. Their collection is
+    /// still dirtied by the mutation, and `device_changed` collects their
+    /// invalidations once their media starts matching.
+    fn device_for_invalidations<'d>(
+        &mut self,
+        device: Option<&'d Device>,
+        sheet: &S,
+        guard: &SharedRwLockReadGuard,
+    ) -> Option<&'d Device> {
+        let device = device?;
+        if !sheet.is_effective_for_device(device, guard) {
+            return None;
+        }
+        if self.defer_invalidations {
+            let origin = sheet.origin(guard);
+            self.deferred_invalidations.push((origin, sheet.identity()));
+            return None;
+        }
+        Some(device)
+    }
+
+    fn collect_invalidations_for(
+        &mut self,
+        device: Option<&Device>,
+        sheet: &S,
+        guard: &SharedRwLockReadGuard,
+    ) {
+        if let Some(device) = self.device_for_invalidations(device, sheet, guard) {
+            self.invalidations_for(sheet, guard)
+                .collect_invalidations_for(device, sheet, guard);
+        }
+    }
+
+    fn collect_invalidations_for_removal(
+        &mut self,
+        device: Option<&Device>,
+        sheet: &S,
+        guard: &SharedRwLockReadGuard,
+    ) {
+        if let Some(device) = self.device_for_invalidations(device, sheet, guard) {
+            self.invalidations_for(sheet, guard)
+                .collect_invalidations_for_removal(device, sheet, guard);
+        }
+    }
+
+    /// Starts deferring the invalidation collection for the sheets appended
+    /// to, removed from, or enabled or disabled in the set, until `end_batch`
+    /// is called.
+    ///
+    /// This is meant for bulk-loading sheets, where each of them would
+    /// otherwise be analyzed as soon as it is added.
+    pub fn begin_batch(&mut self) {
+        debug!("DocumentStylesheetSet::begin_batch");
+        debug_assert!(!self.defer_invalidations, "Nested stylesheet batches");
+        self.defer_invalidations = true;
+    }
+
+    /// Returns whether there are invalidations collected since the last
+    /// flush, waiting to be processed by the next one.
+    ///
+    /// Invalidations deferred by a batch in progress don't count.
+    #[inline]
+    pub fn has_pending_invalidations(&self) -> bool {
+        !self.invalidations.is_empty()
+    }
+
+    /// Returns why the invalidations pending for the sheets of `origin` are
+    /// going to restyle the whole document, if they are.
+    ///
+    /// If the invalidations are shared with other sets, this is the same for
+    /// all the origins.
+    pub fn full_invalidation_reason(&self, origin: Origin) -> Option<FullInvalidationReason> {
+        self.invalidations
+            .borrow_for_origin(origin)
+            .full_invalidation_reason()
+    }
+
+    /// Returns whether a batch started by `begin_batch` is in progress.
+    #[inline]
+    pub fn in_batch(&self) -> bool {
+        self.defer_invalidations
+    }
+
+    /// Ends the batch started by `begin_batch`, collecting the invalidations
+    /// for the sheets mutated during it, in mutation order.
+    ///
+    /// The invalidations are collected against the sheets as they are at this
+    /// point, which gives the same result as collecting them for each
+    /// mutation, as long as the sheets themselves weren't mutated in the
+    /// meantime. Mutations that were given no device don't collect any
+    /// invalidations, like outside of a batch.
+    pub fn end_batch(&mut self, device: &Device, guard: &SharedRwLockReadGuard) {
+        debug!(
+            "DocumentStylesheetSet::end_batch({})",
+            self.deferred_invalidations.len()
+        );
+        debug_assert!(
+            self.defer_invalidations,
+            "Ending a batch that wasn't started"
+        );
+        self.defer_invalidations = false;
+        for (origin, key) in self.deferred_invalidations.drain(..) {
+            // Removed sheets stick around until the next flush, which can't
+            // happen in the middle of a batch.
+            let collection = match self.collections.get(origin) {
+                Some(collection) => collection,
+                None => continue,
+            };
+            if let Some(&index) = collection.index.get(&key) {
+                let sheet = &collection.entries[index].sheet;
+                self.invalidations
+                    .borrow_mut_for_origin(origin)
+                    .collect_invalidations_for(device, sheet, guard);
+                continue;
+            }
+            let removed_sheet = collection
+                .removed
+                .iter()
+                .rev()
+                .find(|sheet| sheet.identity() == key);
+            if let Some(sheet) = removed_sheet {
+                self.invalidations
+                    .borrow_mut_for_origin(origin)
+                    .collect_invalidations_for_removal(device, sheet, guard);
+            }
+        }
+        self.update_dirty_origins();
+    }
+
+    /// Computes the set of origins whose collection is dirty.
+    fn compute_dirty_origins(&self) -> OriginSet {
+        let mut origins = OriginSet::empty();
+        for (collection, origin) in self.collections.iter_origins() {
+            if collection.dirty {
+                origins |= origin;
+            }
+        }
+        origins
+    }
+
+    /// Updates the cached set of dirty origins after a mutation.
+    ///
+    /// If the mutation undid all the changes since the last flush, the
+    /// invalidations collected for them are dropped too.
+    fn update_dirty_origins(&mut self) {
+        #[cfg(debug_assertions)]
+        {
+            for (collection, _) in self.collections.iter_origins() {
+                collection.assert_ordering_consistent();
+            }
+        }
+        self.dirty_origins = self.compute_dirty_origins();
+        if self.dirty_origins.is_empty() && !self.invalidations_outlived_flush {
+            self.invalidations.clear_owned();
+        }
     }
 
     sheet_set_methods!("DocumentStylesheetSet");
@@ -470,74 +2473,958 @@ where
             .fold(0, |s, (item, _)| s + item.len())
     }
 
+    /// Returns whether the set has no stylesheets, stopping at the first
+    /// origin that has any.
+    pub fn is_empty(&self) -> bool {
+        self.collections
+            .iter_origins()
+            .all(|(collection, _)| collection.len() == 0)
+    }
+
+    /// Returns the number of stylesheets in the set for the given origin,
+    /// including disabled ones.
+    pub fn sheet_count(&self, origin: Origin) -> usize {
+        self.collections
+            .get(origin)
+            .map_or(0, |collection| collection.len())
+    }
+
+    /// Reserves space for at least `additional` more stylesheets in `origin`,
+    /// to avoid growing the lists of sheets repeatedly when a lot of them are
+    /// about to be appended.
+    pub fn reserve(&mut self, origin: Origin, additional: usize) {
+        self.collections.get_or_create(origin).reserve(additional);
+    }
+
+    /// Returns the number of stylesheets in the set for each origin,
+    /// including disabled ones, so that callers can reserve space for them
+    /// before flushing.
+    pub fn len_per_origin(&self) -> PerOrigin<usize> {
+        let mut lens = PerOrigin::<usize>::default();
+        for (collection, origin) in self.collections.iter_origins() {
+            *lens.borrow_mut_for_origin(&origin) = collection.len();
+        }
+        lens
+    }
+
+    /// Returns the entries of the sheets of `origin`, including disabled ones.
+    fn entries(&self, origin: Origin) -> &[StylesheetSetEntry<S>] {
+        self.collections
+            .get(origin)
+            .map_or(&[][..], |collection| &collection.entries[..])
+    }
+
+    /// Returns whether `other` has the same stylesheets as this set, in the
+    /// same order, for every origin.
+    ///
+    /// Only the list of sheets is compared, not whether they're enabled or
+    /// have been flushed.
+    pub fn has_same_order_as(&self, other: &Self) -> bool {
+        OriginSet::all().iter().all(|origin| {
+            let ours = self.entries(origin);
+            let theirs = other.entries(origin);
+            ours.len() == theirs.len() &&
+                ours.iter()
+                    .zip(theirs)
+                    .all(|(ours, theirs)| ours.sheet == theirs.sheet)
+        })
+    }
+
+    /// Returns the heap memory used by the set, for memory reporting.
+    ///
+    /// This is approximate in that it doesn't include the sheets themselves,
+    /// which are measured by whoever owns them.
+    pub fn approximate_memory_usage(&self, ops: &mut MallocSizeOfOps) -> usize {
+        let mut n =
+            self.invalidations.size_of(ops) + self.deferred_invalidations.shallow_size_of(ops);
+        n + self.collections.size_of_excluding_sheets(ops)
+    }
+
+    /// Releases the memory the set keeps around for sheets that are no
+    /// longer there, and the buffers of its invalidation set.
+    ///
+    /// Removing sheets already gives the memory back when the set got much
+    /// smaller, so this is mostly useful under memory pressure. This doesn't
+    /// change the set in any observable way.
+    pub fn shrink_to_fit(&mut self) {
+        debug!("DocumentStylesheetSet::shrink_to_fit");
+        self.collections.shrink_to_fit();
+        self.deferred_invalidations.shrink_to_fit();
+        self.invalidations
+            .for_each_mut(|invalidations| invalidations.shrink_to_fit());
+    }
+
+    /// Moves `sheet` to position `index` within its origin, returning whether
+    /// it was in the set.
+    ///
+    /// This keeps the state of the sheet, and only invalidates the cascade
+    /// data, since the invalidation data doesn't depend on the order of the
+    /// sheets.
+    pub fn move_stylesheet(
+        &mut self,
+        sheet: &S,
+        index: usize,
+        guard: &SharedRwLockReadGuard,
+    ) -> bool {
+        debug!("DocumentStylesheetSet::move_stylesheet");
+        let moved = match self.collections.get_mut(sheet.origin(guard)) {
+            Some(collection) => collection.move_to(sheet, index),
+            None => false,
+        };
+        self.update_dirty_origins();
+        moved
+    }
+
+    /// Exchanges the positions of `a` and `b` within their origin.
+    ///
+    /// Like `move_stylesheet`, this keeps the state of both sheets and only
+    /// invalidates the cascade data. Swapping a sheet with itself doesn't
+    /// dirty anything.
+    pub fn swap_stylesheets(
+        &mut self,
+        a: &S,
+        b: &S,
+        guard: &SharedRwLockReadGuard,
+    ) -> Result<(), StylesheetSetError> {
+        debug!("DocumentStylesheetSet::swap_stylesheets");
+        if a.origin(guard) != b.origin(guard) {
+            return Err(StylesheetSetError::OriginMismatch);
+        }
+        let swapped = match self.collections.get_mut(a.origin(guard)) {
+            Some(collection) => collection.swap(a, b),
+            None => false,
+        };
+        self.update_dirty_origins();
+        if swapped {
+            Ok(())
+        } else {
+            Err(StylesheetSetError::NotFound)
+        }
+    }
+
+    /// Replaces `old` with `new`.
+    ///
+    /// If both sheets have the same origin, `new` takes the position of `old`,
+    /// and only the cascade data needs to be rebuilt, see
+    /// `SheetCollection::replace_sheet`. Otherwise, this is equivalent to
+    /// removing `old` and appending `new`.
+    ///
+    /// `new` may be the same sheet as `old`, for example when its contents
+    /// have been replaced, but it can't be any other sheet in the set, since
+    /// that would leave it in the set twice. On error, the set is left
+    /// untouched.
+    pub fn replace_stylesheet(
+        &mut self,
+        device: Option<&Device>,
+        old: &S,
+        new: S,
+        guard: &SharedRwLockReadGuard,
+    ) -> Result<(), InsertError<S>> {
+        debug!("DocumentStylesheetSet::replace_stylesheet");
+        let old_index = self
+            .existing_collection_for(old, guard)
+            .and_then(|collection| collection.index_of(old));
+        let index = match old_index {
+            Some(index) => index,
+            None => {
+                if cfg!(debug_assertions) {
+                    warn!("DocumentStylesheetSet::replace_stylesheet: sheet not found");
+                }
+                return Err(InsertError::ReferenceNotFound(new));
+            },
+        };
+        if new != *old && self.has_sheet(&new, guard) {
+            return Err(InsertError::AlreadyInSet(new));
+        }
+
+        self.collect_invalidations_for_removal(device, old, guard);
+        self.collect_invalidations_for(device, &new, guard);
+
+        if new.origin(guard) == old.origin(guard) {
+            self.collection_for(old, guard).replace_sheet(index, new);
+        } else {
+            self.collection_for(old, guard).remove(old);
+            self.collection_for(&new, guard).append(new);
+        }
+        self.update_dirty_origins();
+        Ok(())
+    }
+
+    /// Returns the number of stylesheets of `origin` that have been part of a
+    /// flush, and the total number of stylesheets of `origin`, in that order.
+    ///
+    /// Sheets that are appended and removed before the next flush never get
+    /// committed, and thus don't cause a full rebuild.
+    pub fn sheet_counts(&self, origin: Origin) -> (usize, usize) {
+        self.collections.get(origin).map_or((0, 0), |collection| {
+            (collection.committed_count(), collection.len())
+        })
+    }
+
+    /// Returns an iterator over the enabled stylesheets of the given origin.
+    pub fn sheets(&self, origin: Origin) -> StylesheetCollectionIterator<S> {
+        self.collections.iter_for_origin(origin)
+    }
+
+    /// Returns the `index`th enabled stylesheet in the set, along with its
+    /// origin, without iterating over the sheets of the other origins.
+    ///
+    /// Sheets are indexed in the same order `iter` yields them, that is, the
+    /// author sheets first, then the user sheets, then the user agent sheets,
+    /// each of them in document order.
+    pub fn nth(&self, index: usize) -> Option<(&S, Origin)> {
+        let mut index = index;
+        for (collection, origin) in self.collections.iter_origins() {
+            let len = collection.enabled_len();
+            if index >= len {
+                index -= len;
+                continue;
+            }
+            let sheet = if collection.disabled_count == 0 {
+                collection.get(index)
+            } else {
+                collection.iter().nth(index)
+            };
+            return sheet.map(|sheet| (sheet, origin));
+        }
+        None
+    }
+
     /// Returns the `index`th stylesheet in the set for the given origin.
     pub fn get(&self, origin: Origin, index: usize) -> Option<&S> {
-        self.collections.borrow_for_origin(&origin).get(index)
+        self.collections.get(origin)?.get(index)
+    }
+
+    /// Returns the first stylesheet for the given origin, including disabled
+    /// ones.
+    pub fn first_sheet(&self, origin: Origin) -> Option<&S> {
+        self.collections.get(origin)?.first()
+    }
+
+    /// Returns the last stylesheet for the given origin, including disabled
+    /// ones.
+    pub fn last_sheet(&self, origin: Origin) -> Option<&S> {
+        self.collections.get(origin)?.last()
+    }
+
+    /// Returns whether the given stylesheet is already part of the set.
+    pub fn contains(&self, sheet: &S, guard: &SharedRwLockReadGuard) -> bool {
+        let origin = sheet.origin(guard);
+        self.collections
+            .get(origin)
+            .map_or(false, |collection| collection.contains(sheet))
+    }
+
+    /// Returns whether the given stylesheet has been part of at least one
+    /// flush, or `None` if it's not part of the set.
+    ///
+    /// Removing a sheet that hasn't been committed doesn't invalidate the
+    /// existing data.
+    pub fn is_committed(&self, sheet: &S, guard: &SharedRwLockReadGuard) -> Option<bool> {
+        let origin = sheet.origin(guard);
+        self.collections.get(origin)?.is_committed(sheet)
+    }
+
+    /// Returns the origin and the index of the given stylesheet in the set, if
+    /// present.
+    ///
+    /// The result can be passed back to `get` to retrieve the same sheet.
+    pub fn position_of(&self, sheet: &S, guard: &SharedRwLockReadGuard) -> Option<(Origin, usize)> {
+        let origin = sheet.origin(guard);
+        let index = self.collections.get(origin)?.index_of(sheet)?;
+        Some((origin, index))
+    }
+
+    /// Returns whether the internal state of the set is consistent.
+    ///
+    /// This is only meant to be used in tests.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn is_consistent_for_testing(&self) -> bool {
+        self.collections
+            .iter_origins()
+            .all(|(collection, _)| collection.is_consistent())
+    }
+
+    /// Appends `sheet` to its origin bypassing the duplicate check and the
+    /// bookkeeping of the set, which leaves it in an inconsistent state.
+    ///
+    /// This is only meant to be used to test the consistency checks.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn append_unchecked_for_testing(&mut self, sheet: S, guard: &SharedRwLockReadGuard) {
+        let collection = self.collections.get_or_create(sheet.origin(guard));
+        collection.entries.push(StylesheetSetEntry::new(sheet));
+    }
+
+    /// Returns the origins that have changed from the last flush.
+    #[inline]
+    pub fn dirty_origins(&self) -> OriginSet {
+        debug_assert_eq!(
+            self.dirty_origins,
+            self.compute_dirty_origins(),
+            "Cached dirty origins out of sync"
+        );
+        self.dirty_origins
     }
 
     /// Returns whether the given set has changed from the last flush.
+    #[inline]
     pub fn has_changed(&self) -> bool {
+        !self.dirty_origins().is_empty()
+    }
+
+    /// Returns a number that increases every time the set changes.
+    ///
+    /// This isn't reset by flushing, so callers can store it and compare it
+    /// later to know whether anything changed in the meantime.
+    #[inline]
+    pub fn generation(&self) -> u64 {
         self.collections
             .iter_origins()
-            .any(|(collection, _)| collection.dirty)
+            .map(|(collection, _)| collection.generation)
+            .sum()
+    }
+
+    /// Like `generation`, but only changes when the stylesheets of `origin`
+    /// change.
+    #[inline]
+    pub fn origin_generation(&self, origin: Origin) -> u64 {
+        self.collections
+            .get(origin)
+            .map_or(0, |collection| collection.generation)
+    }
+
+    /// Returns the validity of the data for the given origin, as it would be
+    /// reported by the next flush.
+    pub fn data_validity(&self, origin: Origin) -> DataValidity {
+        self.collections
+            .get(origin)
+            .map_or(DataValidity::Valid, |collection| collection.data_validity)
+    }
+
+    /// Removes all the stylesheets for which `f` returns false from the set,
+    /// in a single pass per origin.
+    ///
+    /// This has the same effect on the validity of each origin, and on the
+    /// invalidations, as removing the sheets one by one.
+    ///
+    /// No device implies not computing invalidations.
+    pub fn retain<F>(&mut self, device: Option<&Device>, guard: &SharedRwLockReadGuard, mut f: F)
+    where
+        F: FnMut(&S) -> bool,
+    {
+        debug!("DocumentStylesheetSet::retain");
+        for origin in OriginSet::all().iter() {
+            let keep = match self.collections.get(origin) {
+                Some(collection) => collection
+                    .entries
+                    .iter()
+                    .map(|entry| f(&entry.sheet))
+                    .collect::<Vec<_>>(),
+                None => continue,
+            };
+            let removed_count = keep.iter().filter(|keep| !**keep).count();
+            if removed_count == 0 {
+                continue;
+            }
+
+            // The removed sheets end up at the end of the removed list, in
+            // order, which is also where `end_batch` looks for them if their
+            // invalidations are deferred.
+            let removed = {
+                let collection = self.collections.get_mut(origin).unwrap();
+                collection.retain(&keep);
+                mem::replace(&mut collection.removed, vec![])
+            };
+            for sheet in &removed[removed.len() - removed_count..] {
+                self.collect_invalidations_for_removal(device, sheet, guard);
+            }
+            self.collections.get_mut(origin).unwrap().removed = removed;
+        }
+        self.update_dirty_origins();
+    }
+
+    /// Replaces the stylesheets of `origin` with `sheets`, in order.
+    ///
+    /// This is cheaper than clearing the origin and appending the new list,
+    /// since sheets present in both lists keep their state, and the existing
+    /// data is only invalidated as much as the changes require: appending
+    /// sheets keeps it valid, inserting or reordering them invalidates the
+    /// cascade data, and removing a committed sheet invalidates all of it.
+    ///
+    /// No device implies not computing invalidations.
+    pub fn replace_origin_sheets(
+        &mut self,
+        device: Option<&Device>,
+        origin: Origin,
+        sheets: Vec<S>,
+        guard: &SharedRwLockReadGuard,
+    ) {
+        debug!("DocumentStylesheetSet::replace_origin_sheets({:?})", origin);
+        if sheets.is_empty() && self.collections.get(origin).is_none() {
+            return;
+        }
+        let collection = self.collections.get_or_create(origin);
+        let old_indices = sheets
+            .iter()
+            .map(|sheet| {
+                debug_assert_eq!(sheet.origin(guard), origin);
+                collection.index_of(sheet)
+            })
+            .collect::<Vec<_>>();
+
+        if let Some(device) = device {
+            let mut invalidations = self.invalidations.borrow_mut_for_origin(origin);
+            for (sheet, old_index) in sheets.iter().zip(&old_indices) {
+                if old_index.is_none() {
+                    invalidations.collect_invalidations_for(device, sheet, guard);
+                }
+            }
+            let mut kept = vec![false; collection.len()];
+            for index in old_indices.iter().filter_map(|index| *index) {
+                kept[index] = true;
+            }
+            for (entry, kept) in collection.entries.iter().zip(&kept) {
+                if !*kept {
+                    invalidations.collect_invalidations_for_removal(device, &entry.sheet, guard);
+                }
+            }
+        }
+
+        collection.replace(sheets, &old_indices);
+        self.update_dirty_origins();
+    }
+
+    /// Removes all the stylesheets for the given origins from the set,
+    /// returning them in the same order `iter` would.
+    ///
+    /// Each origin that contained committed sheets becomes fully invalid,
+    /// otherwise it's just marked as dirty.
+    pub fn clear(&mut self, origins: OriginSet) -> Vec<S> {
+        debug!("DocumentStylesheetSet::clear({:?})", origins);
+        let mut sheets = vec![];
+        for origin in origins.iter() {
+            sheets.extend(self.clear_origin(origin));
+        }
+        sheets
+    }
+
+    /// Removes all the stylesheets for the given origin from the set,
+    /// returning them in order.
+    pub fn clear_origin(&mut self, origin: Origin) -> Vec<S> {
+        debug!("DocumentStylesheetSet::clear_origin({:?})", origin);
+        let collection = match self.collections.get_mut(origin) {
+            Some(collection) => collection,
+            None => return vec![],
+        };
+        let (sheets, needs_invalidation) = collection.clear();
+        if needs_invalidation {
+            self.invalidations
+                .borrow_mut_for_origin(origin)
+                .invalidate_fully();
+        }
+        self.update_dirty_origins();
+        sheets
+    }
+
+    /// Removes the stylesheets of the given origins that haven't been part of
+    /// a flush yet, returning them in order, and keeps the ones that have.
+    ///
+    /// Since the removed sheets never contributed to the cascade, the origins
+    /// are only marked as dirty, and the validity of their data is untouched.
+    pub fn drain_uncommitted(&mut self, origins: OriginSet) -> Vec<S> {
+        debug!("DocumentStylesheetSet::drain_uncommitted({:?})", origins);
+        let mut sheets = vec![];
+        for origin in origins.iter() {
+            if let Some(collection) = self.collections.get_mut(origin) {
+                sheets.extend(collection.drain_uncommitted());
+            }
+        }
+        self.update_dirty_origins();
+        sheets
     }
 
     /// Flush the current set, unmarking it as dirty, and returns a
     /// `DocumentStylesheetFlusher` in order to rebuild the stylist.
+    ///
+    /// If there's no document element, the invalidations collected so far are
+    /// kept for the next flush.
+    ///
+    /// The sheets that don't apply to `device` are skipped, see
+    /// `update_applicable_sheets`.
     pub fn flush<E>(
         &mut self,
+        device: &Device,
+        guards: &StylesheetGuards,
+        document_element: Option<E>,
+        snapshots: Option<&SnapshotMap>,
+    ) -> DocumentStylesheetFlusher<S>
+    where
+        E: TElement,
+    {
+        self.flush_origins(
+            device,
+            guards,
+            OriginSet::all(),
+            document_element,
+            snapshots,
+        )
+    }
+
+    /// Like `flush`, but only flushes the given origins, which is useful to
+    /// rebuild the data of a single origin, like the UA one, cheaply.
+    ///
+    /// The other origins are reported as clean by the flusher, and stay dirty
+    /// in the set until a later flush.
+    ///
+    /// Only the invalidations for `origins` are processed, the rest are kept
+    /// for a later flush. If the invalidations are shared with other sets,
+    /// they can't be told apart by origin, so they're only processed if none
+    /// of them can come from an origin that isn't being flushed.
+    pub fn flush_origins<E>(
+        &mut self,
+        device: &Device,
+        guards: &StylesheetGuards,
+        origins: OriginSet,
         document_element: Option<E>,
         snapshots: Option<&SnapshotMap>,
     ) -> DocumentStylesheetFlusher<S>
     where
         E: TElement,
     {
-        debug!("DocumentStylesheetSet::flush");
+        debug!("DocumentStylesheetSet::flush_origins({:?})", origins);
+        debug_assert!(
+            !self.defer_invalidations,
+            "Flushing in the middle of a stylesheet batch"
+        );
+
+        self.update_applicable_sheets(device, guards);
+        #[cfg(feature = "profiling")]
+        let start = Instant::now();
+        let pending_origins = if self.invalidations_outlived_flush {
+            OriginSet::all()
+        } else {
+            self.dirty_origins
+        };
+        let invalidation_stats =
+            self.invalidations
+                .flush(origins, pending_origins, document_element, snapshots);
+        // Either there was no document element, or some of the invalidations
+        // were for origins that aren't being flushed.
+        self.invalidations_outlived_flush = !self.invalidations.is_empty();
+        #[cfg(feature = "profiling")]
+        let invalidated = Instant::now();
+        #[allow(unused_mut)]
+        let mut flusher = self.flusher(origins, invalidation_stats);
+        #[cfg(feature = "profiling")]
+        {
+            flusher.timings = Some(FlushTimings {
+                start,
+                invalidated,
+                scanned: Instant::now(),
+            });
+        }
+        flusher
+    }
+
+    /// Records which sheets apply to `device`, according to their media list
+    /// and whether they're enabled, so that flushing skips the ones that
+    /// don't.
+    ///
+    /// This is done by `flush`. The media query results are cached per sheet
+    /// like for `iter_effective`, so `device` needs to be the device the set
+    /// was last told about through `device_changed`. Unless the device
+    /// changed since the last call, only the origins with new or re-enabled
+    /// sheets are looked at.
+    ///
+    /// If a sheet started or stopped applying since it was last flushed, its
+    /// origin becomes fully invalid.
+    pub fn update_applicable_sheets(&mut self, device: &Device, guards: &StylesheetGuards) {
+        debug!("DocumentStylesheetSet::update_applicable_sheets");
+        let device_generation = self.device_generation;
+        let device_changed = self.applicable_sheets_generation != Some(device_generation);
+        self.applicable_sheets_generation = Some(device_generation);
+        for (collection, origin) in self.collections.iter_mut_origins() {
+            if !device_changed && !collection.dirty {
+                continue;
+            }
+            let guard = guards.for_origin(origin);
+            let mut fully_invalid = false;
+            for entry in collection.entries.iter_mut() {
+                if entry.disabled {
+                    continue;
+                }
+                let applies =
+                    entry.sheet.enabled() && entry.matches_device(device_generation, device, guard);
+                if applies == entry.applies {
+                    continue;
+                }
+                entry.applies = applies;
+                if applies {
+                    // The sheet was skipped so far, so this is like inserting
+                    // it.
+                    self.invalidations
+                        .borrow_mut_for_origin(origin)
+                        .collect_invalidations_for(device, &entry.sheet, guard);
+                    fully_invalid = true;
+                } else if entry.committed {
+                    // We don't know what the rules of the sheet matched with
+                    // the device it applied to.
+                    self.invalidations
+                        .borrow_mut_for_origin(origin)
+                        .invalidate_fully();
+                    entry.committed = false;
+                    fully_invalid = true;
+                }
+            }
+            if fully_invalid {
+                collection.set_data_validity_at_least(DataValidity::FullyInvalid);
+            }
+        }
+        self.update_dirty_origins();
+    }
 
-        let had_invalidations = self.invalidations.flush(document_element, snapshots);
+    /// Returns a flusher for `origins` in the current state of the set, once
+    /// the invalidations have been taken care of.
+    fn flusher(
+        &mut self,
+        origins: OriginSet,
+        invalidation_stats: InvalidationStats,
+    ) -> DocumentStylesheetFlusher<S> {
+        let mut origin_data_validity = PerOrigin::<DataValidity>::default();
+        let mut removed_sheets = PerOrigin::<Vec<S>>::default();
+        for (collection, origin) in self.collections.iter_mut_origins() {
+            if !origins.contains(origin.into()) {
+                continue;
+            }
+            *origin_data_validity.borrow_mut_for_origin(&origin) = collection.data_validity;
+            *removed_sheets.borrow_mut_for_origin(&origin) =
+                mem::replace(&mut collection.removed, vec![]);
+        }
 
         DocumentStylesheetFlusher {
             collections: &mut self.collections,
-            had_invalidations,
+            origins,
+            origins_dirty_at_start: self.dirty_origins & origins,
+            dirty_origins: &mut self.dirty_origins,
+            origins_to_commit: OriginSet::empty(),
+            completed: false,
+            origin_data_validity,
+            removed_sheets,
+            invalidation_stats,
+            #[cfg(feature = "profiling")]
+            timings: None,
+        }
+    }
+
+    /// Returns the current data validity of each origin.
+    fn origin_data_validities(&self) -> PerOrigin<DataValidity> {
+        let mut origin_data_validity = PerOrigin::<DataValidity>::default();
+        for (collection, origin) in self.collections.iter_origins() {
+            *origin_data_validity.borrow_mut_for_origin(&origin) = collection.data_validity;
         }
+        origin_data_validity
+    }
+
+    /// Returns the origins that the next flush would rebuild, and the validity
+    /// of the existing data for each origin, as `flush` would report them,
+    /// without flushing anything.
+    pub fn peek_flush(&self) -> (OriginSet, PerOrigin<DataValidity>) {
+        (self.dirty_origins, self.origin_data_validities())
     }
 
     /// Flush stylesheets, but without running any of the invalidation passes.
+    ///
+    /// Returns the set of origins that were dirty. The sheets are committed,
+    /// and the data validity reset, the same way a real flush would, so that
+    /// the next flush doesn't redo this work.
     #[cfg(feature = "servo")]
     pub fn flush_without_invalidation(&mut self) -> OriginSet {
         debug!("DocumentStylesheetSet::flush_without_invalidation");
+        let mut flusher = self.flusher_without_invalidation();
+        let origins = flusher.dirty_origins();
 
-        let mut origins = OriginSet::empty();
-        self.invalidations.clear();
-
-        for (collection, origin) in self.collections.iter_mut_origins() {
-            if collection.flush().dirty() {
-                origins |= origin;
-            }
-        }
+        // Flush every dirty origin, so that completing commits their sheets.
+        flusher.sheets().for_each(|_| {});
+        flusher.complete();
+        debug_assert!(self.dirty_origins.is_empty());
 
         origins
     }
 
+    /// Drops the pending invalidations and returns a flusher for the current
+    /// state of the set.
+    #[cfg(feature = "servo")]
+    fn flusher_without_invalidation(&mut self) -> DocumentStylesheetFlusher<S> {
+        self.invalidations.clear();
+        self.deferred_invalidations.clear();
+        self.invalidations_outlived_flush = false;
+        self.flusher(OriginSet::all(), InvalidationStats::default())
+    }
+
+    /// Like `flush_without_invalidation`, but returns the flusher instead of
+    /// consuming it.
+    ///
+    /// This is only meant to be used to test what happens when a flush isn't
+    /// completed.
+    #[cfg(all(feature = "servo", any(test, feature = "testing")))]
+    pub fn flusher_without_invalidation_for_testing(&mut self) -> DocumentStylesheetFlusher<S> {
+        self.flusher_without_invalidation()
+    }
+
     /// Return an iterator over the flattened view of all the stylesheets.
     pub fn iter(&self) -> StylesheetIterator<S> {
-        StylesheetIterator {
-            origins: OriginSet::all().iter(),
-            collections: &self.collections,
-            current: None,
-        }
+        self.iter_origins(OriginSet::all())
+    }
+
+    /// Return an iterator over the flattened view of the stylesheets of the
+    /// given origins, in the same order as `iter`.
+    pub fn iter_origins(&self, origins: OriginSet) -> StylesheetIterator<S> {
+        StylesheetIterator::new(&self.collections, origins)
+    }
+
+    /// Return an iterator over the same stylesheets as `iter`, along with their
+    /// origin and whether they've been part of at least one flush.
+    pub fn iter_with_state(&self) -> impl Iterator<Item = (&S, Origin, bool)> {
+        self.collections
+            .iter_origins()
+            .flat_map(|(collection, origin)| {
+                collection
+                    .entries
+                    .iter()
+                    .filter(|entry| !entry.disabled)
+                    .map(move |entry| (&entry.sheet, origin, entry.committed))
+            })
     }
 
-    /// Mark the stylesheets for the specified origin as dirty, because
-    /// something external may have invalidated it.
+    /// Return an iterator over the enabled stylesheets whose media list
+    /// matches `device`, in the same order as `iter`, along with their origin.
+    ///
+    /// The media query results are cached per sheet until the next call to
+    /// `device_changed` or `force_dirty`, so `device` needs to be the device
+    /// the set was last told about.
+    pub fn iter_effective<'a>(
+        &'a self,
+        device: &'a Device,
+        guard: &'a SharedRwLockReadGuard<'a>,
+    ) -> impl Iterator<Item = (&'a S, Origin)> + 'a {
+        let device_generation = self.device_generation;
+        self.collections
+            .iter_origins()
+            .flat_map(move |(collection, origin)| {
+                collection
+                    .entries
+                    .iter()
+                    .filter(move |entry| {
+                        !entry.disabled && entry.matches_device(device_generation, device, guard)
+                    })
+                    .map(move |entry| (&entry.sheet, origin))
+            })
+    }
+
+    /// Mark the stylesheets for the specified origins as dirty, because
+    /// something external may have invalidated them.
+    ///
+    /// The DOM is fully invalidated only if any of those origins has sheets
+    /// whose rules may have changed meaning. Other origins keep their
+    /// validity.
     pub fn force_dirty(&mut self, origins: OriginSet) {
+        // We don't know what happened, assume the worse.
+        self.force_dirty_with_validity(origins, DataValidity::FullyInvalid)
+    }
+
+    /// Like `force_dirty`, but for callers that know how much of the existing
+    /// data is still valid.
+    ///
+    /// The DOM is only invalidated for `DataValidity::FullyInvalid`. Otherwise
+    /// the caller is responsible for restyling whatever is affected by the
+    /// change.
+    ///
+    /// The media query results cached for the sheets are kept, callers that
+    /// changed the media lists of the sheets should use `invalidate_all`.
+    pub fn force_dirty_with_validity(&mut self, origins: OriginSet, validity: DataValidity) {
+        // Origins without a collection have no data to rebuild.
+        for (collection, origin) in self.collections.iter_mut_origins() {
+            if !origins.contains(origin.into()) {
+                continue;
+            }
+            if collection.force_dirty(validity) {
+                self.invalidations
+                    .borrow_mut_for_origin(origin)
+                    .invalidate_fully();
+            }
+        }
+        self.update_dirty_origins();
+    }
+
+    /// Treats every stylesheet in the set as if it had never been flushed, so
+    /// that the next flush does a full rebuild for all of them, and restyles
+    /// the whole document.
+    ///
+    /// This is meant for drastic device changes. Unlike `force_dirty`, it also
+    /// resets the commit state of the sheets, as if they had been removed and
+    /// appended again, without actually doing so.
+    pub fn invalidate_all(&mut self) {
+        debug!("DocumentStylesheetSet::invalidate_all");
+        self.device_generation = self.device_generation.wrapping_add(1);
+        for (collection, _) in self.collections.iter_mut_origins() {
+            collection.uncommit_all();
+        }
         self.invalidations.invalidate_fully();
-        for origin in origins.iter() {
-            // We don't know what happened, assume the worse.
-            self.collections
-                .borrow_mut_for_origin(&origin)
-                .set_data_validity_at_least(DataValidity::FullyInvalid);
+        self.update_dirty_origins();
+    }
+
+    /// Mark the stylesheets for a single origin as dirty, because something
+    /// external may have invalidated them.
+    ///
+    /// See `force_dirty`.
+    #[inline]
+    pub fn force_dirty_origin(&mut self, origin: Origin) {
+        self.force_dirty(origin.into())
+    }
+
+    /// Notes that the device changed from `old` to `new`, marking dirty only
+    /// the origins with a sheet that started or stopped applying because of
+    /// its media list.
+    ///
+    /// Only the media list of the sheet itself is taken into account, nested
+    /// `@media` rules are the responsibility of the caller. The result of the
+    /// last evaluation is cached, so that sheets are only evaluated once per
+    /// device change.
+    ///
+    /// This also invalidates the media query results cached for
+    /// `iter_effective`.
+    pub fn device_changed(&mut self, old: &Device, new: &Device, guard: &SharedRwLockReadGuard) {
+        debug!("DocumentStylesheetSet::device_changed");
+        let old_generation = self.device_generation;
+        let new_generation = old_generation.wrapping_add(1);
+        self.device_generation = new_generation;
+        for (collection, origin) in self.collections.iter_mut_origins() {
+            let mut cascade_invalid = false;
+            let mut changed = false;
+            for entry in collection.entries.iter_mut() {
+                if entry.disabled || entry.sheet.media(guard).is_none() {
+                    continue;
+                }
+
+                let was_effective = entry.matches_device(old_generation, old, guard);
+                let is_effective = entry.sheet.is_effective_for_device(new, guard);
+                entry.cache_matches_device(new_generation, is_effective);
+                // This takes care of the change, so that flushing doesn't
+                // escalate it further.
+                let was_skipped = !entry.applies;
+                entry.applies = is_effective;
+                if was_effective == is_effective {
+                    continue;
+                }
+
+                let device = if is_effective { new } else { old };
+                self.invalidations
+                    .borrow_mut_for_origin(origin)
+                    .collect_invalidations_for(device, &entry.sheet, guard);
+                changed = true;
+                // A sheet skipped by the last flush isn't committed, but,
+                // unlike a new one, it may be in the middle of the others.
+                if !entry.committed && !was_skipped {
+                    continue;
+                }
+                cascade_invalid = true;
+                // Either the invalidation data for the sheet wasn't built,
+                // since it didn't apply, or the sheet is skipped from now on.
+                entry.committed = false;
+            }
+
+            if cascade_invalid {
+                collection.set_data_validity_at_least(DataValidity::CascadeInvalid);
+            } else if changed {
+                collection.mark_dirty();
+            }
         }
+        self.update_dirty_origins();
+    }
+}
+
+/// A read-only view of a `DocumentStylesheetSet`.
+///
+/// This is `Sync` as long as the stylesheets are, and is what should be handed
+/// to other threads that need to look at the list of stylesheets while the
+/// main thread is guaranteed not to mutate it, like during a parallel
+/// traversal.
+///
+/// The view borrows the set, so the borrow checker already guarantees that
+/// the set isn't mutated while any view is alive.
+pub struct StylesheetSetReadView<'a, S>
+where
+    S: StylesheetInDocument + PartialEq + 'static,
+{
+    set: &'a DocumentStylesheetSet<S>,
+}
+
+impl<'a, S> Clone for StylesheetSetReadView<'a, S>
+where
+    S: StylesheetInDocument + PartialEq + 'static,
+{
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, S> Copy for StylesheetSetReadView<'a, S> where S: StylesheetInDocument + PartialEq + 'static
+{}
+
+impl<'a, S> StylesheetSetReadView<'a, S>
+where
+    S: StylesheetInDocument + PartialEq + 'static,
+{
+    /// Returns the number of stylesheets in the set.
+    pub fn len(&self) -> usize {
+        self.set.len()
+    }
+
+    /// Returns whether the set has no stylesheets.
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty()
+    }
+
+    /// Returns the `index`th stylesheet in the set for the given origin.
+    pub fn get(&self, origin: Origin, index: usize) -> Option<&'a S> {
+        self.set.get(origin, index)
+    }
+
+    /// Returns the `index`th enabled stylesheet in the set, in the order
+    /// `iter` yields them, along with its origin.
+    pub fn nth(&self, index: usize) -> Option<(&'a S, Origin)> {
+        self.set.nth(index)
+    }
+
+    /// Returns the stylesheet whose `identity()` is `key`, and its origin, if
+    /// present.
+    pub fn find_by_key(&self, key: usize) -> Option<(&'a S, Origin)> {
+        self.set
+            .collections
+            .iter_origins()
+            .filter_map(|(collection, origin)| Some((collection.find_by_key(key)?, origin)))
+            .next()
+    }
+
+    /// Return an iterator over the flattened view of all the stylesheets.
+    pub fn iter(&self) -> StylesheetIterator<'a, S> {
+        self.set.iter()
+    }
+
+    /// Return an iterator over the flattened view of the stylesheets of the
+    /// given origins.
+    pub fn iter_origins(&self, origins: OriginSet) -> StylesheetIterator<'a, S> {
+        self.set.iter_origins(origins)
+    }
+
+    /// Return an iterator over the enabled stylesheets whose media list
+    /// matches `device`, see `DocumentStylesheetSet::iter_effective`.
+    pub fn iter_effective(
+        &self,
+        device: &'a Device,
+        guard: &'a SharedRwLockReadGuard<'a>,
+    ) -> impl Iterator<Item = (&'a S, Origin)> + 'a {
+        self.set.iter_effective(device, guard)
     }
 }
 
@@ -554,14 +3441,102 @@ where
 }
 
 /// A struct to flush an author style sheet collection.
+///
+/// Like for `DocumentStylesheetFlusher`, the caller must call `complete` once
+/// it's done rebuilding its data from the sheets. If the flusher is dropped
+/// without that, the collection is left dirty, with the validity of its data
+/// restored, so that the next flush redoes the work.
 pub struct AuthorStylesheetFlusher<'a, S>
 where
     S: StylesheetInDocument + PartialEq + 'static,
 {
-    /// The actual flusher for the collection.
-    pub sheets: SheetCollectionFlusher<'a, S>,
-    /// Whether any sheet invalidation matched.
-    pub had_invalidations: bool,
+    collection: &'a mut SheetCollection<S>,
+    /// Whether the collection was dirty when the flush started.
+    dirty: bool,
+    /// The validity of the existing data when the flush started.
+    data_validity: DataValidity,
+    removed_sheets: Vec<S>,
+    /// Whether the sheets were handed out by `sheets`, and thus need to be
+    /// committed by `complete`.
+    flushed: bool,
+    completed: bool,
+    /// Statistics about the elements the sheet invalidations matched.
+    pub invalidation_stats: InvalidationStats,
+}
+
+impl<'a, S> AuthorStylesheetFlusher<'a, S>
+where
+    S: StylesheetInDocument + PartialEq + 'static,
+{
+    fn new(collection: &'a mut SheetCollection<S>, invalidation_stats: InvalidationStats) -> Self {
+        Self {
+            dirty: collection.dirty,
+            data_validity: collection.data_validity,
+            removed_sheets: mem::replace(&mut collection.removed, vec![]),
+            collection,
+            flushed: false,
+            completed: false,
+            invalidation_stats,
+        }
+    }
+
+    /// Returns the origins that need to be rebuilt, which is either nothing or
+    /// just the author origin, since author sets only contain author sheets.
+    #[inline]
+    pub fn dirty_origins(&self) -> OriginSet {
+        if self.dirty {
+            OriginSet::ORIGIN_AUTHOR
+        } else {
+            OriginSet::empty()
+        }
+    }
+
+    /// Returns whether any DOM invalidations were processed as a result of the
+    /// stylesheet flush.
+    #[inline]
+    pub fn had_invalidations(&self) -> bool {
+        self.invalidation_stats.had_invalidations()
+    }
+
+    /// Returns the sheets that were removed since the last flush, in removal
+    /// order.
+    ///
+    /// These are dropped along with the flusher.
+    #[inline]
+    pub fn removed_sheets(&self) -> slice::Iter<S> {
+        self.removed_sheets.iter()
+    }
+
+    /// Returns a flusher for the sheets of the collection.
+    pub fn sheets(&mut self) -> SheetCollectionFlusher<S> {
+        self.flushed = true;
+        self.collection.flush()
+    }
+
+    /// Finishes the flush, once the caller has rebuilt its data from the
+    /// sheets it got.
+    pub fn complete(mut self) {
+        if self.flushed {
+            self.collection.commit_all();
+        }
+        self.completed = true;
+    }
+}
+
+impl<'a, S> Drop for AuthorStylesheetFlusher<'a, S>
+where
+    S: StylesheetInDocument + PartialEq + 'static,
+{
+    fn drop(&mut self) {
+        if self.completed || !self.dirty {
+            return;
+        }
+
+        debug!("AuthorStylesheetFlusher dropped without completing the flush");
+        let removed = mem::replace(&mut self.removed_sheets, vec![]);
+        self.collection
+            .restore_after_abandoned_flush(self.data_validity, removed);
+    }
 }
 
 impl<S> AuthorStylesheetSet<S>
@@ -582,11 +3557,72 @@ where
         self.collection.dirty
     }
 
+    /// Returns whether the given set has changed from the last flush.
+    ///
+    /// This is the same as `dirty`, for consistency with
+    /// `DocumentStylesheetSet`.
+    #[inline]
+    pub fn has_changed(&self) -> bool {
+        self.dirty()
+    }
+
+    /// Returns a number that increases every time the set changes.
+    ///
+    /// This isn't reset by flushing, so callers can store it and compare it
+    /// later to know whether anything changed in the meantime.
+    #[inline]
+    pub fn generation(&self) -> u64 {
+        self.collection.generation
+    }
+
     /// Whether the collection is empty.
     pub fn is_empty(&self) -> bool {
         self.collection.len() == 0
     }
 
+    /// Returns the number of stylesheets in the set.
+    pub fn len(&self) -> usize {
+        self.collection.len()
+    }
+
+    /// Returns the `index`th stylesheet in the set, if present.
+    pub fn get(&self, index: usize) -> Option<&S> {
+        self.collection.get(index)
+    }
+
+    /// Returns the first stylesheet in the set, including disabled ones.
+    pub fn first(&self) -> Option<&S> {
+        self.collection.first()
+    }
+
+    /// Returns the last stylesheet in the set, including disabled ones.
+    pub fn last(&self) -> Option<&S> {
+        self.collection.last()
+    }
+
+    /// Returns whether the internal state of the set is consistent.
+    ///
+    /// This is only meant to be used in tests.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn is_consistent_for_testing(&self) -> bool {
+        self.collection.is_consistent()
+    }
+
+    fn invalidations_for(
+        &mut self,
+        _sheet: &S,
+        _guard: &SharedRwLockReadGuard,
+    ) -> &mut StylesheetInvalidationSet {
+        &mut self.invalidations
+    }
+
+    fn for_each_invalidation_set<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&mut StylesheetInvalidationSet),
+    {
+        f(&mut self.invalidations)
+    }
+
     fn collection_for(
         &mut self,
         _sheet: &S,
@@ -595,13 +3631,100 @@ where
         &mut self.collection
     }
 
+    fn existing_collection_for(
+        &self,
+        _sheet: &S,
+        _guard: &SharedRwLockReadGuard,
+    ) -> Option<&SheetCollection<S>> {
+        Some(&self.collection)
+    }
+
+    // See `DocumentStylesheetSet::device_for_invalidations` about the sheets
+    // whose media doesn't match.
+    fn collect_invalidations_for(
+        &mut self,
+        device: Option<&Device>,
+        sheet: &S,
+        guard: &SharedRwLockReadGuard,
+    ) {
+        if let Some(device) = device {
+            if sheet.is_effective_for_device(device, guard) {
+                self.invalidations
+                    .collect_invalidations_for(device, sheet, guard);
+            }
+        }
+    }
+
+    fn collect_invalidations_for_removal(
+        &mut self,
+        device: Option<&Device>,
+        sheet: &S,
+        guard: &SharedRwLockReadGuard,
+    ) {
+        if let Some(device) = device {
+            if sheet.is_effective_for_device(device, guard) {
+                self.invalidations
+                    .collect_invalidations_for_removal(device, sheet, guard);
+            }
+        }
+    }
+
+    // Author sets only have one collection, whose dirty flag is all we need,
+    // but if the mutation undid all the changes since the last flush, the
+    // invalidations collected for them are moot.
+    #[inline]
+    fn update_dirty_origins(&mut self) {
+        #[cfg(debug_assertions)]
+        {
+            self.collection.assert_ordering_consistent();
+        }
+        if !self.collection.dirty {
+            self.invalidations.clear();
+        }
+    }
+
     sheet_set_methods!("AuthorStylesheetSet");
 
+    /// Returns the heap memory used by the set, see
+    /// `DocumentStylesheetSet::approximate_memory_usage`.
+    pub fn approximate_memory_usage(&self, ops: &mut MallocSizeOfOps) -> usize {
+        self.collection.size_of_excluding_sheets(ops) + self.invalidations.size_of(ops)
+    }
+
+    /// Releases the memory the set keeps around, see
+    /// `DocumentStylesheetSet::shrink_to_fit`.
+    pub fn shrink_to_fit(&mut self) {
+        debug!("AuthorStylesheetSet::shrink_to_fit");
+        self.collection.shrink_to_fit();
+        self.invalidations.shrink_to_fit();
+    }
+
     /// Iterate over the list of stylesheets.
     pub fn iter(&self) -> StylesheetCollectionIterator<S> {
         self.collection.iter()
     }
 
+    /// Returns whether the given stylesheet is already part of the set.
+    pub fn contains(&self, sheet: &S) -> bool {
+        self.collection.contains(sheet)
+    }
+
+    /// Returns the index of the given stylesheet in the set, if present.
+    pub fn position_of(&self, sheet: &S) -> Option<usize> {
+        self.collection.index_of(sheet)
+    }
+
+    /// Removes all the stylesheets from the set, returning them in order.
+    pub fn clear(&mut self) -> Vec<S> {
+        debug!("AuthorStylesheetSet::clear");
+        let (sheets, needs_invalidation) = self.collection.clear();
+        if needs_invalidation {
+            self.invalidations.invalidate_fully();
+        }
+        self.update_dirty_origins();
+        sheets
+    }
+
     /// Mark the sheet set dirty, as appropriate.
     pub fn force_dirty(&mut self) {
         self.invalidations.invalidate_fully();
@@ -609,6 +3732,17 @@ where
             .set_data_validity_at_least(DataValidity::FullyInvalid);
     }
 
+    /// Like `force_dirty`, but for callers that know how much of the existing
+    /// data is still valid.
+    ///
+    /// See `DocumentStylesheetSet::force_dirty_with_validity`.
+    pub fn force_dirty_with_validity(&mut self, validity: DataValidity) {
+        if self.collection.force_dirty(validity) {
+            self.invalidations.invalidate_fully();
+        }
+        self.update_dirty_origins();
+    }
+
     /// Flush the stylesheets for this author set.
     ///
     /// `host` is the root of the affected subtree, like the shadow host, for
@@ -621,10 +3755,14 @@ where
     where
         E: TElement,
     {
-        let had_invalidations = self.invalidations.flush(host, snapshots);
-        AuthorStylesheetFlusher {
-            sheets: self.collection.flush(),
-            had_invalidations,
-        }
+        let invalidation_stats = self.invalidations.flush_for_shadow_host(host, snapshots);
+        AuthorStylesheetFlusher::new(&mut self.collection, invalidation_stats)
+    }
+
+    /// Flush the stylesheets for this author set, but without running any of
+    /// the invalidation passes.
+    pub fn flush_without_invalidation(&mut self) -> AuthorStylesheetFlusher<S> {
+        self.invalidations.clear();
+        AuthorStylesheetFlusher::new(&mut self.collection, InvalidationStats::default())
     }
 }
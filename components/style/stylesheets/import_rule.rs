@@ -33,7 +33,10 @@ pub enum ImportSheet {
     Sheet(crate::gecko::data::GeckoStyleSheet),
     /// An @import created while parsing off-main-thread, whose Gecko sheet has
     /// yet to be created and attached.
-    Pending(PendingSheet),
+    ///
+    /// This is behind an `Arc` so that it has a stable address to use as its
+    /// identity.
+    Pending(::servo_arc::Arc<PendingSheet>),
 }
 
 #[cfg(feature = "gecko")]
@@ -45,10 +48,10 @@ impl ImportSheet {
 
     /// Creates a pending ImportSheet for a load that has not started yet.
     pub fn new_pending(origin: Origin, quirks_mode: QuirksMode) -> Self {
-        ImportSheet::Pending(PendingSheet {
+        ImportSheet::Pending(::servo_arc::Arc::new(PendingSheet {
             origin,
             quirks_mode,
-        })
+        }))
     }
 
     /// Returns a reference to the GeckoStyleSheet in this ImportSheet, if it
@@ -78,7 +81,9 @@ impl DeepCloneWithLock for ImportSheet {
                 };
                 ImportSheet::Sheet(unsafe { GeckoStyleSheet::from_addrefed(clone) })
             },
-            ImportSheet::Pending(ref p) => ImportSheet::Pending(p.clone()),
+            ImportSheet::Pending(ref p) => {
+                ImportSheet::Pending(::servo_arc::Arc::new((**p).clone()))
+            },
         }
     }
 }
@@ -119,6 +124,13 @@ impl StylesheetInDocument for ImportSheet {
             ImportSheet::Pending(_) => &[],
         }
     }
+
+    fn identity(&self) -> usize {
+        match *self {
+            ImportSheet::Sheet(ref s) => s.identity(),
+            ImportSheet::Pending(ref p) => &**p as *const PendingSheet as usize,
+        }
+    }
 }
 
 /// A sheet that is held from an import rule.
@@ -147,6 +159,10 @@ impl StylesheetInDocument for ImportSheet {
     fn rules<'a, 'b: 'a>(&'a self, guard: &'b SharedRwLockReadGuard) -> &'a [CssRule] {
         self.0.rules(guard)
     }
+
+    fn identity(&self) -> usize {
+        self.0.identity()
+    }
 }
 
 #[cfg(feature = "servo")]
@@ -211,6 +211,17 @@ pub enum CssRuleType {
     Viewport = 15,
 }
 
+/// The kind of change that happened to a rule in a stylesheet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RuleChangeKind {
+    /// A rule was inserted into the stylesheet.
+    Insert,
+    /// A rule was removed from the stylesheet.
+    Remove,
+    /// The declarations of a style rule changed.
+    StyleDeclarationChange,
+}
+
 #[allow(missing_docs)]
 pub enum RulesMutateError {
     Syntax,
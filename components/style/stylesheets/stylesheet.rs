@@ -197,6 +197,21 @@ pub trait StylesheetInDocument: ::std::fmt::Debug {
     /// Returns a reference to the list of rules in this stylesheet.
     fn rules<'a, 'b: 'a>(&'a self, guard: &'b SharedRwLockReadGuard) -> &'a [CssRule];
 
+    /// Returns a cheap key identifying this stylesheet.
+    ///
+    /// Two sheets that compare equal must return the same key, which allows
+    /// stylesheet sets to look sheets up without comparing them one by one.
+    ///
+    /// The key must also stay the same for as long as the sheet is in a set,
+    /// even though sets move their sheets around as they grow. So for handles
+    /// to a shared sheet, this should be the address of the sheet they point
+    /// to, not the address of the handle itself.
+    ///
+    /// There's no default implementation: the address of `self` changes when
+    /// the sheet moves, and the trait has no other address to key on without
+    /// a lock guard, so each implementor has to pick a stable one.
+    fn identity(&self) -> usize;
+
     /// Return an iterator using the condition `C`.
     #[inline]
     fn iter_rules<'a, 'b, C>(
@@ -264,6 +279,14 @@ impl StylesheetInDocument for Stylesheet {
     fn rules<'a, 'b: 'a>(&'a self, guard: &'b SharedRwLockReadGuard) -> &'a [CssRule] {
         self.contents.rules(guard)
     }
+
+    /// The address of the media list, which lives on the heap and so doesn't
+    /// move with the sheet, and which isn't shared with any other sheet (even
+    /// clones get their own).
+    #[inline]
+    fn identity(&self) -> usize {
+        &*self.media as *const Locked<MediaList> as usize
+    }
 }
 
 /// A simple wrapper over an `Arc<Stylesheet>`, with pointer comparison, and
@@ -307,6 +330,12 @@ impl StylesheetInDocument for DocumentStyleSheet {
     fn rules<'a, 'b: 'a>(&'a self, guard: &'b SharedRwLockReadGuard) -> &'a [CssRule] {
         self.0.rules(guard)
     }
+
+    /// The identity of the shared sheet, which `PartialEq` compares.
+    #[inline]
+    fn identity(&self) -> usize {
+        self.0.identity()
+    }
 }
 
 impl Stylesheet {
@@ -22,8 +22,9 @@ use crate::rule_tree::{CascadeLevel, RuleTree, ShadowCascadeOrder, StrongRuleNod
 use crate::selector_map::{PrecomputedHashMap, PrecomputedHashSet, SelectorMap, SelectorMapEntry};
 use crate::selector_parser::{PerPseudoElementMap, PseudoElement, SelectorImpl, SnapshotMap};
 use crate::shared_lock::{Locked, SharedRwLockReadGuard, StylesheetGuards};
+use crate::stylesheet_set::StylesheetSetReadView;
 use crate::stylesheet_set::{DataValidity, DocumentStylesheetSet, SheetRebuildKind};
-use crate::stylesheet_set::{DocumentStylesheetFlusher, SheetCollectionFlusher};
+use crate::stylesheet_set::{DocumentStylesheetFlusher, InsertError, SheetCollectionFlusher};
 use crate::stylesheets::keyframes_rule::KeyframesAnimation;
 use crate::stylesheets::viewport_rule::{self, MaybeNew, ViewportRule};
 use crate::stylesheets::StyleRule;
@@ -237,7 +238,8 @@ impl DocumentCascadeData {
 
     /// Rebuild the cascade data for the given document stylesheets, and
     /// optionally with a set of user agent stylesheets.  Returns Err(..)
-    /// to signify OOM.
+    /// to signify OOM, in which case the flusher is dropped without being
+    /// completed, so the stylesheets are flushed again next time.
     fn rebuild<'a, S>(
         &mut self,
         device: &Device,
@@ -277,6 +279,7 @@ impl DocumentCascadeData {
             guards.author,
         )?;
 
+        flusher.complete();
         Ok(())
     }
 
@@ -535,7 +538,9 @@ impl Stylist {
             }
         }
 
-        let flusher = self.stylesheets.flush(document_element, snapshots);
+        let flusher = self
+            .stylesheets
+            .flush(&self.device, guards, document_element, snapshots);
 
         let had_invalidations = flusher.had_invalidations();
 
@@ -552,7 +557,7 @@ impl Stylist {
         sheet: StylistSheet,
         before_sheet: StylistSheet,
         guard: &SharedRwLockReadGuard,
-    ) {
+    ) -> Result<(), InsertError<StylistSheet>> {
         self.stylesheets
             .insert_stylesheet_before(Some(&self.device), sheet, before_sheet, guard)
     }
@@ -576,14 +581,28 @@ impl Stylist {
         self.stylesheets.has_changed()
     }
 
+    /// Returns a read-only view of the document stylesheets.
+    ///
+    /// This is what the worker threads of a parallel traversal use to look at
+    /// the list of stylesheets, instead of poking at the set directly.
+    pub fn stylesheets(&self) -> StylesheetSetReadView<StylistSheet> {
+        self.stylesheets.read_view()
+    }
+
     /// Appends a new stylesheet to the current set.
     pub fn append_stylesheet(&mut self, sheet: StylistSheet, guard: &SharedRwLockReadGuard) {
         self.stylesheets
-            .append_stylesheet(Some(&self.device), sheet, guard)
+            .append_stylesheet(Some(&self.device), sheet, guard);
     }
 
     /// Remove a given stylesheet to the current set.
-    pub fn remove_stylesheet(&mut self, sheet: StylistSheet, guard: &SharedRwLockReadGuard) {
+    ///
+    /// Returns whether the sheet was in the set.
+    pub fn remove_stylesheet(
+        &mut self,
+        sheet: StylistSheet,
+        guard: &SharedRwLockReadGuard,
+    ) -> bool {
         self.stylesheets
             .remove_stylesheet(Some(&self.device), sheet, guard)
     }
@@ -1775,11 +1794,14 @@ impl CascadeData {
 
     /// Rebuild the cascade data from a given SheetCollection, incrementally if
     /// possible.
+    ///
+    /// On OOM, the sheets that were already added stay committed, and the rest
+    /// are flushed again next time.
     pub fn rebuild<'a, S>(
         &mut self,
         device: &Device,
         quirks_mode: QuirksMode,
-        collection: SheetCollectionFlusher<S>,
+        mut collection: SheetCollectionFlusher<S>,
         guard: &SharedRwLockReadGuard,
     ) -> Result<(), FailedAllocationError>
     where
@@ -1794,10 +1816,11 @@ impl CascadeData {
         match validity {
             DataValidity::Valid => {},
             DataValidity::CascadeInvalid => self.clear_cascade_data(),
+            DataValidity::InvalidationInvalid => self.clear_invalidation_data(),
             DataValidity::FullyInvalid => self.clear(),
         }
 
-        for (stylesheet, rebuild_kind) in collection {
+        while let Some((stylesheet, rebuild_kind)) = collection.next() {
             self.add_stylesheet(
                 device,
                 quirks_mode,
@@ -1806,8 +1829,10 @@ impl CascadeData {
                 rebuild_kind,
                 /* precomputed_pseudo_element_decls = */ None,
             )?;
+            collection.sheet_done();
         }
 
+        collection.finish();
         Ok(())
     }
 
@@ -1906,6 +1931,7 @@ impl CascadeData {
         }
 
         let origin = stylesheet.origin(guard);
+        let rebuild_cascade = rebuild_kind.should_rebuild_cascade();
 
         if rebuild_kind.should_rebuild_invalidation() {
             self.effective_media_query_results.saw_effective(stylesheet);
@@ -1915,9 +1941,13 @@ impl CascadeData {
             match *rule {
                 CssRule::Style(ref locked) => {
                     let style_rule = locked.read_with(&guard);
-                    self.num_declarations += style_rule.block.read_with(&guard).len();
+                    if rebuild_cascade {
+                        self.num_declarations += style_rule.block.read_with(&guard).len();
+                    }
                     for selector in &style_rule.selectors.0 {
-                        self.num_selectors += 1;
+                        if rebuild_cascade {
+                            self.num_selectors += 1;
+                        }
 
                         let pseudo_element = selector.pseudo_element();
 
@@ -1926,6 +1956,10 @@ impl CascadeData {
                                 debug_assert!(selector.is_universal());
                                 debug_assert!(matches!(origin, Origin::UserAgent));
 
+                                if !rebuild_cascade {
+                                    continue;
+                                }
+
                                 precomputed_pseudo_element_decls
                                     .as_mut()
                                     .expect("Expected precomputed declarations for the UA level")
@@ -1977,6 +2011,10 @@ impl CascadeData {
                             }
                         }
 
+                        if !rebuild_cascade {
+                            continue;
+                        }
+
                         // NOTE(emilio): It's fine to look at :host and then at
                         // ::slotted(..), since :host::slotted(..) could never
                         // possibly match, as <slot> is not a valid shadow host.
@@ -1992,7 +2030,9 @@ impl CascadeData {
 
                         rules.insert(rule, pseudo_element, quirks_mode)?;
                     }
-                    self.rules_source_order += 1;
+                    if rebuild_cascade {
+                        self.rules_source_order += 1;
+                    }
                 },
                 CssRule::Import(ref lock) => {
                     if rebuild_kind.should_rebuild_invalidation() {
@@ -2010,7 +2050,7 @@ impl CascadeData {
                         self.effective_media_query_results.saw_effective(media_rule);
                     }
                 },
-                CssRule::Keyframes(ref keyframes_rule) => {
+                CssRule::Keyframes(ref keyframes_rule) if rebuild_cascade => {
                     let keyframes_rule = keyframes_rule.read_with(guard);
                     debug!("Found valid keyframes rule: {:?}", *keyframes_rule);
 
@@ -2031,19 +2071,19 @@ impl CascadeData {
                     }
                 },
                 #[cfg(feature = "gecko")]
-                CssRule::FontFace(ref rule) => {
+                CssRule::FontFace(ref rule) if rebuild_cascade => {
                     self.extra_data.add_font_face(rule);
                 },
                 #[cfg(feature = "gecko")]
-                CssRule::FontFeatureValues(ref rule) => {
+                CssRule::FontFeatureValues(ref rule) if rebuild_cascade => {
                     self.extra_data.add_font_feature_values(rule);
                 },
                 #[cfg(feature = "gecko")]
-                CssRule::CounterStyle(ref rule) => {
+                CssRule::CounterStyle(ref rule) if rebuild_cascade => {
                     self.extra_data.add_counter_style(guard, rule);
                 },
                 #[cfg(feature = "gecko")]
-                CssRule::Page(ref rule) => {
+                CssRule::Page(ref rule) if rebuild_cascade => {
                     self.extra_data.add_page(rule);
                 },
                 // We don't care about any other rule.
@@ -2166,8 +2206,8 @@ impl CascadeData {
         self.num_declarations = 0;
     }
 
-    fn clear(&mut self) {
-        self.clear_cascade_data();
+    /// Clears the invalidation data, but not the cascade data.
+    fn clear_invalidation_data(&mut self) {
         self.invalidation_map.clear();
         self.attribute_dependencies.clear();
         self.state_dependencies = ElementState::empty();
@@ -2177,6 +2217,11 @@ impl CascadeData {
         self.effective_media_query_results.clear();
     }
 
+    fn clear(&mut self) {
+        self.clear_cascade_data();
+        self.clear_invalidation_data();
+    }
+
     /// Measures heap usage.
     #[cfg(feature = "gecko")]
     fn add_size_of(&self, ops: &mut MallocSizeOfOps, sizes: &mut ServoStyleSetSizes) {
@@ -6,10 +6,12 @@
 #![feature(test)]
 
 extern crate app_units;
+extern crate atomic_refcell;
 extern crate cssparser;
 extern crate euclid;
 #[macro_use]
 extern crate html5ever;
+extern crate malloc_size_of;
 extern crate parking_lot;
 extern crate rayon;
 extern crate selectors;
@@ -29,12 +31,14 @@ mod animated_properties;
 mod attr;
 mod custom_properties;
 mod logical_geometry;
+mod mock_element;
 mod parsing;
 mod properties;
 mod rule_tree;
 mod size_of;
 mod specified_values;
 mod str;
+mod stylesheet_set;
 mod stylesheets;
 mod stylist;
 mod viewport;
@@ -0,0 +1,433 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! A minimal DOM with a single root element, for the tests that need to flush
+//! stylesheet invalidations against an actual element.
+
+use atomic_refcell::{AtomicRefCell, AtomicRefMut};
+use selectors::attr::{AttrSelectorOperation, CaseSensitivity, NamespaceConstraint};
+use selectors::matching::VisitedHandlingMode;
+use selectors::matching::{ElementSelectorFlags, MatchingContext, QuirksMode};
+use selectors::sink::Push;
+use selectors::{Element, OpaqueElement};
+use servo_arc::{Arc, ArcBorrow};
+use servo_atoms::Atom;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::iter;
+use style::applicable_declarations::ApplicableDeclarationBlock;
+use style::data::ElementData;
+use style::dom::{LayoutIterator, NodeInfo, OpaqueNode};
+use style::dom::{TDocument, TElement, TNode, TShadowRoot};
+use style::element_state::ElementState;
+use style::font_metrics::ServoMetricsProvider;
+use style::invalidation::element::restyle_hints::RestyleHint;
+use style::properties::{ComputedValues, PropertyDeclarationBlock};
+use style::selector_parser::{AttrValue, Lang, NonTSPseudoClass, PseudoElement, SelectorImpl};
+use style::shared_lock::Locked;
+use style::stylist::CascadeData;
+use style::{LocalName, Namespace};
+
+/// A document whose only node is an unstyled `<html>` root element.
+pub struct MockDocumentData {
+    local_name: LocalName,
+    namespace: Namespace,
+    data: AtomicRefCell<ElementData>,
+}
+
+impl MockDocumentData {
+    pub fn new() -> Self {
+        MockDocumentData {
+            local_name: local_name!("html"),
+            namespace: ns!(html),
+            data: AtomicRefCell::new(ElementData::default()),
+        }
+    }
+
+    /// The root element of this document.
+    pub fn root(&self) -> MockElement {
+        MockElement(self)
+    }
+
+    /// Gives the root element its initial style, so that scoped invalidations
+    /// get processed for it, like for an element that was already styled.
+    pub fn style_root(&self) {
+        let style = ComputedValues::initial_values().clone();
+        self.data.borrow_mut().styles.primary = Some(Arc::new(style));
+    }
+
+    /// Takes the restyle hint that flushing invalidations left in the root
+    /// element, if any.
+    pub fn take_root_hint(&self) -> bool {
+        let mut data = self.data.borrow_mut();
+        let had_hint = !data.hint.is_empty();
+        data.hint = RestyleHint::empty();
+        had_hint
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct MockElement<'a>(&'a MockDocumentData);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MockNode<'a>(MockElement<'a>);
+
+#[derive(Clone, Copy)]
+pub struct MockDocument<'a>(&'a MockDocumentData);
+
+/// There are no shadow roots in a `MockDocumentData`, so this is never
+/// constructed.
+#[derive(Clone, Copy, PartialEq)]
+pub struct MockShadowRoot<'a>(MockElement<'a>);
+
+impl<'a> PartialEq for MockElement<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 as *const _ == other.0 as *const _
+    }
+}
+
+impl<'a> Eq for MockElement<'a> {}
+
+impl<'a> Hash for MockElement<'a> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.0 as *const MockDocumentData).hash(state)
+    }
+}
+
+impl<'a> fmt::Debug for MockElement<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<{}> ({:p})", self.0.local_name, self.0)
+    }
+}
+
+impl<'a> NodeInfo for MockNode<'a> {
+    fn is_element(&self) -> bool {
+        true
+    }
+
+    fn is_text_node(&self) -> bool {
+        false
+    }
+}
+
+impl<'a> TNode for MockNode<'a> {
+    type ConcreteElement = MockElement<'a>;
+    type ConcreteDocument = MockDocument<'a>;
+    type ConcreteShadowRoot = MockShadowRoot<'a>;
+
+    fn parent_node(&self) -> Option<Self> {
+        None
+    }
+
+    fn first_child(&self) -> Option<Self> {
+        None
+    }
+
+    fn last_child(&self) -> Option<Self> {
+        None
+    }
+
+    fn prev_sibling(&self) -> Option<Self> {
+        None
+    }
+
+    fn next_sibling(&self) -> Option<Self> {
+        None
+    }
+
+    fn owner_doc(&self) -> MockDocument<'a> {
+        MockDocument((self.0).0)
+    }
+
+    fn is_in_document(&self) -> bool {
+        true
+    }
+
+    fn traversal_parent(&self) -> Option<MockElement<'a>> {
+        None
+    }
+
+    fn opaque(&self) -> OpaqueNode {
+        OpaqueNode((self.0).0 as *const MockDocumentData as usize)
+    }
+
+    fn debug_id(self) -> usize {
+        self.opaque().0
+    }
+
+    fn as_element(&self) -> Option<MockElement<'a>> {
+        Some(self.0)
+    }
+
+    fn as_document(&self) -> Option<MockDocument<'a>> {
+        None
+    }
+
+    fn as_shadow_root(&self) -> Option<MockShadowRoot<'a>> {
+        None
+    }
+}
+
+impl<'a> TDocument for MockDocument<'a> {
+    type ConcreteNode = MockNode<'a>;
+
+    fn as_node(&self) -> MockNode<'a> {
+        unreachable!("MockDocument has no node of its own")
+    }
+
+    fn is_html_document(&self) -> bool {
+        true
+    }
+
+    fn quirks_mode(&self) -> QuirksMode {
+        QuirksMode::NoQuirks
+    }
+}
+
+impl<'a> TShadowRoot for MockShadowRoot<'a> {
+    type ConcreteNode = MockNode<'a>;
+
+    fn as_node(&self) -> MockNode<'a> {
+        unreachable!("MockShadowRoot is never constructed")
+    }
+
+    fn host(&self) -> MockElement<'a> {
+        unreachable!("MockShadowRoot is never constructed")
+    }
+
+    fn style_data<'b>(&self) -> Option<&'b CascadeData>
+    where
+        Self: 'b,
+    {
+        unreachable!("MockShadowRoot is never constructed")
+    }
+}
+
+impl<'a> TElement for MockElement<'a> {
+    type ConcreteNode = MockNode<'a>;
+    type TraversalChildrenIterator = iter::Empty<MockNode<'a>>;
+    type FontMetricsProvider = ServoMetricsProvider;
+
+    fn as_node(&self) -> MockNode<'a> {
+        MockNode(*self)
+    }
+
+    fn traversal_children(&self) -> LayoutIterator<Self::TraversalChildrenIterator> {
+        LayoutIterator(iter::empty())
+    }
+
+    fn is_html_element(&self) -> bool {
+        true
+    }
+
+    fn is_mathml_element(&self) -> bool {
+        false
+    }
+
+    fn is_svg_element(&self) -> bool {
+        false
+    }
+
+    fn style_attribute(&self) -> Option<ArcBorrow<Locked<PropertyDeclarationBlock>>> {
+        None
+    }
+
+    fn state(&self) -> ElementState {
+        ElementState::empty()
+    }
+
+    fn has_attr(&self, _: &Namespace, _: &LocalName) -> bool {
+        false
+    }
+
+    fn id(&self) -> Option<&Atom> {
+        None
+    }
+
+    fn each_class<F>(&self, _: F)
+    where
+        F: FnMut(&Atom),
+    {
+    }
+
+    fn has_dirty_descendants(&self) -> bool {
+        false
+    }
+
+    fn has_snapshot(&self) -> bool {
+        false
+    }
+
+    fn handled_snapshot(&self) -> bool {
+        true
+    }
+
+    unsafe fn set_handled_snapshot(&self) {}
+
+    unsafe fn set_dirty_descendants(&self) {}
+
+    unsafe fn unset_dirty_descendants(&self) {}
+
+    fn store_children_to_process(&self, _: isize) {}
+
+    fn did_process_child(&self) -> isize {
+        0
+    }
+
+    unsafe fn ensure_data(&self) -> AtomicRefMut<ElementData> {
+        self.0.data.borrow_mut()
+    }
+
+    unsafe fn clear_data(&self) {
+        *self.0.data.borrow_mut() = ElementData::default();
+    }
+
+    fn get_data(&self) -> Option<&AtomicRefCell<ElementData>> {
+        Some(&self.0.data)
+    }
+
+    fn skip_item_display_fixup(&self) -> bool {
+        false
+    }
+
+    unsafe fn set_selector_flags(&self, _: ElementSelectorFlags) {}
+
+    fn has_selector_flags(&self, _: ElementSelectorFlags) -> bool {
+        false
+    }
+
+    fn has_animations(&self) -> bool {
+        false
+    }
+
+    fn has_css_animations(&self) -> bool {
+        false
+    }
+
+    fn has_css_transitions(&self) -> bool {
+        false
+    }
+
+    fn shadow_root(&self) -> Option<MockShadowRoot<'a>> {
+        None
+    }
+
+    fn containing_shadow(&self) -> Option<MockShadowRoot<'a>> {
+        None
+    }
+
+    fn lang_attr(&self) -> Option<AttrValue> {
+        None
+    }
+
+    fn match_element_lang(&self, _: Option<Option<AttrValue>>, _: &Lang) -> bool {
+        false
+    }
+
+    fn is_html_document_body_element(&self) -> bool {
+        false
+    }
+
+    fn synthesize_presentational_hints_for_legacy_attributes<V>(
+        &self,
+        _: VisitedHandlingMode,
+        _: &mut V,
+    ) where
+        V: Push<ApplicableDeclarationBlock>,
+    {
+    }
+}
+
+impl<'a> Element for MockElement<'a> {
+    type Impl = SelectorImpl;
+
+    fn opaque(&self) -> OpaqueElement {
+        OpaqueElement::new(self.0)
+    }
+
+    fn parent_element(&self) -> Option<Self> {
+        None
+    }
+
+    fn parent_node_is_shadow_root(&self) -> bool {
+        false
+    }
+
+    fn containing_shadow_host(&self) -> Option<Self> {
+        None
+    }
+
+    fn prev_sibling_element(&self) -> Option<Self> {
+        None
+    }
+
+    fn next_sibling_element(&self) -> Option<Self> {
+        None
+    }
+
+    fn is_html_element_in_html_document(&self) -> bool {
+        true
+    }
+
+    fn local_name(&self) -> &LocalName {
+        &self.0.local_name
+    }
+
+    fn namespace(&self) -> &Namespace {
+        &self.0.namespace
+    }
+
+    fn attr_matches(
+        &self,
+        _: &NamespaceConstraint<&Namespace>,
+        _: &LocalName,
+        _: &AttrSelectorOperation<&String>,
+    ) -> bool {
+        false
+    }
+
+    fn match_non_ts_pseudo_class<F>(
+        &self,
+        _: &NonTSPseudoClass,
+        _: &mut MatchingContext<SelectorImpl>,
+        _: &mut F,
+    ) -> bool
+    where
+        F: FnMut(&Self, ElementSelectorFlags),
+    {
+        false
+    }
+
+    fn match_pseudo_element(
+        &self,
+        _: &PseudoElement,
+        _: &mut MatchingContext<SelectorImpl>,
+    ) -> bool {
+        false
+    }
+
+    fn is_link(&self) -> bool {
+        false
+    }
+
+    fn is_html_slot_element(&self) -> bool {
+        false
+    }
+
+    fn has_id(&self, _: &Atom, _: CaseSensitivity) -> bool {
+        false
+    }
+
+    fn has_class(&self, _: &Atom, _: CaseSensitivity) -> bool {
+        false
+    }
+
+    fn is_empty(&self) -> bool {
+        true
+    }
+
+    fn is_root(&self) -> bool {
+        true
+    }
+}
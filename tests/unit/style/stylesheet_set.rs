@@ -0,0 +1,3099 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use cssparser::{Parser, ParserInput, SourceLocation};
+use euclid::{TypedScale, TypedSize2D};
+use malloc_size_of::{MallocSizeOf, MallocSizeOfOps};
+use mock_element::{MockDocumentData, MockElement};
+use rayon;
+use servo_arc;
+use servo_url::ServoUrl;
+use std::cell::Cell;
+use std::os::raw::c_void;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::usize;
+use style::context::QuirksMode;
+use style::invalidation::stylesheets::SharedStylesheetInvalidationSet;
+use style::invalidation::stylesheets::StylesheetInvalidationSet;
+use style::invalidation::stylesheets::{FullInvalidationReason, InvalidationStats};
+use style::media_queries::{Device, MediaList, MediaType};
+use style::parser::ParserContext;
+use style::shared_lock::{SharedRwLock, SharedRwLockReadGuard, StylesheetGuards};
+use style::stylesheet_set::{
+    AuthorStylesheetSet, DataValidity, DocumentStylesheetSet, InsertError, SheetRebuildKind,
+    StylesheetSetError,
+};
+use style::stylesheets::import_rule::ImportSheet;
+use style::stylesheets::{CssRule, CssRuleType, DocumentStyleSheet, Origin, OriginSet};
+use style::stylesheets::{ImportRule, RuleChangeKind, Stylesheet, StylesheetInDocument};
+use style::values::CssUrl;
+use style_traits::ParsingMode;
+use test::{self, Bencher};
+
+/// A stylesheet without any rules, identified by `id`.
+///
+/// If it has a `budget`, comparing or identifying it panics after a given
+/// number of calls, shared across all the sheets with the same budget.
+#[derive(Debug)]
+struct MockSheet {
+    id: usize,
+    origin: Origin,
+    budget: Option<Rc<Cell<usize>>>,
+}
+
+impl MockSheet {
+    fn new(id: usize, origin: Origin) -> Self {
+        MockSheet {
+            id,
+            origin,
+            budget: None,
+        }
+    }
+
+    fn author(id: usize) -> Self {
+        Self::new(id, Origin::Author)
+    }
+
+    fn panicky(id: usize, budget: &Rc<Cell<usize>>) -> Self {
+        MockSheet {
+            budget: Some(budget.clone()),
+            ..Self::author(id)
+        }
+    }
+
+    fn spend(&self) {
+        if let Some(ref budget) = self.budget {
+            let left = budget.get();
+            if left == 0 {
+                panic!("Out of budget");
+            }
+            budget.set(left - 1);
+        }
+    }
+}
+
+impl PartialEq for MockSheet {
+    fn eq(&self, other: &Self) -> bool {
+        self.spend();
+        self.id == other.id && self.origin == other.origin
+    }
+}
+
+impl StylesheetInDocument for MockSheet {
+    fn origin(&self, _guard: &SharedRwLockReadGuard) -> Origin {
+        self.origin
+    }
+
+    fn quirks_mode(&self, _guard: &SharedRwLockReadGuard) -> QuirksMode {
+        QuirksMode::NoQuirks
+    }
+
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn media<'a>(&'a self, _guard: &'a SharedRwLockReadGuard) -> Option<&'a MediaList> {
+        None
+    }
+
+    fn rules<'a, 'b: 'a>(&'a self, _guard: &'b SharedRwLockReadGuard) -> &'a [CssRule] {
+        &[]
+    }
+
+    fn identity(&self) -> usize {
+        self.spend();
+        self.id
+    }
+}
+
+impl MallocSizeOf for MockSheet {
+    fn size_of(&self, _ops: &mut MallocSizeOfOps) -> usize {
+        0
+    }
+}
+
+fn assert_index_consistent(set: &AuthorStylesheetSet<MockSheet>) {
+    for (i, sheet) in set.iter().enumerate() {
+        assert_eq!(set.position_of(sheet), Some(i));
+    }
+}
+
+#[test]
+fn test_index_stress() {
+    const COUNT: usize = 10_000;
+
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = AuthorStylesheetSet::new();
+
+    for id in 0..COUNT {
+        set.append_stylesheet(None, MockSheet::author(id), &guard);
+    }
+    assert_index_consistent(&set);
+
+    // Remove every other sheet, checking the index every now and then.
+    for id in (0..COUNT).filter(|id| id % 2 == 0) {
+        assert!(set.contains(&MockSheet::author(id)));
+        set.remove_stylesheet(None, MockSheet::author(id), &guard);
+        assert!(!set.contains(&MockSheet::author(id)));
+        if id % 1000 == 0 {
+            assert_index_consistent(&set);
+        }
+    }
+    assert_index_consistent(&set);
+
+    // Re-insert them in the middle of the list.
+    for id in (0..COUNT).filter(|id| id % 2 == 0).take(100) {
+        set.insert_stylesheet_before(
+            None,
+            MockSheet::author(id),
+            MockSheet::author(id + 1),
+            &guard,
+        )
+        .unwrap();
+    }
+    assert_index_consistent(&set);
+
+    let expected = (0..COUNT)
+        .filter(|id| id % 2 != 0 || *id < 200)
+        .collect::<Vec<_>>();
+    let ids = set.iter().map(|s| s.id).collect::<Vec<_>>();
+    assert_eq!(ids, expected);
+}
+
+#[test]
+fn test_panic_safety() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+
+    for calls_before_panic in 0..10 {
+        let budget = Rc::new(Cell::new(usize::MAX));
+        let sheet = |id| MockSheet::panicky(id, &budget);
+
+        let mut set = DocumentStylesheetSet::new();
+        for id in 0..5 {
+            set.append_stylesheet(None, sheet(id), &guard);
+        }
+        set.flush_without_invalidation();
+
+        budget.set(calls_before_panic);
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            set.insert_stylesheet_before(None, sheet(5), sheet(2), &guard)
+                .unwrap();
+        }));
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            set.remove_stylesheet(None, sheet(3), &guard);
+        }));
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            set.append_stylesheet(None, sheet(6), &guard);
+        }));
+        budget.set(usize::MAX);
+
+        assert!(set.is_consistent_for_testing());
+        assert!(set.iter().count() >= 4);
+        set.flush_without_invalidation();
+        assert!(!set.has_changed());
+        assert!(set.is_consistent_for_testing());
+    }
+}
+
+/// Mutating a set whose bookkeeping got out of sync with its sheets, like
+/// after appending a sheet twice, panics in debug builds, instead of
+/// silently leaving a ghost sheet behind.
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "Stylesheet index out of sync")]
+fn test_ordering_consistency_check() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+    set.append_unchecked_for_testing(MockSheet::author(0), &guard);
+    assert!(!set.is_consistent_for_testing());
+
+    set.append_stylesheet(None, MockSheet::author(1), &guard);
+}
+
+#[test]
+fn test_disabled_sheets_keep_their_index() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+
+    for id in 0..3 {
+        set.append_stylesheet(None, MockSheet::author(id), &guard);
+    }
+    set.flush_without_invalidation();
+
+    set.set_stylesheet_enabled(None, &MockSheet::author(1), false, &guard);
+    assert!(set.has_changed());
+    assert_eq!(
+        set.data_validity(Origin::Author),
+        DataValidity::CascadeInvalid
+    );
+
+    let ids = set.iter().map(|(s, _)| s.id).collect::<Vec<_>>();
+    assert_eq!(ids, vec![0, 2]);
+    assert_eq!(set.get(Origin::Author, 1), Some(&MockSheet::author(1)));
+    assert_eq!(
+        set.position_of(&MockSheet::author(1), &guard),
+        Some((Origin::Author, 1))
+    );
+
+    set.set_stylesheet_enabled(None, &MockSheet::author(1), true, &guard);
+    let ids = set.iter().map(|(s, _)| s.id).collect::<Vec<_>>();
+    assert_eq!(ids, vec![0, 1, 2]);
+
+    // `set_disabled` is the same toggle, the other way around.
+    set.set_disabled(None, &MockSheet::author(2), true, &guard);
+    let ids = set.iter().map(|(s, _)| s.id).collect::<Vec<_>>();
+    assert_eq!(ids, vec![0, 1]);
+    set.set_disabled(None, &MockSheet::author(2), false, &guard);
+    let ids = set.iter().map(|(s, _)| s.id).collect::<Vec<_>>();
+    assert_eq!(ids, vec![0, 1, 2]);
+}
+
+#[test]
+fn test_read_views_across_threads() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    for id in 0..10 {
+        set.append_stylesheet(None, MockSheet::author(id), &guard);
+    }
+
+    {
+        let view = set.read_view();
+        rayon::scope(|scope| {
+            for id in 0..10 {
+                scope.spawn(move |_| {
+                    assert_eq!(view.len(), 10);
+                    assert_eq!(view.iter().count(), 10);
+                    assert_eq!(view.get(Origin::Author, id).map(|s| s.id), Some(id));
+                    let (sheet, origin) = view.find_by_key(id).unwrap();
+                    assert_eq!((sheet.id, origin), (id, Origin::Author));
+                });
+            }
+        });
+    }
+
+    // Once all the views are gone, we can mutate the set again.
+    set.append_stylesheet(None, MockSheet::author(10), &guard);
+    assert_eq!(set.read_view().len(), 11);
+}
+
+#[test]
+fn test_remove_missing_sheet() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+    set.append_stylesheet(None, MockSheet::author(1), &guard);
+
+    // Removing twice.
+    assert!(set.remove_stylesheet(None, MockSheet::author(0), &guard));
+    assert!(!set.remove_stylesheet(None, MockSheet::author(0), &guard));
+
+    // Removing a sheet that was never added.
+    assert!(!set.remove_stylesheet(None, MockSheet::author(2), &guard));
+
+    // Removing a sheet from an origin it's not in.
+    let user_sheet = MockSheet::new(1, Origin::User);
+    assert!(!set.remove_stylesheet(None, user_sheet, &guard));
+
+    let ids = set.iter().map(|(s, _)| s.id).collect::<Vec<_>>();
+    assert_eq!(ids, vec![1]);
+    assert!(set.is_consistent_for_testing());
+}
+
+#[test]
+fn test_author_set_flush() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = AuthorStylesheetSet::new();
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+
+    // Dropping the flusher without completing the flush leaves the set
+    // dirty.
+    for _ in 0..2 {
+        let mut flusher = set.flush_without_invalidation();
+        assert_eq!(flusher.dirty_origins(), OriginSet::ORIGIN_AUTHOR);
+        let sheets = flusher.sheets().collect::<Vec<_>>();
+        assert_eq!(sheets.len(), 1);
+        assert_eq!(sheets[0].0.id, 0);
+        assert!(sheets[0].1.should_rebuild_invalidation());
+    }
+    assert!(set.dirty());
+
+    let mut flusher = set.flush_without_invalidation();
+    assert_eq!(flusher.sheets().count(), 1);
+    flusher.complete();
+    assert!(!set.dirty());
+
+    let mut flusher = set.flush_without_invalidation();
+    assert_eq!(flusher.dirty_origins(), OriginSet::empty());
+    assert_eq!(flusher.sheets().count(), 0);
+}
+
+#[test]
+fn test_abandoned_flush_commits_only_processed_sheets() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = AuthorStylesheetSet::new();
+    for id in 0..3 {
+        set.append_stylesheet(None, MockSheet::author(id), &guard);
+    }
+
+    // Iterating the sheets doesn't commit them on its own.
+    {
+        let mut flusher = set.flush_without_invalidation();
+        assert_eq!(flusher.sheets().count(), 3);
+    }
+
+    // Bail out while processing the second sheet.
+    {
+        let mut flusher = set.flush_without_invalidation();
+        let mut sheets = flusher.sheets();
+        assert_eq!(sheets.next().map(|(s, _)| s.id), Some(0));
+        sheets.sheet_done();
+        assert_eq!(sheets.next().map(|(s, _)| s.id), Some(1));
+    }
+
+    // The next flush picks up where it left.
+    set.append_stylesheet(None, MockSheet::author(3), &guard);
+    let mut flusher = set.flush_without_invalidation();
+    let sheets = flusher
+        .sheets()
+        .map(|(s, rebuild_kind)| (s.id, rebuild_kind))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        sheets,
+        vec![
+            (1, SheetRebuildKind::Full),
+            (2, SheetRebuildKind::Full),
+            (3, SheetRebuildKind::Full),
+        ]
+    );
+    flusher.complete();
+
+    let mut flusher = set.flush_without_invalidation();
+    assert_eq!(flusher.sheets().count(), 0);
+}
+
+#[test]
+fn test_try_append_stylesheets() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+
+    let mut set = DocumentStylesheetSet::new();
+    set.try_append_stylesheets(None, (0..4).map(MockSheet::author), &guard)
+        .unwrap();
+    let ids = set.iter().map(|(s, _)| s.id).collect::<Vec<_>>();
+    assert_eq!(ids, vec![0, 1, 2, 3]);
+    assert!(set.has_changed());
+    assert!(set.is_consistent_for_testing());
+
+    let mut set = AuthorStylesheetSet::new();
+    set.try_append_stylesheets(None, (0..4).map(MockSheet::author), &guard)
+        .unwrap();
+    assert_eq!(set.len(), 4);
+    assert!(set.dirty());
+    assert_index_consistent(&set);
+}
+
+/// Pretends that every heap block is one byte long.
+unsafe extern "C" fn mock_malloc_size_of(_ptr: *const c_void) -> usize {
+    1
+}
+
+#[test]
+fn test_author_set_malloc_size_of() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut ops = MallocSizeOfOps::new(mock_malloc_size_of, None, None);
+
+    let mut set = AuthorStylesheetSet::new();
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+    assert!(set.size_of(&mut ops) > 0);
+}
+
+#[test]
+fn test_approximate_memory_usage() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut ops = MallocSizeOfOps::new(mock_malloc_size_of, None, None);
+
+    let mut set = DocumentStylesheetSet::new();
+    let empty = set.approximate_memory_usage(&mut ops);
+    set.append_stylesheets(None, (0..16).map(MockSheet::author), &guard);
+    let full = set.approximate_memory_usage(&mut ops);
+    assert!(full > empty);
+
+    // The capacity of the set is still around after removing a few sheets.
+    set.flush_without_invalidation();
+    for id in 0..4 {
+        set.remove_stylesheet(None, MockSheet::author(id), &guard);
+    }
+    set.flush_without_invalidation();
+    assert_eq!(set.approximate_memory_usage(&mut ops), full);
+
+    let mut author_set = AuthorStylesheetSet::new();
+    let empty = author_set.approximate_memory_usage(&mut ops);
+    author_set.append_stylesheet(None, MockSheet::author(0), &guard);
+    assert!(author_set.approximate_memory_usage(&mut ops) > empty);
+}
+
+#[test]
+fn test_shrink_to_fit() {
+    let lock = SharedRwLock::new();
+    let sheet = sheet_from_css(".a, .b, #c { color: red }", "", Origin::Author, &lock);
+    let guard = lock.read();
+    let mut ops = MallocSizeOfOps::new(mock_malloc_size_of, None, None);
+
+    // Removing most of the sheets gives the memory back.
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheets(None, (0..64).map(MockSheet::author), &guard);
+    set.flush_without_invalidation();
+    let full = set.approximate_memory_usage(&mut ops);
+    for id in 1..64 {
+        set.remove_stylesheet(None, MockSheet::author(id), &guard);
+    }
+    set.flush_without_invalidation();
+    let trimmed = set.approximate_memory_usage(&mut ops);
+    assert!(trimmed < full);
+
+    set.shrink_to_fit();
+    assert!(set.approximate_memory_usage(&mut ops) < trimmed);
+    assert_eq!(set.iter().map(|(s, _)| s.id).collect::<Vec<_>>(), vec![0]);
+    assert!(!set.has_changed());
+
+    // Removing half of them doesn't, but shrinking does.
+    let mut author_set = AuthorStylesheetSet::new();
+    author_set.append_stylesheets(None, (0..64).map(MockSheet::author), &guard);
+    complete_author_flush(&mut author_set);
+    let full = author_set.approximate_memory_usage(&mut ops);
+    for id in 0..32 {
+        author_set.remove_stylesheet(None, MockSheet::author(id), &guard);
+    }
+    complete_author_flush(&mut author_set);
+    assert_eq!(author_set.approximate_memory_usage(&mut ops), full);
+    author_set.shrink_to_fit();
+    assert!(author_set.approximate_memory_usage(&mut ops) < full);
+    assert_eq!(author_set.len(), 32);
+
+    // The invalidation set keeps its buffers around until shrunk.
+    let device = device_with_width(800.);
+    let mut invalidations = StylesheetInvalidationSet::new();
+    let empty = invalidations.size_of(&mut ops);
+    invalidations.collect_invalidations_for(&device, &sheet, &guard);
+    invalidations.clear();
+    assert!(invalidations.size_of(&mut ops) > empty);
+    invalidations.shrink_to_fit();
+    assert_eq!(invalidations.size_of(&mut ops), empty);
+}
+
+#[test]
+fn test_flush_without_invalidation() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+
+    assert_eq!(set.flush_without_invalidation(), OriginSet::empty());
+
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+    set.append_stylesheet(None, MockSheet::author(1), &guard);
+    assert_eq!(set.flush_without_invalidation(), OriginSet::ORIGIN_AUTHOR);
+    assert!(!set.has_changed());
+    assert_eq!(set.flush_without_invalidation(), OriginSet::empty());
+
+    // The sheets were committed, so removing one now invalidates the
+    // existing data.
+    set.remove_stylesheet(None, MockSheet::author(0), &guard);
+    assert_eq!(
+        set.data_validity(Origin::Author),
+        DataValidity::FullyInvalid
+    );
+    assert_eq!(set.flush_without_invalidation(), OriginSet::ORIGIN_AUTHOR);
+    assert_eq!(set.data_validity(Origin::Author), DataValidity::Valid);
+
+    // Whereas removing a sheet that was never flushed doesn't, and just
+    // undoes the append.
+    set.append_stylesheet(None, MockSheet::author(2), &guard);
+    set.remove_stylesheet(None, MockSheet::author(2), &guard);
+    assert!(!set.has_changed());
+    assert_eq!(set.data_validity(Origin::Author), DataValidity::Valid);
+}
+
+/// Flushes `set`, returning whether the existing data needed to be cleared,
+/// and the ids of the flushed sheets along with whether their invalidation
+/// data needs to be rebuilt.
+fn flush_author_set(set: &mut AuthorStylesheetSet<MockSheet>) -> (bool, Vec<(usize, bool)>) {
+    let mut flusher = set.flush_without_invalidation();
+    let (needs_clear, sheets) = {
+        let sheets = flusher.sheets();
+        let needs_clear = sheets.needs_clear();
+        let sheets = sheets
+            .map(|(s, kind)| (s.id, kind.should_rebuild_invalidation()))
+            .collect();
+        (needs_clear, sheets)
+    };
+    flusher.complete();
+    (needs_clear, sheets)
+}
+
+/// Flushes `set` as if its data had been rebuilt from all its sheets.
+fn complete_author_flush(set: &mut AuthorStylesheetSet<MockSheet>) {
+    let mut flusher = set.flush_without_invalidation();
+    flusher.sheets().for_each(|_| {});
+    flusher.complete();
+}
+
+#[test]
+fn test_flusher_needs_clear() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = AuthorStylesheetSet::new();
+
+    // Appending only yields the new sheets.
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+    assert_eq!(flush_author_set(&mut set), (false, vec![(0, true)]));
+    set.append_stylesheet(None, MockSheet::author(2), &guard);
+    assert_eq!(flush_author_set(&mut set), (false, vec![(2, true)]));
+
+    // Inserting in the middle invalidates the existing cascade data.
+    set.insert_stylesheet_before(None, MockSheet::author(1), MockSheet::author(2), &guard)
+        .unwrap();
+    assert_eq!(
+        flush_author_set(&mut set),
+        (true, vec![(0, false), (1, true), (2, false)])
+    );
+
+    // Removing a committed sheet invalidates everything.
+    set.remove_stylesheet(None, MockSheet::author(1), &guard);
+    assert_eq!(
+        flush_author_set(&mut set),
+        (true, vec![(0, true), (2, true)])
+    );
+
+    // Nothing changed.
+    assert_eq!(flush_author_set(&mut set), (false, vec![]));
+}
+
+#[test]
+fn test_force_dirty_origin() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+    set.flush_without_invalidation();
+
+    // Origins that never had any sheet have no data to rebuild.
+    set.force_dirty_origin(Origin::User);
+    assert!(!set.has_changed());
+    assert_eq!(set.data_validity(Origin::User), DataValidity::Valid);
+
+    let user_sheet = MockSheet::new(1, Origin::User);
+    set.append_stylesheet(None, user_sheet, &guard);
+    set.flush_without_invalidation();
+    set.force_dirty_origin(Origin::User);
+    assert!(set.has_changed());
+    assert_eq!(set.data_validity(Origin::User), DataValidity::FullyInvalid);
+    assert_eq!(set.data_validity(Origin::Author), DataValidity::Valid);
+    assert_eq!(set.flush_without_invalidation(), OriginSet::ORIGIN_USER);
+
+    set.force_dirty(OriginSet::all());
+    assert_eq!(
+        set.flush_without_invalidation(),
+        OriginSet::ORIGIN_USER | OriginSet::ORIGIN_AUTHOR
+    );
+
+    set.force_dirty_with_validity(OriginSet::ORIGIN_AUTHOR, DataValidity::CascadeInvalid);
+    assert_eq!(set.dirty_origins(), OriginSet::ORIGIN_AUTHOR);
+    assert_eq!(
+        set.data_validity(Origin::Author),
+        DataValidity::CascadeInvalid
+    );
+    set.force_dirty_with_validity(OriginSet::ORIGIN_AUTHOR, DataValidity::Valid);
+    assert_eq!(
+        set.data_validity(Origin::Author),
+        DataValidity::CascadeInvalid
+    );
+    set.flush_without_invalidation();
+
+    set.force_dirty_with_validity(OriginSet::ORIGIN_USER, DataValidity::Valid);
+    assert_eq!(set.dirty_origins(), OriginSet::ORIGIN_USER);
+    assert_eq!(set.data_validity(Origin::User), DataValidity::Valid);
+}
+
+#[test]
+fn test_insert_stylesheet_at() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(None, MockSheet::author(1), &guard);
+    set.flush_without_invalidation();
+
+    // Inserting at the end behaves like an append.
+    set.insert_stylesheet_at(None, 1, MockSheet::author(3), &guard);
+    assert_eq!(set.data_validity(Origin::Author), DataValidity::Valid);
+    set.insert_stylesheet_at(None, 10, MockSheet::author(4), &guard);
+    assert_eq!(set.data_validity(Origin::Author), DataValidity::Valid);
+
+    // So does inserting before sheets that haven't been flushed yet, which
+    // removing the sheet again undoes.
+    set.insert_stylesheet_at(None, 1, MockSheet::author(2), &guard);
+    assert_eq!(set.data_validity(Origin::Author), DataValidity::Valid);
+    set.flush_without_invalidation();
+    set.append_stylesheet(None, MockSheet::author(6), &guard);
+    set.insert_stylesheet_at(None, 4, MockSheet::author(5), &guard);
+    assert_eq!(set.data_validity(Origin::Author), DataValidity::Valid);
+    set.remove_stylesheet(None, MockSheet::author(5), &guard);
+    set.remove_stylesheet(None, MockSheet::author(6), &guard);
+    assert!(!set.has_changed());
+
+    // Anywhere else invalidates the cascade data.
+    set.insert_stylesheet_at(None, 0, MockSheet::author(0), &guard);
+    assert_eq!(
+        set.data_validity(Origin::Author),
+        DataValidity::CascadeInvalid
+    );
+
+    let ids = set.iter().map(|(s, _)| s.id).collect::<Vec<_>>();
+    assert_eq!(ids, vec![0, 1, 2, 3, 4]);
+    assert!(set.is_consistent_for_testing());
+}
+
+#[test]
+fn test_append_stylesheet_reports_validity() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(None, MockSheet::author(1), &guard);
+    set.flush_without_invalidation();
+
+    // Appending to a clean origin keeps the existing data valid.
+    assert_eq!(
+        set.append_stylesheet(None, MockSheet::author(2), &guard),
+        DataValidity::Valid
+    );
+
+    // But it reports the validity other changes left the origin in.
+    set.insert_stylesheet_at(None, 0, MockSheet::author(0), &guard);
+    assert_eq!(
+        set.append_stylesheet(None, MockSheet::author(3), &guard),
+        DataValidity::CascadeInvalid
+    );
+    assert_eq!(
+        set.append_stylesheet(None, MockSheet::author(3), &guard),
+        DataValidity::CascadeInvalid
+    );
+
+    // Each origin reports its own validity.
+    assert_eq!(
+        set.append_stylesheet(None, MockSheet::new(4, Origin::User), &guard),
+        DataValidity::Valid
+    );
+
+    let mut author_set = AuthorStylesheetSet::new();
+    assert_eq!(
+        author_set.append_stylesheet(None, MockSheet::author(0), &guard),
+        DataValidity::Valid
+    );
+}
+
+#[test]
+fn test_removed_sheets() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = AuthorStylesheetSet::new();
+    for id in 0..3 {
+        set.append_stylesheet(None, MockSheet::author(id), &guard);
+    }
+    complete_author_flush(&mut set);
+
+    set.remove_stylesheet(None, MockSheet::author(2), &guard);
+    set.remove_stylesheet(None, MockSheet::author(0), &guard);
+    // The removed sheets are kept around until the flush completes.
+    for _ in 0..2 {
+        let flusher = set.flush_without_invalidation();
+        let removed = flusher.removed_sheets().map(|s| s.id).collect::<Vec<_>>();
+        assert_eq!(removed, vec![2, 0]);
+    }
+    complete_author_flush(&mut set);
+
+    // Removed sheets are dropped on flush, even if nobody looked at them.
+    set.remove_stylesheet(None, MockSheet::author(1), &guard);
+    complete_author_flush(&mut set);
+    let flusher = set.flush_without_invalidation();
+    assert_eq!(flusher.removed_sheets().count(), 0);
+}
+
+#[test]
+fn test_author_set_bookkeeping_matches_document_set() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut author_set = AuthorStylesheetSet::new();
+    let mut document_set = DocumentStylesheetSet::new();
+
+    for id in 0..3 {
+        author_set.append_stylesheet(None, MockSheet::author(id), &guard);
+        document_set.append_stylesheet(None, MockSheet::author(id), &guard);
+    }
+    assert_eq!(author_set.len(), document_set.len());
+    assert!(author_set.has_changed() && document_set.has_changed());
+
+    complete_author_flush(&mut author_set);
+    document_set.flush_without_invalidation();
+    assert!(!author_set.has_changed() && !document_set.has_changed());
+
+    author_set.remove_stylesheet(None, MockSheet::author(1), &guard);
+    document_set.remove_stylesheet(None, MockSheet::author(1), &guard);
+    let mut flusher = author_set.flush_without_invalidation();
+    assert_eq!(
+        flusher.sheets().data_validity(),
+        document_set.data_validity(Origin::Author)
+    );
+}
+
+#[test]
+fn test_full_rebuild_sheets() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = AuthorStylesheetSet::new();
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+    set.append_stylesheet(None, MockSheet::author(1), &guard);
+    complete_author_flush(&mut set);
+    let full_rebuild_ids = |set: &mut AuthorStylesheetSet<MockSheet>| {
+        let mut flusher = set.flush_without_invalidation();
+        let ids = flusher
+            .sheets()
+            .full_rebuild_sheets()
+            .map(|sheet| sheet.id)
+            .collect::<Vec<_>>();
+        flusher.complete();
+        ids
+    };
+
+    // Only the new sheet needs a full rebuild, but all of them get committed.
+    set.force_dirty_with_validity(DataValidity::CascadeInvalid);
+    set.append_stylesheet(None, MockSheet::author(2), &guard);
+    assert_eq!(full_rebuild_ids(&mut set), vec![2]);
+
+    set.force_dirty_with_validity(DataValidity::CascadeInvalid);
+    assert_eq!(full_rebuild_ids(&mut set), Vec::<usize>::new());
+
+    // Every sheet needs a full rebuild when the data is fully invalid.
+    set.force_dirty();
+    assert_eq!(full_rebuild_ids(&mut set), vec![0, 1, 2]);
+}
+
+#[test]
+fn test_clear() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let user_sheet = |id| MockSheet::new(id, Origin::User);
+
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+    set.append_stylesheet(None, user_sheet(1), &guard);
+    set.flush_without_invalidation();
+    set.append_stylesheet(None, MockSheet::author(2), &guard);
+
+    let cleared = set.clear(OriginSet::ORIGIN_AUTHOR);
+    assert_eq!(cleared, vec![MockSheet::author(0), MockSheet::author(2)]);
+    assert_eq!(set.len(), 1);
+    assert_eq!(
+        set.data_validity(Origin::Author),
+        DataValidity::FullyInvalid
+    );
+    assert_eq!(set.data_validity(Origin::User), DataValidity::Valid);
+    assert_eq!(set.flush_without_invalidation(), OriginSet::ORIGIN_AUTHOR);
+
+    // Clearing sheets that were never flushed doesn't invalidate anything.
+    set.append_stylesheet(None, MockSheet::author(3), &guard);
+    assert_eq!(
+        set.clear(OriginSet::all()),
+        vec![MockSheet::author(3), user_sheet(1)]
+    );
+    assert_eq!(set.data_validity(Origin::Author), DataValidity::Valid);
+    assert_eq!(set.data_validity(Origin::User), DataValidity::FullyInvalid);
+    assert!(set.has_changed());
+
+    let mut set = AuthorStylesheetSet::new();
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+    complete_author_flush(&mut set);
+    assert_eq!(set.clear(), vec![MockSheet::author(0)]);
+    assert!(set.is_empty());
+    let mut flusher = set.flush_without_invalidation();
+    assert_eq!(flusher.sheets().data_validity(), DataValidity::FullyInvalid);
+}
+
+#[test]
+fn test_retain() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    for id in 0..10 {
+        set.append_stylesheet(None, MockSheet::author(id), &guard);
+    }
+    set.flush_without_invalidation();
+
+    let mut calls = 0;
+    set.retain(None, &guard, |sheet| {
+        calls += 1;
+        sheet.id % 2 == 0
+    });
+    assert_eq!(calls, 10);
+    let ids = set.iter().map(|(s, _)| s.id).collect::<Vec<_>>();
+    assert_eq!(ids, vec![0, 2, 4, 6, 8]);
+    assert_eq!(
+        set.data_validity(Origin::Author),
+        DataValidity::FullyInvalid
+    );
+    assert!(set.is_consistent_for_testing());
+    assert_eq!(set.flush_without_invalidation(), OriginSet::ORIGIN_AUTHOR);
+
+    // Removing uncommitted sheets only marks the set as dirty.
+    set.append_stylesheet(None, MockSheet::author(10), &guard);
+    set.retain(None, &guard, |sheet| sheet.id != 10);
+    assert!(set.has_changed());
+    assert_eq!(set.data_validity(Origin::Author), DataValidity::Valid);
+    assert_eq!(set.len(), 5);
+
+    // Keeping everything doesn't change anything.
+    set.flush_without_invalidation();
+    set.retain(None, &guard, |_| true);
+    assert!(!set.has_changed());
+}
+
+#[test]
+fn test_retain_collects_removal_invalidations() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let device = device_with_width(800.);
+    let sheets = [".foo { color: red }", ".bar { color: blue }"]
+        .iter()
+        .map(|css| sheet_from_css(css, "all", Origin::Author, &lock))
+        .collect::<Vec<_>>();
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheets(None, sheets.iter().cloned(), &guard);
+    set.flush_without_invalidation();
+
+    // The invalidations of the removed sheets are deferred like those of any
+    // other removal.
+    set.begin_batch();
+    set.retain(Some(&device), &guard, |sheet| *sheet != sheets[1]);
+    assert!(!set.has_pending_invalidations());
+    set.end_batch(&device, &guard);
+    assert!(set.has_pending_invalidations());
+    assert_eq!(set.full_invalidation_reason(Origin::Author), None);
+    set.flush_without_invalidation();
+
+    // And are collected right away outside of a batch.
+    set.retain(Some(&device), &guard, |_| false);
+    assert!(set.has_pending_invalidations());
+    assert_eq!(set.full_invalidation_reason(Origin::Author), None);
+    assert!(set.is_empty());
+}
+
+#[test]
+fn test_iterate_backwards() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    let origins = [
+        Origin::Author,
+        Origin::UserAgent,
+        Origin::Author,
+        Origin::User,
+    ];
+    for (id, origin) in origins.iter().enumerate() {
+        let origin = *origin;
+        set.append_stylesheet(None, MockSheet::new(id, origin), &guard);
+    }
+    set.set_stylesheet_enabled(None, &MockSheet::author(2), false, &guard);
+
+    let forward = set.iter().map(|(s, o)| (s.id, o)).collect::<Vec<_>>();
+    let mut backward = set.iter().rev().map(|(s, o)| (s.id, o)).collect::<Vec<_>>();
+    backward.reverse();
+    assert_eq!(forward, backward);
+    assert_eq!(forward.len(), 3);
+
+    // Meeting in the middle of a collection.
+    set.set_stylesheet_enabled(None, &MockSheet::author(2), true, &guard);
+    for split in 0..=4 {
+        let mut iter = set.iter();
+        let mut ids = (&mut iter)
+            .take(split)
+            .map(|(s, _)| s.id)
+            .collect::<Vec<_>>();
+        let mut rest = iter.rev().map(|(s, _)| s.id).collect::<Vec<_>>();
+        rest.reverse();
+        ids.extend(rest);
+        assert_eq!(ids, vec![0, 2, 3, 1]);
+    }
+}
+
+/// Removing lots of sheets only looks each one up by identity, and doesn't
+/// compare it against every other sheet in the set.
+#[test]
+fn test_remove_many_sheets() {
+    const COUNT: usize = 5_000;
+
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let budget = Rc::new(Cell::new(usize::MAX));
+    let sheet = |id| MockSheet::panicky(id, &budget);
+
+    let mut set = DocumentStylesheetSet::new();
+    for id in 0..COUNT {
+        set.append_stylesheet(None, sheet(id), &guard);
+    }
+
+    // Each removal looks the sheet up twice, computing its identity and, in
+    // debug builds, comparing it with the sheet that was found.
+    budget.set(4 * COUNT);
+    for id in (0..COUNT).filter(|id| id % 2 == 0) {
+        assert!(set.remove_stylesheet(None, sheet(id), &guard));
+    }
+    for id in (0..COUNT).filter(|id| id % 2 != 0).rev() {
+        assert!(set.remove_stylesheet(None, sheet(id), &guard));
+    }
+    budget.set(usize::MAX);
+
+    assert_eq!(set.len(), 0);
+    assert!(set.is_consistent_for_testing());
+}
+
+#[test]
+fn test_collection_iterator_len() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = AuthorStylesheetSet::new();
+    for id in 0..5 {
+        set.append_stylesheet(None, MockSheet::author(id), &guard);
+    }
+    set.set_stylesheet_enabled(None, &MockSheet::author(1), false, &guard);
+    set.set_stylesheet_enabled(None, &MockSheet::author(3), false, &guard);
+    set.remove_stylesheet(None, MockSheet::author(3), &guard);
+
+    let mut iter = set.iter();
+    assert_eq!(iter.len(), set.iter().collect::<Vec<_>>().len());
+    assert_eq!(iter.len(), 3);
+    iter.next();
+    iter.next_back();
+    assert_eq!(iter.len(), 1);
+    assert!(set.is_consistent_for_testing());
+}
+
+#[test]
+fn test_dirty_origins() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let user_sheet = MockSheet::new(1, Origin::User);
+
+    let mut set = DocumentStylesheetSet::new();
+    assert_eq!(set.dirty_origins(), OriginSet::empty());
+    assert!(!set.has_changed());
+
+    set.append_stylesheet(None, user_sheet, &guard);
+    assert_eq!(set.dirty_origins(), OriginSet::ORIGIN_USER);
+    set.append_stylesheets(None, vec![MockSheet::author(0)], &guard);
+    assert_eq!(
+        set.dirty_origins(),
+        OriginSet::ORIGIN_USER | OriginSet::ORIGIN_AUTHOR
+    );
+    assert_eq!(
+        set.flush_without_invalidation(),
+        OriginSet::ORIGIN_USER | OriginSet::ORIGIN_AUTHOR
+    );
+    assert_eq!(set.dirty_origins(), OriginSet::empty());
+
+    // Removing a sheet that isn't there doesn't dirty anything.
+    set.remove_stylesheet(None, MockSheet::author(2), &guard);
+    assert!(!set.has_changed());
+
+    // Origins that never had any sheet have no data to rebuild.
+    set.force_dirty_origin(Origin::UserAgent);
+    assert!(!set.has_changed());
+    set.force_dirty_origin(Origin::User);
+    assert_eq!(set.dirty_origins(), OriginSet::ORIGIN_USER);
+    set.flush_without_invalidation();
+
+    set.retain(None, &guard, |sheet| sheet.origin != Origin::Author);
+    assert_eq!(set.dirty_origins(), OriginSet::ORIGIN_AUTHOR);
+    set.clear(OriginSet::ORIGIN_USER);
+    assert_eq!(
+        set.dirty_origins(),
+        OriginSet::ORIGIN_USER | OriginSet::ORIGIN_AUTHOR
+    );
+}
+
+#[test]
+fn test_sheets_per_origin() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    for id in 0..3 {
+        set.append_stylesheet(None, MockSheet::author(id), &guard);
+    }
+    set.append_stylesheet(None, MockSheet::new(3, Origin::User), &guard);
+    set.set_stylesheet_enabled(None, &MockSheet::author(1), false, &guard);
+
+    assert_eq!(set.sheet_count(Origin::Author), 3);
+    assert_eq!(set.sheet_count(Origin::User), 1);
+    assert_eq!(set.sheet_count(Origin::UserAgent), 0);
+
+    let sheets = set.sheets(Origin::Author);
+    assert_eq!(sheets.len(), 2);
+    let ids = sheets.rev().map(|s| s.id).collect::<Vec<_>>();
+    assert_eq!(ids, vec![2, 0]);
+}
+
+#[test]
+fn test_len_per_origin() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    for id in 0..2 {
+        let origin = Origin::UserAgent;
+        set.append_stylesheet(None, MockSheet::new(id, origin), &guard);
+    }
+    set.append_stylesheets(None, (2..5).map(MockSheet::author), &guard);
+    set.set_stylesheet_enabled(None, &MockSheet::author(3), false, &guard);
+
+    let lens = set.len_per_origin();
+    assert_eq!(lens.user_agent, 2);
+    assert_eq!(lens.user, 0);
+    assert_eq!(lens.author, 3);
+}
+
+#[test]
+fn test_dropped_flusher_restores_dirty_state() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheets(None, (0..2).map(MockSheet::author), &guard);
+    set.flush_without_invalidation();
+    set.remove_stylesheet(None, MockSheet::author(0), &guard);
+    set.append_stylesheet(None, MockSheet::author(2), &guard);
+    let origin = Origin::UserAgent;
+    set.append_stylesheet(None, MockSheet::new(3, origin), &guard);
+
+    {
+        let mut flusher = set.flusher_without_invalidation_for_testing();
+        assert!(flusher.flush_origin(Origin::UserAgent).dirty());
+        assert_eq!(flusher.origin_sheets(Origin::UserAgent).count(), 1);
+        assert!(flusher.flush_origin(Origin::Author).dirty());
+        assert_eq!(flusher.dirty_origin_count(), 0);
+    }
+
+    let (origins, validity) = set.peek_flush();
+    assert_eq!(
+        origins,
+        OriginSet::ORIGIN_AUTHOR | OriginSet::ORIGIN_USER_AGENT
+    );
+    assert_eq!(validity.author, DataValidity::FullyInvalid);
+    // The invalidations were already processed before the flush was
+    // abandoned, only the cascade data needs to be rebuilt again.
+    assert!(!set.has_pending_invalidations());
+    assert!(set.is_consistent_for_testing());
+
+    {
+        let mut flusher = set.flusher_without_invalidation_for_testing();
+        let removed = flusher
+            .removed_sheets(Origin::Author)
+            .map(|s| s.id)
+            .collect::<Vec<_>>();
+        assert_eq!(removed, vec![0]);
+        assert_eq!(
+            flusher
+                .full_rebuild_sheets(Origin::Author)
+                .map(|s| s.id)
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert!(flusher.flush_origin(Origin::UserAgent).dirty());
+        assert_eq!(flusher.origin_sheets(Origin::UserAgent).count(), 1);
+        flusher.complete();
+    }
+
+    assert!(set.dirty_origins().is_empty());
+    assert!(!set.has_changed());
+}
+
+#[test]
+fn test_has_same_order_as() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let build = |ids: &[usize]| {
+        let mut set = DocumentStylesheetSet::new();
+        let origin = Origin::UserAgent;
+        set.append_stylesheet(None, MockSheet::new(0, origin), &guard);
+        set.append_stylesheets(None, ids.iter().cloned().map(MockSheet::author), &guard);
+        set
+    };
+
+    let mut set = build(&[1, 2, 3]);
+    assert!(set.has_same_order_as(&set));
+    let mut other = build(&[1, 2, 3]);
+    other.set_stylesheet_enabled(None, &MockSheet::author(2), false, &guard);
+    other.flush_without_invalidation();
+    assert!(set.has_same_order_as(&other));
+
+    set.insert_stylesheet_at(None, 0, MockSheet::author(4), &guard);
+    assert!(!set.has_same_order_as(&other));
+    assert!(!other.has_same_order_as(&set));
+    assert!(set.has_same_order_as(&build(&[4, 1, 2, 3])));
+    assert!(!set.has_same_order_as(&build(&[1, 4, 2, 3])));
+    assert!(!set.has_same_order_as(&build(&[4, 1, 2])));
+}
+
+#[test]
+fn test_drain_uncommitted() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheets(None, (0..2).map(MockSheet::author), &guard);
+    let origin = Origin::UserAgent;
+    set.append_stylesheet(None, MockSheet::new(2, origin), &guard);
+    set.flush_without_invalidation();
+
+    set.append_stylesheet(None, MockSheet::author(3), &guard);
+    set.append_stylesheet(None, MockSheet::author(4), &guard);
+    set.append_stylesheet(None, MockSheet::new(5, origin), &guard);
+    let origin = Origin::User;
+    set.append_stylesheet(None, MockSheet::new(6, origin), &guard);
+
+    let drained = set.drain_uncommitted(OriginSet::ORIGIN_AUTHOR | OriginSet::ORIGIN_USER);
+    assert_eq!(
+        drained.iter().map(|s| s.id).collect::<Vec<_>>(),
+        vec![3, 4, 6]
+    );
+    assert!(set.is_consistent_for_testing());
+
+    let ids = |origin: Origin| set.sheets(origin).map(|s| s.id).collect::<Vec<_>>();
+    assert_eq!(ids(Origin::Author), vec![0, 1]);
+    assert!(ids(Origin::User).is_empty());
+    assert_eq!(ids(Origin::UserAgent), vec![2, 5]);
+
+    let (origins, validity) = set.peek_flush();
+    assert!(origins.contains(OriginSet::ORIGIN_AUTHOR));
+    assert_eq!(validity.author, DataValidity::Valid);
+    assert_eq!(validity.user, DataValidity::Valid);
+    assert_eq!(validity.user_agent, DataValidity::Valid);
+}
+
+#[test]
+fn test_skip_origin() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    let origin = Origin::UserAgent;
+    set.append_stylesheet(None, MockSheet::new(0, origin), &guard);
+    set.append_stylesheet(None, MockSheet::author(1), &guard);
+
+    {
+        let mut flusher = set.flusher_without_invalidation_for_testing();
+        flusher.skip_origin(Origin::UserAgent);
+        assert_eq!(flusher.dirty_origins(), OriginSet::ORIGIN_AUTHOR);
+        assert_eq!(flusher.origin_sheets(Origin::UserAgent).count(), 0);
+        let flushed = flusher
+            .sheets()
+            .map(|(s, origin, _)| (s.id, origin))
+            .collect::<Vec<_>>();
+        assert_eq!(flushed, vec![(1, Origin::Author)]);
+        flusher.complete();
+    }
+
+    assert_eq!(set.dirty_origins(), OriginSet::ORIGIN_USER_AGENT);
+    assert_eq!(
+        set.flush_without_invalidation(),
+        OriginSet::ORIGIN_USER_AGENT
+    );
+    assert!(set.dirty_origins().is_empty());
+}
+
+#[test]
+fn test_iterate_from_both_ends() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    let origins = [Origin::UserAgent, Origin::Author, Origin::User];
+    for id in 0..12 {
+        let origin = origins[id % 3];
+        set.append_stylesheet(None, MockSheet::new(id, origin), &guard);
+    }
+    set.set_stylesheet_enabled(None, &MockSheet::author(4), false, &guard);
+
+    let reference = [Origin::Author, Origin::User, Origin::UserAgent]
+        .iter()
+        .flat_map(|origin| set.sheets(*origin).map(move |s| (s.id, *origin)))
+        .collect::<Vec<_>>();
+    assert_eq!(reference.len(), 11);
+
+    let mut iter = set.iter();
+    assert_eq!(iter.len(), reference.len());
+    let mut front = vec![];
+    let mut back = vec![];
+    loop {
+        match iter.next() {
+            Some((s, origin)) => front.push((s.id, origin)),
+            None => break,
+        }
+        assert_eq!(iter.len(), reference.len() - front.len() - back.len());
+        match iter.next_back() {
+            Some((s, origin)) => back.push((s.id, origin)),
+            None => break,
+        }
+        assert_eq!(iter.len(), reference.len() - front.len() - back.len());
+    }
+    assert!(iter.next().is_none() && iter.next_back().is_none());
+
+    back.reverse();
+    front.extend(back);
+    assert_eq!(front, reference);
+}
+
+#[bench]
+fn bench_iterate_many_author_sheets(b: &mut Bencher) {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    let ua_sheet = MockSheet::new(0, Origin::UserAgent);
+    set.append_stylesheet(None, ua_sheet, &guard);
+    set.append_stylesheets(None, (1..2001).map(MockSheet::author), &guard);
+    set.flush_without_invalidation();
+
+    b.iter(|| test::black_box(set.iter().map(|(s, _)| s.id).sum::<usize>()))
+}
+
+#[test]
+fn test_query_dirtiness_before_flush() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+
+    // Appending is dirty, but keeps the existing data valid.
+    for _ in 0..2 {
+        assert_eq!(set.dirty_origins(), OriginSet::ORIGIN_AUTHOR);
+        assert_eq!(set.data_validity(Origin::Author), DataValidity::Valid);
+        assert_eq!(set.data_validity(Origin::User), DataValidity::Valid);
+    }
+
+    set.flush_without_invalidation();
+    set.remove_stylesheet(None, MockSheet::author(0), &guard);
+    for _ in 0..2 {
+        assert_eq!(set.dirty_origins(), OriginSet::ORIGIN_AUTHOR);
+        assert_eq!(
+            set.data_validity(Origin::Author),
+            DataValidity::FullyInvalid
+        );
+    }
+}
+
+#[test]
+fn test_flush_commits_every_dirty_origin() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let origins = [Origin::Author, Origin::User, Origin::UserAgent];
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+    set.flush_without_invalidation();
+
+    for (i, origin) in origins.iter().enumerate() {
+        let sheet = MockSheet::new(i + 1, *origin);
+        set.append_stylesheet(None, sheet, &guard);
+    }
+    assert_eq!(set.flush_without_invalidation(), OriginSet::all());
+    assert_eq!(set.flush_without_invalidation(), OriginSet::empty());
+
+    // Every sheet got committed exactly by the flush that first saw it, so
+    // removing any of them now invalidates the data built from it.
+    for (i, origin) in origins.iter().enumerate() {
+        let sheet = MockSheet::new(i + 1, *origin);
+        set.remove_stylesheet(None, sheet, &guard);
+        assert_eq!(set.data_validity(*origin), DataValidity::FullyInvalid);
+    }
+    assert_eq!(set.flush_without_invalidation(), OriginSet::all());
+}
+
+#[test]
+fn test_replace_origin_sheets() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let ids = |set: &DocumentStylesheetSet<MockSheet>| {
+        set.sheets(Origin::Author).map(|s| s.id).collect::<Vec<_>>()
+    };
+    let replace = |set: &mut DocumentStylesheetSet<MockSheet>, ids: &[usize]| {
+        let sheets = ids.iter().map(|id| MockSheet::author(*id)).collect();
+        set.replace_origin_sheets(None, Origin::Author, sheets, &guard);
+    };
+
+    let mut set = DocumentStylesheetSet::new();
+    replace(&mut set, &[0, 1]);
+    assert_eq!(ids(&set), vec![0, 1]);
+    set.flush_without_invalidation();
+
+    // Replacing with the same list is a no-op.
+    replace(&mut set, &[0, 1]);
+    assert!(!set.has_changed());
+
+    // Appending keeps the existing data valid.
+    replace(&mut set, &[0, 1, 2]);
+    assert_eq!(ids(&set), vec![0, 1, 2]);
+    assert_eq!(set.dirty_origins(), OriginSet::ORIGIN_AUTHOR);
+    assert_eq!(set.data_validity(Origin::Author), DataValidity::Valid);
+    set.flush_without_invalidation();
+
+    // Removing sheets that were never flushed doesn't invalidate anything.
+    set.append_stylesheet(None, MockSheet::author(3), &guard);
+    replace(&mut set, &[0, 1, 2]);
+    assert_eq!(set.data_validity(Origin::Author), DataValidity::Valid);
+    set.flush_without_invalidation();
+
+    // Inserting sheets before already flushed ones, or reordering them, only
+    // invalidates the cascade data.
+    replace(&mut set, &[4, 0, 1, 2]);
+    assert_eq!(ids(&set), vec![4, 0, 1, 2]);
+    assert_eq!(
+        set.data_validity(Origin::Author),
+        DataValidity::CascadeInvalid
+    );
+    set.flush_without_invalidation();
+    replace(&mut set, &[4, 1, 0, 2]);
+    assert_eq!(
+        set.data_validity(Origin::Author),
+        DataValidity::CascadeInvalid
+    );
+    set.flush_without_invalidation();
+
+    // Removing a flushed sheet invalidates everything.
+    replace(&mut set, &[4, 1, 2]);
+    assert_eq!(ids(&set), vec![4, 1, 2]);
+    assert_eq!(
+        set.data_validity(Origin::Author),
+        DataValidity::FullyInvalid
+    );
+    assert_eq!(set.dirty_origins(), OriginSet::ORIGIN_AUTHOR);
+}
+
+#[test]
+fn test_replace_origin_sheets_keeps_state() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheets(None, (0..3).map(MockSheet::author), &guard);
+    set.flush_without_invalidation();
+    set.set_stylesheet_enabled(None, &MockSheet::author(1), false, &guard);
+    set.flush_without_invalidation();
+
+    let sheets = vec![
+        MockSheet::author(3),
+        MockSheet::author(1),
+        MockSheet::author(2),
+    ];
+    set.replace_origin_sheets(None, Origin::Author, sheets, &guard);
+    assert_eq!(
+        set.sheets(Origin::Author).map(|s| s.id).collect::<Vec<_>>(),
+        vec![3, 2]
+    );
+    assert_eq!(set.sheet_count(Origin::Author), 3);
+    set.flush_without_invalidation();
+
+    // The sheet that was already there is still committed.
+    set.remove_stylesheet(None, MockSheet::author(2), &guard);
+    assert_eq!(
+        set.data_validity(Origin::Author),
+        DataValidity::FullyInvalid
+    );
+}
+
+#[test]
+fn test_generation() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let ua_sheet = MockSheet::new(1, Origin::UserAgent);
+
+    let mut set = DocumentStylesheetSet::new();
+    assert_eq!(set.generation(), 0);
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+    let generation = set.generation();
+    let author_generation = set.origin_generation(Origin::Author);
+    assert!(generation > 0);
+
+    // Flushing doesn't reset the generations, and neither do mutations that
+    // don't change anything.
+    set.flush_without_invalidation();
+    set.remove_stylesheet(None, MockSheet::author(2), &guard);
+    assert_eq!(set.generation(), generation);
+
+    set.append_stylesheet(None, ua_sheet, &guard);
+    assert!(set.generation() > generation);
+    assert!(set.origin_generation(Origin::UserAgent) > 0);
+    assert_eq!(set.origin_generation(Origin::Author), author_generation);
+
+    let generation = set.generation();
+    set.force_dirty_origin(Origin::Author);
+    assert!(set.origin_generation(Origin::Author) > author_generation);
+    assert!(set.generation() > generation);
+
+    let mut author_set = AuthorStylesheetSet::new();
+    author_set.append_stylesheet(None, MockSheet::author(0), &guard);
+    let generation = author_set.generation();
+    flush_author_set(&mut author_set);
+    assert_eq!(author_set.generation(), generation);
+    author_set.remove_stylesheet(None, MockSheet::author(0), &guard);
+    assert!(author_set.generation() > generation);
+}
+
+#[test]
+fn test_sheet_counts() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheets(None, (0..2).map(MockSheet::author), &guard);
+    assert_eq!(set.sheet_counts(Origin::Author), (0, 2));
+    set.flush_without_invalidation();
+    set.append_stylesheet(None, MockSheet::author(2), &guard);
+    assert_eq!(set.sheet_counts(Origin::Author), (2, 3));
+    assert_eq!(set.sheet_counts(Origin::User), (0, 0));
+
+    // Removing the pending sheet before flushing doesn't need a full rebuild.
+    set.remove_stylesheet(None, MockSheet::author(2), &guard);
+    assert_eq!(set.sheet_counts(Origin::Author), (2, 2));
+    assert_eq!(set.data_validity(Origin::Author), DataValidity::Valid);
+}
+
+#[test]
+fn test_replace_stylesheet() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheets(None, (0..3).map(MockSheet::author), &guard);
+    set.flush_without_invalidation();
+
+    let result = set.replace_stylesheet(None, &MockSheet::author(1), MockSheet::author(3), &guard);
+    assert_eq!(result, Ok(()));
+    assert_eq!(set.get(Origin::Author, 1), Some(&MockSheet::author(3)));
+    assert_eq!(set.sheet_counts(Origin::Author), (2, 3));
+    assert_eq!(
+        set.data_validity(Origin::Author),
+        DataValidity::CascadeInvalid
+    );
+    assert!(!set.contains(&MockSheet::author(1), &guard));
+    set.flush_without_invalidation();
+
+    // Replacing a sheet with itself just rebuilds it.
+    let result = set.replace_stylesheet(None, &MockSheet::author(3), MockSheet::author(3), &guard);
+    assert_eq!(result, Ok(()));
+    assert_eq!(set.get(Origin::Author, 1), Some(&MockSheet::author(3)));
+    assert_eq!(set.sheet_counts(Origin::Author), (2, 3));
+    set.flush_without_invalidation();
+
+    let result = set.replace_stylesheet(None, &MockSheet::author(1), MockSheet::author(4), &guard);
+    assert_eq!(
+        result,
+        Err(InsertError::ReferenceNotFound(MockSheet::author(4)))
+    );
+    assert!(!set.has_changed());
+
+    // Replacing a sheet with another sheet of the set would leave it there
+    // twice.
+    let result = set.replace_stylesheet(None, &MockSheet::author(0), MockSheet::author(2), &guard);
+    assert_eq!(result, Err(InsertError::AlreadyInSet(MockSheet::author(2))));
+    assert!(!set.has_changed());
+    assert!(set.is_consistent_for_testing());
+
+    // Sheets can't keep their position across origins.
+    let user_sheet = MockSheet::new(4, Origin::User);
+    let result = set.replace_stylesheet(None, &MockSheet::author(0), user_sheet, &guard);
+    assert_eq!(result, Ok(()));
+    assert_eq!(set.get(Origin::Author, 0), Some(&MockSheet::author(3)));
+    assert_eq!(set.get(Origin::User, 0).map(|s| s.id), Some(4));
+    assert_eq!(
+        set.data_validity(Origin::Author),
+        DataValidity::FullyInvalid
+    );
+}
+
+#[test]
+fn test_move_stylesheet() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let ids = |set: &DocumentStylesheetSet<MockSheet>| {
+        set.sheets(Origin::Author).map(|s| s.id).collect::<Vec<_>>()
+    };
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheets(None, (0..4).map(MockSheet::author), &guard);
+    set.flush_without_invalidation();
+
+    assert!(set.move_stylesheet(&MockSheet::author(3), 0, &guard));
+    assert_eq!(ids(&set), vec![3, 0, 1, 2]);
+    assert_eq!(
+        set.data_validity(Origin::Author),
+        DataValidity::CascadeInvalid
+    );
+    assert_eq!(set.sheet_counts(Origin::Author), (4, 4));
+    set.flush_without_invalidation();
+
+    assert!(set.move_stylesheet(&MockSheet::author(0), 10, &guard));
+    assert_eq!(ids(&set), vec![3, 1, 2, 0]);
+    set.flush_without_invalidation();
+
+    // Moving a sheet to where it already is doesn't change anything.
+    assert!(set.move_stylesheet(&MockSheet::author(1), 1, &guard));
+    assert!(!set.move_stylesheet(&MockSheet::author(4), 1, &guard));
+    assert!(!set.has_changed());
+}
+
+#[test]
+fn test_swap_stylesheets() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheets(None, (0..3).map(MockSheet::author), &guard);
+    set.flush_without_invalidation();
+    set.append_stylesheet(None, MockSheet::author(3), &guard);
+
+    assert_eq!(
+        set.swap_stylesheets(&MockSheet::author(0), &MockSheet::author(3), &guard),
+        Ok(())
+    );
+    assert_eq!(set.get(Origin::Author, 0), Some(&MockSheet::author(3)));
+    assert_eq!(set.get(Origin::Author, 3), Some(&MockSheet::author(0)));
+    assert_eq!(
+        set.data_validity(Origin::Author),
+        DataValidity::CascadeInvalid
+    );
+    assert_eq!(set.sheet_counts(Origin::Author), (3, 4));
+    set.flush_without_invalidation();
+
+    // The index follows the swapped sheets.
+    assert!(set.move_stylesheet(&MockSheet::author(0), 0, &guard));
+    assert_eq!(set.get(Origin::Author, 0), Some(&MockSheet::author(0)));
+    assert_eq!(set.get(Origin::Author, 1), Some(&MockSheet::author(3)));
+    set.flush_without_invalidation();
+
+    // Swapping a sheet with itself doesn't change anything.
+    assert_eq!(
+        set.swap_stylesheets(&MockSheet::author(1), &MockSheet::author(1), &guard),
+        Ok(())
+    );
+    assert!(!set.has_changed());
+
+    let user = || MockSheet::new(4, Origin::User);
+    set.append_stylesheet(None, user(), &guard);
+    set.flush_without_invalidation();
+    assert_eq!(
+        set.swap_stylesheets(&MockSheet::author(1), &user(), &guard),
+        Err(StylesheetSetError::OriginMismatch)
+    );
+    assert_eq!(
+        set.swap_stylesheets(&MockSheet::author(1), &MockSheet::author(5), &guard),
+        Err(StylesheetSetError::NotFound)
+    );
+    assert!(!set.has_changed());
+}
+
+#[test]
+fn test_invalidate_all() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheets(None, (0..3).map(MockSheet::author), &guard);
+    set.append_stylesheet(None, MockSheet::new(3, Origin::UserAgent), &guard);
+    set.flush_without_invalidation();
+    assert_eq!(set.sheet_counts(Origin::Author), (3, 3));
+
+    // Uncommitted sheets are rebuilt fully on the next flush.
+    set.invalidate_all();
+    assert_eq!(set.sheet_counts(Origin::Author), (0, 3));
+    assert_eq!(set.sheet_counts(Origin::UserAgent), (0, 1));
+    assert_eq!(
+        set.data_validity(Origin::Author),
+        DataValidity::FullyInvalid
+    );
+    assert_eq!(
+        set.dirty_origins(),
+        OriginSet::ORIGIN_AUTHOR | OriginSet::ORIGIN_USER_AGENT
+    );
+
+    set.flush_without_invalidation();
+    assert_eq!(set.sheet_counts(Origin::Author), (3, 3));
+    assert_eq!(set.data_validity(Origin::Author), DataValidity::Valid);
+    assert_eq!(set.len(), 4);
+}
+
+#[test]
+fn test_nth() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let origins = [Origin::UserAgent, Origin::Author, Origin::User];
+    let mut set = DocumentStylesheetSet::new();
+    for id in 0..9 {
+        let origin = origins[id % 3];
+        set.append_stylesheet(None, MockSheet::new(id, origin), &guard);
+    }
+    set.set_stylesheet_enabled(None, &MockSheet::author(4), false, &guard);
+
+    let expected = [
+        (1, Origin::Author),
+        (7, Origin::Author),
+        (2, Origin::User),
+        (5, Origin::User),
+        (8, Origin::User),
+        (0, Origin::UserAgent),
+        (3, Origin::UserAgent),
+        (6, Origin::UserAgent),
+    ];
+    for (i, &(id, origin)) in expected.iter().enumerate() {
+        assert_eq!(set.nth(i), Some((&MockSheet::new(id, origin), origin)));
+        assert_eq!(set.nth(i), set.iter().nth(i));
+    }
+    assert_eq!(set.nth(expected.len()), None);
+}
+
+#[test]
+fn test_remove_flushed_ua_sheet() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let ua_sheet = || MockSheet::new(0, Origin::UserAgent);
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(None, ua_sheet(), &guard);
+    assert_eq!(
+        set.flush_without_invalidation(),
+        OriginSet::ORIGIN_USER_AGENT
+    );
+    assert_eq!(set.sheet_counts(Origin::UserAgent), (1, 1));
+
+    assert!(set.remove_stylesheet(None, ua_sheet(), &guard));
+    assert_eq!(
+        set.data_validity(Origin::UserAgent),
+        DataValidity::FullyInvalid
+    );
+    assert_eq!(
+        set.flush_without_invalidation(),
+        OriginSet::ORIGIN_USER_AGENT
+    );
+}
+
+#[test]
+fn test_append_and_remove_before_flush() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let user_sheet = || MockSheet::new(10, Origin::User);
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+    set.remove_stylesheet(None, MockSheet::author(0), &guard);
+    assert!(!set.has_changed());
+
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+    set.flush_without_invalidation();
+    set.append_stylesheet(None, MockSheet::author(1), &guard);
+    set.append_stylesheet(None, MockSheet::author(2), &guard);
+    set.remove_stylesheet(None, MockSheet::author(1), &guard);
+    assert_eq!(set.dirty_origins(), OriginSet::ORIGIN_AUTHOR);
+    set.remove_stylesheet(None, MockSheet::author(2), &guard);
+    assert!(!set.has_changed());
+    assert_eq!(set.data_validity(Origin::Author), DataValidity::Valid);
+
+    // Changes to other origins are kept.
+    set.append_stylesheet(None, user_sheet(), &guard);
+    set.append_stylesheet(None, MockSheet::author(3), &guard);
+    set.remove_stylesheet(None, MockSheet::author(3), &guard);
+    assert_eq!(set.dirty_origins(), OriginSet::ORIGIN_USER);
+    set.flush_without_invalidation();
+
+    // So are other changes to the same origin.
+    set.insert_stylesheet_before(None, MockSheet::author(4), MockSheet::author(0), &guard)
+        .unwrap();
+    set.flush_without_invalidation();
+    set.move_stylesheet(&MockSheet::author(4), 1, &guard);
+    set.append_stylesheet(None, MockSheet::author(5), &guard);
+    set.remove_stylesheet(None, MockSheet::author(5), &guard);
+    assert_eq!(set.dirty_origins(), OriginSet::ORIGIN_AUTHOR);
+
+    let mut author_set = AuthorStylesheetSet::new();
+    author_set.append_stylesheet(None, MockSheet::author(0), &guard);
+    author_set.remove_stylesheet(None, MockSheet::author(0), &guard);
+    assert!(!author_set.has_changed());
+}
+
+#[test]
+fn test_iter_origins() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let origins = [Origin::UserAgent, Origin::Author, Origin::User];
+    let mut set = DocumentStylesheetSet::new();
+    for id in 0..9 {
+        let origin = origins[id % 3];
+        set.append_stylesheet(None, MockSheet::new(id, origin), &guard);
+    }
+
+    let ids = |origins| {
+        set.iter_origins(origins)
+            .map(|(s, origin)| (s.id, origin))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        ids(OriginSet::ORIGIN_AUTHOR),
+        vec![
+            (1, Origin::Author),
+            (4, Origin::Author),
+            (7, Origin::Author)
+        ]
+    );
+    assert_eq!(
+        ids(OriginSet::ORIGIN_USER_AGENT | OriginSet::ORIGIN_USER),
+        vec![
+            (2, Origin::User),
+            (5, Origin::User),
+            (8, Origin::User),
+            (0, Origin::UserAgent),
+            (3, Origin::UserAgent),
+            (6, Origin::UserAgent),
+        ]
+    );
+    assert_eq!(
+        set.iter_origins(OriginSet::ORIGIN_USER)
+            .rev()
+            .next()
+            .map(|(s, _)| s.id),
+        Some(8)
+    );
+    assert_eq!(set.iter_origins(OriginSet::empty()).len(), 0);
+    assert_eq!(
+        ids(OriginSet::all()),
+        set.iter()
+            .map(|(s, origin)| (s.id, origin))
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_first_and_last_sheets() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    let mut author_set = AuthorStylesheetSet::new();
+    assert_eq!(set.first_sheet(Origin::Author), None);
+    assert_eq!(author_set.last(), None);
+
+    for id in 0..2 {
+        set.append_stylesheet(None, MockSheet::author(id), &guard);
+        author_set.append_stylesheet(None, MockSheet::author(id), &guard);
+    }
+    assert_ne!(
+        set.first_sheet(Origin::Author),
+        set.last_sheet(Origin::Author)
+    );
+    assert_eq!(set.first_sheet(Origin::Author), set.get(Origin::Author, 0));
+    assert_eq!(set.last_sheet(Origin::Author), set.get(Origin::Author, 1));
+    assert_eq!(set.last_sheet(Origin::User), None);
+    assert_eq!(author_set.first(), author_set.get(0));
+    assert_eq!(author_set.last(), author_set.get(1));
+}
+
+/// Returns the first rule of a stylesheet parsed from `css`.
+fn parse_rule(css: &str, lock: &SharedRwLock) -> CssRule {
+    let sheet = Stylesheet::from_str(
+        css,
+        ServoUrl::parse("about:blank").unwrap(),
+        Origin::Author,
+        servo_arc::Arc::new(lock.wrap(MediaList::empty())),
+        lock.clone(),
+        None,
+        None,
+        QuirksMode::NoQuirks,
+        0,
+    );
+    let guard = lock.read();
+    let rule = sheet.rules(&guard)[0].clone();
+    rule
+}
+
+#[test]
+fn test_rule_changed() {
+    let lock = SharedRwLock::new();
+    let rule = parse_rule("a { color: red }", &lock);
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheets(None, (0..2).map(MockSheet::author), &guard);
+    set.flush_without_invalidation();
+
+    // Inserted rules need their invalidation data built.
+    let sheet = MockSheet::author(1);
+    set.rule_changed(None, &sheet, &rule, &guard, RuleChangeKind::Insert);
+    assert_eq!(
+        set.data_validity(Origin::Author),
+        DataValidity::CascadeInvalid
+    );
+    assert_eq!(set.sheet_counts(Origin::Author), (1, 2));
+    set.flush_without_invalidation();
+
+    set.rule_changed(None, &sheet, &rule, &guard, RuleChangeKind::Remove);
+    assert_eq!(
+        set.data_validity(Origin::Author),
+        DataValidity::CascadeInvalid
+    );
+    assert_eq!(set.sheet_counts(Origin::Author), (2, 2));
+    set.flush_without_invalidation();
+
+    let change = RuleChangeKind::StyleDeclarationChange;
+    set.rule_changed(None, &sheet, &rule, &guard, change);
+    assert_eq!(set.dirty_origins(), OriginSet::ORIGIN_AUTHOR);
+    assert_eq!(set.data_validity(Origin::Author), DataValidity::Valid);
+
+    // Appending and removing a sheet doesn't undo the change.
+    set.append_stylesheet(None, MockSheet::author(2), &guard);
+    set.remove_stylesheet(None, MockSheet::author(2), &guard);
+    assert_eq!(set.dirty_origins(), OriginSet::ORIGIN_AUTHOR);
+    set.flush_without_invalidation();
+
+    set.rule_changed(None, &MockSheet::author(3), &rule, &guard, change);
+    assert!(!set.has_changed());
+}
+
+fn sheet_with_media(media: &str, origin: Origin, lock: &SharedRwLock) -> DocumentStyleSheet {
+    sheet_from_css("a { color: red }", media, origin, lock)
+}
+
+fn sheet_from_css(
+    css: &str,
+    media: &str,
+    origin: Origin,
+    lock: &SharedRwLock,
+) -> DocumentStyleSheet {
+    DocumentStyleSheet(servo_arc::Arc::new(stylesheet_from_css(
+        css, media, origin, lock,
+    )))
+}
+
+fn stylesheet_from_css(css: &str, media: &str, origin: Origin, lock: &SharedRwLock) -> Stylesheet {
+    let url = ServoUrl::parse("about:blank").unwrap();
+    let context = ParserContext::new(
+        origin,
+        &url,
+        Some(CssRuleType::Media),
+        ParsingMode::DEFAULT,
+        QuirksMode::NoQuirks,
+        None,
+        None,
+    );
+    let mut input = ParserInput::new(media);
+    let media = MediaList::parse(&context, &mut Parser::new(&mut input));
+    Stylesheet::from_str(
+        css,
+        url,
+        origin,
+        servo_arc::Arc::new(lock.wrap(media)),
+        lock.clone(),
+        None,
+        None,
+        QuirksMode::NoQuirks,
+        0,
+    )
+}
+
+#[test]
+fn test_sheet_identity_survives_moves() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let sheets = (0..16)
+        .map(|_| sheet_from_css("", "all", Origin::Author, &lock))
+        .collect::<Vec<_>>();
+    let first = sheets[0].clone();
+    assert_eq!(first.identity(), sheets[0].identity());
+
+    // Growing the set moves the handles it holds around, but they still key
+    // on the shared sheet.
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheets(None, sheets, &guard);
+    assert_eq!(
+        set.get(Origin::Author, 0).unwrap().identity(),
+        first.identity()
+    );
+    assert!(set.remove_stylesheet(None, first, &guard));
+    assert!(set.is_consistent_for_testing());
+}
+
+#[test]
+fn test_owned_sheet_identity_survives_moves() {
+    let lock = SharedRwLock::new();
+    let sheet = stylesheet_from_css("", "all", Origin::Author, &lock);
+    let identity = sheet.identity();
+    let clone = sheet.clone();
+    assert_ne!(clone.identity(), identity);
+
+    let boxed = Box::new(sheet);
+    assert_eq!(boxed.identity(), identity);
+    let mut sheets = vec![*boxed];
+    sheets.reserve(64);
+    assert_eq!(sheets[0].identity(), identity);
+}
+
+fn device_with_width(width: f32) -> Device {
+    Device::new(
+        MediaType::screen(),
+        TypedSize2D::new(width, 600.),
+        TypedScale::new(1.0),
+    )
+}
+
+#[test]
+fn test_device_changed() {
+    let lock = SharedRwLock::new();
+    let wide = sheet_with_media("(min-width: 500px)", Origin::Author, &lock);
+    let unconditional = sheet_with_media("", Origin::Author, &lock);
+    let screen = sheet_with_media("screen", Origin::User, &lock);
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(None, wide, &guard);
+    set.append_stylesheet(None, unconditional, &guard);
+    set.append_stylesheet(None, screen, &guard);
+    set.flush_without_invalidation();
+
+    // Resizing without crossing the breakpoint doesn't change anything.
+    set.device_changed(&device_with_width(800.), &device_with_width(700.), &guard);
+    assert!(!set.has_changed());
+
+    set.device_changed(&device_with_width(700.), &device_with_width(400.), &guard);
+    assert_eq!(set.dirty_origins(), OriginSet::ORIGIN_AUTHOR);
+    assert_eq!(
+        set.data_validity(Origin::Author),
+        DataValidity::CascadeInvalid
+    );
+    set.flush_without_invalidation();
+
+    set.device_changed(&device_with_width(400.), &device_with_width(300.), &guard);
+    assert!(!set.has_changed());
+
+    set.device_changed(&device_with_width(300.), &device_with_width(600.), &guard);
+    assert_eq!(set.dirty_origins(), OriginSet::ORIGIN_AUTHOR);
+    assert_eq!(
+        set.data_validity(Origin::Author),
+        DataValidity::CascadeInvalid
+    );
+}
+
+#[test]
+fn test_shared_invalidations() {
+    let lock = SharedRwLock::new();
+    let sheet = sheet_with_media("", Origin::Author, &lock);
+    let shadow_sheet = sheet_with_media("", Origin::Author, &lock);
+    let undone = sheet_with_media("", Origin::Author, &lock);
+    let guard = lock.read();
+    let device = device_with_width(800.);
+
+    let invalidations = SharedStylesheetInvalidationSet::new();
+    let mut document = DocumentStylesheetSet::new_with_shared_invalidations(invalidations.clone());
+    let mut shadow = DocumentStylesheetSet::new_with_shared_invalidations(invalidations.clone());
+    assert!(!shadow.has_pending_invalidations());
+
+    // An append to one of the sets is seen by the other's flush.
+    document.append_stylesheet(Some(&device), sheet, &guard);
+    assert!(shadow.has_pending_invalidations());
+    assert!(!invalidations.borrow().is_empty());
+    assert_eq!(shadow.flush_without_invalidation(), OriginSet::empty());
+    assert!(!document.has_pending_invalidations());
+    assert!(invalidations.borrow().is_empty());
+    assert_eq!(document.dirty_origins(), OriginSet::ORIGIN_AUTHOR);
+
+    // Sets created the usual way own their invalidations.
+    let mut owned = DocumentStylesheetSet::new();
+    owned.append_stylesheet(
+        Some(&device),
+        sheet_with_media("", Origin::Author, &lock),
+        &guard,
+    );
+    assert!(owned.has_pending_invalidations());
+    assert!(!document.has_pending_invalidations());
+
+    // Undoing the changes to one of the sets doesn't drop the invalidations
+    // collected by the others.
+    document.flush_without_invalidation();
+    shadow.append_stylesheet(Some(&device), shadow_sheet, &guard);
+    document.append_stylesheet(Some(&device), undone.clone(), &guard);
+    document.remove_stylesheet(Some(&device), undone, &guard);
+    assert!(!document.has_changed());
+    assert!(shadow.has_pending_invalidations());
+    assert_eq!(shadow.dirty_origins(), OriginSet::ORIGIN_AUTHOR);
+}
+
+#[test]
+fn test_invalidations_wait_for_a_document_element() {
+    let lock = SharedRwLock::new();
+    let sheet = sheet_from_css("* { color: red }", "", Origin::Author, &lock);
+    let guard = lock.read();
+    let guards = StylesheetGuards::same(&guard);
+    let device = device_with_width(800.);
+    let document = MockDocumentData::new();
+
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(Some(&device), sheet.clone(), &guard);
+    assert!(set.has_pending_invalidations());
+
+    // There's nothing to restyle without a document element, so the
+    // invalidations are kept, even though the sheet is committed.
+    {
+        let flusher = set.flush(&device, &guards, None::<MockElement>, None);
+        assert!(!flusher.had_invalidations());
+        flusher.complete();
+    }
+    assert_eq!(set.is_committed(&sheet, &guard), Some(true));
+    assert!(!set.has_changed());
+    assert!(set.has_pending_invalidations());
+    assert_eq!(
+        set.full_invalidation_reason(Origin::Author),
+        Some(FullInvalidationReason::Selector)
+    );
+
+    // The next flush with a document element restyles it.
+    {
+        let flusher = set.flush(&device, &guards, Some(document.root()), None);
+        assert!(flusher.had_invalidations());
+        assert!(flusher.invalidation_stats().fully_invalidated);
+        flusher.complete();
+    }
+    assert!(document.take_root_hint());
+    assert!(!set.has_pending_invalidations());
+
+    // And the one after that has nothing left to do.
+    let flusher = set.flush(&device, &guards, Some(document.root()), None);
+    assert!(!flusher.had_invalidations());
+    flusher.complete();
+    assert!(!document.take_root_hint());
+}
+
+#[test]
+fn test_batch_defers_invalidations() {
+    let lock = SharedRwLock::new();
+    let sheets = ["html", ".foo", "#bar"]
+        .iter()
+        .map(|selector| {
+            let css = format!("{} {{ color: red }}", selector);
+            sheet_from_css(&css, "", Origin::Author, &lock)
+        })
+        .collect::<Vec<_>>();
+    let guard = lock.read();
+    let guards = StylesheetGuards::same(&guard);
+    let device = device_with_width(800.);
+    let eager_document = MockDocumentData::new();
+    let batched_document = MockDocumentData::new();
+    eager_document.style_root();
+    batched_document.style_root();
+
+    // Flushes `set` against `document`, returning the invalidations that were
+    // processed and whether they restyled the root.
+    let flush = |set: &mut DocumentStylesheetSet<DocumentStyleSheet>,
+                 document: &MockDocumentData| {
+        let mut flusher = set.flush(&device, &guards, Some(document.root()), None);
+        flusher.sheets().for_each(|_| {});
+        let stats = flusher.invalidation_stats();
+        flusher.complete();
+        (stats, document.take_root_hint())
+    };
+
+    let mut eager = DocumentStylesheetSet::new();
+    let mut batched = DocumentStylesheetSet::new();
+    batched.begin_batch();
+    assert!(batched.in_batch());
+    for sheet in &sheets {
+        eager.append_stylesheet(Some(&device), sheet.clone(), &guard);
+        batched.append_stylesheet(Some(&device), sheet.clone(), &guard);
+    }
+    assert!(eager.has_pending_invalidations());
+    assert!(!batched.has_pending_invalidations());
+    batched.end_batch(&device, &guard);
+    assert!(!batched.in_batch());
+    assert!(batched.has_pending_invalidations());
+    assert_eq!(batched.dirty_origins(), eager.dirty_origins());
+
+    // Replaying the deferred invalidations collects the same scoped ones as
+    // collecting them eagerly, which restyle the root, and only the root.
+    let expected = InvalidationStats {
+        elements_invalidated: 1,
+        ..InvalidationStats::default()
+    };
+    assert_eq!(batched.full_invalidation_reason(Origin::Author), None);
+    assert_eq!(flush(&mut eager, &eager_document), (expected, true));
+    assert_eq!(flush(&mut batched, &batched_document), (expected, true));
+
+    // Removals are replayed from the removed sheets, including sheets that
+    // were appended in the same batch.
+    let extra = sheet_from_css(".extra { color: red }", "", Origin::Author, &lock);
+    batched.begin_batch();
+    for set in &mut [&mut eager, &mut batched] {
+        set.remove_stylesheet(Some(&device), sheets[0].clone(), &guard);
+        set.append_stylesheet(Some(&device), extra.clone(), &guard);
+        set.remove_stylesheet(Some(&device), extra.clone(), &guard);
+    }
+    batched.end_batch(&device, &guard);
+    assert!(eager.has_pending_invalidations());
+    assert!(batched.has_pending_invalidations());
+    assert_eq!(batched.dirty_origins(), eager.dirty_origins());
+    assert_eq!(batched.full_invalidation_reason(Origin::Author), None);
+    assert_eq!(flush(&mut eager, &eager_document), (expected, true));
+    assert_eq!(flush(&mut batched, &batched_document), (expected, true));
+
+    // Mutations without a device don't collect invalidations either way.
+    batched.begin_batch();
+    for set in &mut [&mut eager, &mut batched] {
+        set.append_stylesheet(None, extra.clone(), &guard);
+    }
+    batched.end_batch(&device, &guard);
+    assert!(!eager.has_pending_invalidations());
+    assert!(!batched.has_pending_invalidations());
+    let nothing = (InvalidationStats::default(), false);
+    assert_eq!(flush(&mut eager, &eager_document), nothing);
+    assert_eq!(flush(&mut batched, &batched_document), nothing);
+}
+
+#[test]
+fn test_iter_effective() {
+    let lock = SharedRwLock::new();
+    let wide = sheet_with_media("(min-width: 500px)", Origin::Author, &lock);
+    let unconditional = sheet_with_media("", Origin::Author, &lock);
+    let disabled = sheet_with_media("", Origin::User, &lock);
+    let guard = lock.read();
+    let wide_device = device_with_width(800.);
+    let narrow_device = device_with_width(300.);
+
+    // The same sheets in two documents with different devices.
+    let mut wide_set = DocumentStylesheetSet::new();
+    let mut narrow_set = DocumentStylesheetSet::new();
+    for set in &mut [&mut wide_set, &mut narrow_set] {
+        set.append_stylesheet(None, wide.clone(), &guard);
+        set.append_stylesheet(None, unconditional.clone(), &guard);
+        set.append_stylesheet(None, disabled.clone(), &guard);
+        set.set_stylesheet_enabled(None, &disabled, false, &guard);
+    }
+
+    let effective = |set: &DocumentStylesheetSet<DocumentStyleSheet>, device: &Device| {
+        set.iter_effective(device, &guard)
+            .map(|(sheet, _)| sheet.clone())
+            .collect::<Vec<_>>()
+    };
+    for _ in 0..2 {
+        assert_eq!(
+            effective(&wide_set, &wide_device),
+            vec![wide.clone(), unconditional.clone()]
+        );
+        assert_eq!(
+            effective(&narrow_set, &narrow_device),
+            vec![unconditional.clone()]
+        );
+    }
+
+    narrow_set.device_changed(&narrow_device, &wide_device, &guard);
+    assert_eq!(
+        effective(&narrow_set, &wide_device),
+        vec![wide.clone(), unconditional.clone()]
+    );
+    assert_eq!(
+        effective(&wide_set, &wide_device),
+        vec![wide.clone(), unconditional.clone()]
+    );
+}
+
+#[test]
+fn test_sheets_that_dont_apply_are_not_flushed() {
+    let lock = SharedRwLock::new();
+    let wide = sheet_with_media("(min-width: 500px)", Origin::Author, &lock);
+    let unconditional = sheet_with_media("", Origin::Author, &lock);
+    let guard = lock.read();
+    let guards = StylesheetGuards::same(&guard);
+    let narrow_device = device_with_width(300.);
+    let wide_device = device_with_width(800.);
+    let committed = |set: &DocumentStylesheetSet<DocumentStyleSheet>| {
+        set.iter_with_state()
+            .map(|(_, _, committed)| committed)
+            .collect::<Vec<_>>()
+    };
+
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(None, wide.clone(), &guard);
+    set.append_stylesheet(None, unconditional.clone(), &guard);
+
+    // A sheet that never applied is just skipped.
+    set.update_applicable_sheets(&narrow_device, &guards);
+    assert_eq!(set.data_validity(Origin::Author), DataValidity::Valid);
+    set.flush_without_invalidation();
+    assert_eq!(committed(&set), vec![false, true]);
+    assert_eq!(set.len(), 2);
+
+    // The media lists are only evaluated again after a device change.
+    set.update_applicable_sheets(&wide_device, &guards);
+    assert!(!set.has_changed());
+
+    // It's flushed again once it applies.
+    set.device_changed(&narrow_device, &wide_device, &guard);
+    assert_eq!(
+        set.data_validity(Origin::Author),
+        DataValidity::CascadeInvalid
+    );
+    assert!(set.has_pending_invalidations());
+    set.update_applicable_sheets(&wide_device, &guards);
+    assert_eq!(
+        set.data_validity(Origin::Author),
+        DataValidity::CascadeInvalid
+    );
+    set.flush_without_invalidation();
+    assert_eq!(committed(&set), vec![true, true]);
+
+    // And skipped again when it stops applying.
+    set.device_changed(&wide_device, &narrow_device, &guard);
+    set.update_applicable_sheets(&narrow_device, &guards);
+    assert_eq!(
+        set.data_validity(Origin::Author),
+        DataValidity::CascadeInvalid
+    );
+    set.flush_without_invalidation();
+    assert_eq!(committed(&set), vec![false, true]);
+
+    // New sheets are looked at without a device change.
+    let print = sheet_with_media("print", Origin::Author, &lock);
+    set.append_stylesheet(None, print, &guard);
+    set.update_applicable_sheets(&narrow_device, &guards);
+    set.flush_without_invalidation();
+    assert_eq!(committed(&set), vec![false, true, false]);
+}
+
+/// Flushes `set` against `device` and `document`, returning the flushed
+/// sheets.
+fn flushed_sheets(
+    set: &mut DocumentStylesheetSet<DocumentStyleSheet>,
+    device: &Device,
+    guards: &StylesheetGuards,
+    document: &MockDocumentData,
+) -> Vec<(DocumentStyleSheet, SheetRebuildKind)> {
+    let mut flusher = set.flush(device, guards, Some(document.root()), None);
+    let sheets = flusher
+        .sheets()
+        .map(|(sheet, _, rebuild_kind)| (sheet.clone(), rebuild_kind))
+        .collect();
+    flusher.complete();
+    sheets
+}
+
+#[test]
+fn test_flush_skips_sheets_that_dont_apply() {
+    let lock = SharedRwLock::new();
+    let wide = sheet_with_media("(min-width: 500px)", Origin::Author, &lock);
+    let unconditional = sheet_with_media("", Origin::Author, &lock);
+    let guard = lock.read();
+    let guards = StylesheetGuards::same(&guard);
+    let narrow_device = device_with_width(300.);
+    let wide_device = device_with_width(800.);
+    let document = MockDocumentData::new();
+
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(Some(&narrow_device), wide.clone(), &guard);
+    set.append_stylesheet(Some(&narrow_device), unconditional.clone(), &guard);
+    assert_eq!(
+        flushed_sheets(&mut set, &narrow_device, &guards, &document),
+        vec![(unconditional.clone(), SheetRebuildKind::Full)]
+    );
+
+    // The sheet needs a full rebuild once it applies, since its rules didn't
+    // match anything so far.
+    set.device_changed(&narrow_device, &wide_device, &guard);
+    assert_eq!(
+        flushed_sheets(&mut set, &wide_device, &guards, &document),
+        vec![
+            (wide.clone(), SheetRebuildKind::Full),
+            (unconditional.clone(), SheetRebuildKind::CascadeOnly),
+        ]
+    );
+
+    // And it's excluded again once it doesn't.
+    set.device_changed(&wide_device, &narrow_device, &guard);
+    assert_eq!(
+        flushed_sheets(&mut set, &narrow_device, &guards, &document),
+        vec![(unconditional.clone(), SheetRebuildKind::CascadeOnly)]
+    );
+}
+
+#[test]
+fn test_is_committed() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    assert_eq!(set.is_committed(&MockSheet::author(0), &guard), None);
+
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+    assert_eq!(set.is_committed(&MockSheet::author(0), &guard), Some(false));
+    set.flush_without_invalidation();
+    assert_eq!(set.is_committed(&MockSheet::author(0), &guard), Some(true));
+
+    // A sheet appended and removed between flushes is never committed, so
+    // removing it doesn't invalidate the existing data (bug 1434756).
+    set.append_stylesheet(None, MockSheet::author(1), &guard);
+    assert_eq!(set.is_committed(&MockSheet::author(1), &guard), Some(false));
+    set.remove_stylesheet(None, MockSheet::author(1), &guard);
+    assert_eq!(set.is_committed(&MockSheet::author(1), &guard), None);
+    assert_eq!(set.data_validity(Origin::Author), DataValidity::Valid);
+}
+
+#[test]
+fn test_non_matching_sheet_invalidations() {
+    let lock = SharedRwLock::new();
+    let wide = sheet_with_media("(min-width: 500px)", Origin::Author, &lock);
+    let print = sheet_with_media("print", Origin::Author, &lock);
+    let guard = lock.read();
+    let narrow_device = device_with_width(300.);
+    let wide_device = device_with_width(800.);
+
+    // Sheets that don't apply don't schedule any invalidation, but are still
+    // part of the set.
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(Some(&narrow_device), wide.clone(), &guard);
+    set.append_stylesheet(Some(&narrow_device), print.clone(), &guard);
+    assert!(set.has_changed());
+    assert!(!set.has_pending_invalidations());
+    assert_eq!(set.len(), 2);
+
+    // They do once they start applying, whether they were flushed or not.
+    set.device_changed(&narrow_device, &wide_device, &guard);
+    assert!(set.has_pending_invalidations());
+    set.flush_without_invalidation();
+
+    set.device_changed(&wide_device, &narrow_device, &guard);
+    assert!(set.has_pending_invalidations());
+    set.flush_without_invalidation();
+
+    set.device_changed(&narrow_device, &wide_device, &guard);
+    assert!(set.has_pending_invalidations());
+    assert_eq!(
+        set.data_validity(Origin::Author),
+        DataValidity::CascadeInvalid
+    );
+
+    // The set doesn't even borrow the invalidations for them, but still
+    // collects them once they start applying.
+    let invalidations = SharedStylesheetInvalidationSet::new();
+    let mut set = DocumentStylesheetSet::new_with_shared_invalidations(invalidations.clone());
+    {
+        let _borrow = invalidations.borrow_mut();
+        set.append_stylesheet(Some(&narrow_device), wide.clone(), &guard);
+        set.append_stylesheet(Some(&narrow_device), print.clone(), &guard);
+        set.remove_stylesheet(Some(&narrow_device), print.clone(), &guard);
+    }
+    assert!(set.has_changed());
+    assert!(!set.has_pending_invalidations());
+    set.device_changed(&narrow_device, &wide_device, &guard);
+    assert!(set.has_pending_invalidations());
+}
+
+#[test]
+fn test_append_twice() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+    assert_eq!(set.len(), 1);
+    set.insert_stylesheet_at(None, 0, MockSheet::author(0), &guard);
+    set.try_append_stylesheets(None, vec![MockSheet::author(0)], &guard)
+        .unwrap();
+    assert_eq!(set.len(), 1);
+
+    // Removing the sheet doesn't leave anything behind.
+    assert!(set.remove_stylesheet(None, MockSheet::author(0), &guard));
+    assert_eq!(set.len(), 0);
+    assert!(!set.contains(&MockSheet::author(0), &guard));
+
+    let mut author_set = AuthorStylesheetSet::new();
+    author_set.append_stylesheet(None, MockSheet::author(0), &guard);
+    author_set.append_stylesheet(None, MockSheet::author(0), &guard);
+    assert_eq!(author_set.len(), 1);
+}
+
+#[test]
+fn test_author_batch_append_skips_duplicates() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = AuthorStylesheetSet::new();
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+    complete_author_flush(&mut set);
+
+    // Re-appending sheets that are already there is a no-op.
+    set.append_stylesheets(None, (0..2).map(|_| MockSheet::author(0)), &guard);
+    assert_eq!(set.len(), 1);
+    assert!(!set.dirty());
+
+    // Sheets repeated in the batch are only appended once.
+    let batch = vec![1, 0, 1].into_iter().map(MockSheet::author);
+    set.append_stylesheets(None, batch, &guard);
+    assert!(set.dirty());
+    assert!(set.is_consistent_for_testing());
+    assert_eq!(
+        set.iter().map(|sheet| sheet.id).collect::<Vec<_>>(),
+        vec![0, 1]
+    );
+}
+
+#[test]
+fn test_document_batch_append_skips_duplicates() {
+    let lock = SharedRwLock::new();
+    let first = sheet_from_css(".foo { color: red }", "", Origin::Author, &lock);
+    let second = sheet_from_css("#bar { color: red }", "", Origin::Author, &lock);
+    let guard = lock.read();
+    let device = device_with_width(800.);
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(Some(&device), first.clone(), &guard);
+    set.flush_without_invalidation();
+
+    // Re-appending sheets that are already there is a no-op, and doesn't
+    // schedule any invalidation for them.
+    set.append_stylesheets(Some(&device), vec![first.clone(), first.clone()], &guard);
+    assert_eq!(set.len(), 1);
+    assert!(!set.has_changed());
+    assert!(!set.has_pending_invalidations());
+
+    // Sheets repeated in the batch are only appended once.
+    set.append_stylesheets(
+        Some(&device),
+        vec![second.clone(), first.clone(), second.clone()],
+        &guard,
+    );
+    assert!(set.has_pending_invalidations());
+    assert!(set.is_consistent_for_testing());
+    assert_eq!(
+        set.iter()
+            .map(|(sheet, _)| sheet.clone())
+            .collect::<Vec<_>>(),
+        vec![first, second]
+    );
+}
+
+#[test]
+fn test_invalidations_skip_non_matching_condition_rules() {
+    let lock = SharedRwLock::new();
+    let never = sheet_from_css(
+        "@media (min-width: 9999px) { .foo { color: red } } \
+         @supports (display: flounder) { #bar { color: red } }",
+        "",
+        Origin::Author,
+        &lock,
+    );
+    let matching = sheet_from_css(
+        "@media (min-width: 100px) { .foo { color: red } }",
+        "",
+        Origin::Author,
+        &lock,
+    );
+    let guard = lock.read();
+    let device = device_with_width(800.);
+
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(Some(&device), never, &guard);
+    assert!(set.has_changed());
+    assert!(!set.has_pending_invalidations());
+
+    set.append_stylesheet(Some(&device), matching, &guard);
+    assert!(set.has_pending_invalidations());
+}
+
+/// Makes `parent` import `child`, with the media list of `child`.
+fn add_import(parent: &DocumentStyleSheet, child: &DocumentStyleSheet, lock: &SharedRwLock) {
+    let rule = CssRule::Import(servo_arc::Arc::new(lock.wrap(ImportRule {
+        url: CssUrl::new_for_testing("about:blank"),
+        stylesheet: ImportSheet(child.0.clone()),
+        source_location: SourceLocation { line: 0, column: 0 },
+    })));
+    let mut guard = lock.write();
+    parent
+        .0
+        .contents
+        .rules
+        .write_with(&mut guard)
+        .0
+        .insert(0, rule);
+}
+
+#[test]
+fn test_invalidations_for_imports() {
+    let lock = SharedRwLock::new();
+    let top = sheet_from_css("", "", Origin::Author, &lock);
+    let middle = sheet_from_css("", "", Origin::Author, &lock);
+    let leaf = sheet_from_css(".leaf { color: red }", "", Origin::Author, &lock);
+    add_import(&top, &middle, &lock);
+    add_import(&middle, &leaf, &lock);
+
+    let print_top = sheet_from_css("", "", Origin::Author, &lock);
+    let print_middle = sheet_from_css("", "", Origin::Author, &lock);
+    let print_leaf = sheet_from_css(".leaf { color: red }", "print", Origin::Author, &lock);
+    add_import(&print_top, &print_middle, &lock);
+    add_import(&print_middle, &print_leaf, &lock);
+
+    let guard = lock.read();
+    let device = device_with_width(800.);
+
+    // The rules of imported sheets are visited...
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(Some(&device), top.clone(), &guard);
+    assert!(set.has_pending_invalidations());
+    set.flush_without_invalidation();
+    set.remove_stylesheet(Some(&device), top, &guard);
+    assert!(set.has_pending_invalidations());
+    set.flush_without_invalidation();
+
+    // ... unless the media list of the import doesn't match.
+    set.append_stylesheet(Some(&device), print_top.clone(), &guard);
+    assert!(!set.has_pending_invalidations());
+    set.flush_without_invalidation();
+    set.remove_stylesheet(Some(&device), print_top, &guard);
+    assert!(!set.has_pending_invalidations());
+}
+
+#[test]
+fn test_invalidations_for_import_cycle() {
+    let lock = SharedRwLock::new();
+    let a = sheet_from_css(".a { color: red }", "", Origin::Author, &lock);
+    let b = sheet_from_css(".b { color: red }", "", Origin::Author, &lock);
+    add_import(&a, &b, &lock);
+    add_import(&b, &a, &lock);
+
+    let guard = lock.read();
+    let device = device_with_width(800.);
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(Some(&device), a, &guard);
+    assert!(set.has_pending_invalidations());
+
+    // Break the cycle so that the sheets can be freed.
+    drop(guard);
+    let mut guard = lock.write();
+    b.0.contents.rules.write_with(&mut guard).0.clear();
+}
+
+#[test]
+fn test_invalidations_are_per_origin() {
+    let lock = SharedRwLock::new();
+    let sidebar = sheet_from_css(".sidebar a { color: red }", "", Origin::Author, &lock);
+    let guard = lock.read();
+    let device = device_with_width(800.);
+
+    let shared = SharedStylesheetInvalidationSet::new();
+    let sets = vec![
+        DocumentStylesheetSet::new(),
+        DocumentStylesheetSet::new_with_shared_invalidations(shared),
+    ];
+    let reasons = sets
+        .into_iter()
+        .map(|mut set| {
+            let ua = sheet_with_media("", Origin::UserAgent, &lock);
+            set.append_stylesheet(Some(&device), ua, &guard);
+            set.flush_without_invalidation();
+            set.append_stylesheet(Some(&device), sidebar.clone(), &guard);
+            assert_eq!(set.full_invalidation_reason(Origin::Author), None);
+
+            set.force_dirty_origin(Origin::UserAgent);
+            assert!(set.has_pending_invalidations());
+            assert_eq!(
+                set.full_invalidation_reason(Origin::UserAgent),
+                Some(FullInvalidationReason::Explicit)
+            );
+            set.full_invalidation_reason(Origin::Author)
+        })
+        .collect::<Vec<_>>();
+
+    // Invalidations shared with other sets can't be told apart by origin.
+    assert_eq!(reasons, vec![None, Some(FullInvalidationReason::Explicit)]);
+}
+
+#[test]
+fn test_removal_invalidations_are_scoped() {
+    let lock = SharedRwLock::new();
+    let sidebar = sheet_from_css(".sidebar a { color: red }", "", Origin::Author, &lock);
+    let universal = sheet_from_css("* { color: red }", "", Origin::Author, &lock);
+    let guard = lock.read();
+    let device = device_with_width(800.);
+
+    let mut invalidations = StylesheetInvalidationSet::new();
+    invalidations.collect_invalidations_for_removal(&device, &sidebar, &guard);
+    assert!(!invalidations.is_empty());
+    assert!(!invalidations.is_fully_invalid());
+    invalidations.collect_invalidations_for_removal(&device, &universal, &guard);
+    assert!(invalidations.is_fully_invalid());
+
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(None, sidebar.clone(), &guard);
+    set.flush_without_invalidation();
+    set.remove_stylesheet(Some(&device), sidebar, &guard);
+    assert!(set.has_pending_invalidations());
+}
+
+#[test]
+fn test_peek_flush() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+    set.append_stylesheet(None, MockSheet::author(1), &guard);
+    set.flush_without_invalidation();
+    set.remove_stylesheet(None, MockSheet::author(0), &guard);
+
+    for _ in 0..2 {
+        let (origins, validity) = set.peek_flush();
+        assert_eq!(origins, OriginSet::ORIGIN_AUTHOR);
+        assert_eq!(validity.author, DataValidity::FullyInvalid);
+        assert_eq!(validity.user, DataValidity::Valid);
+        assert_eq!(validity.user_agent, DataValidity::Valid);
+    }
+
+    assert!(set.has_changed());
+    assert_eq!(
+        set.data_validity(Origin::Author),
+        DataValidity::FullyInvalid
+    );
+    assert_eq!(set.flush_without_invalidation(), OriginSet::ORIGIN_AUTHOR);
+    let (origins, validity) = set.peek_flush();
+    assert!(origins.is_empty());
+    assert_eq!(validity.author, DataValidity::Valid);
+}
+
+#[test]
+fn test_insert_before_missing_reference() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+    set.flush_without_invalidation();
+
+    match set.insert_stylesheet_before(None, MockSheet::author(1), MockSheet::author(2), &guard) {
+        Err(InsertError::ReferenceNotFound(sheet)) => assert_eq!(sheet, MockSheet::author(1)),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+    assert_eq!(set.len(), 1);
+    assert!(!set.has_changed());
+
+    // The caller can fall back to appending the sheet.
+    let error = set
+        .insert_stylesheet_before(None, MockSheet::author(1), MockSheet::author(2), &guard)
+        .unwrap_err();
+    set.append_stylesheet(None, error.into_sheet(), &guard);
+    let ids = set.iter().map(|s| s.id).collect::<Vec<_>>();
+    assert_eq!(ids, vec![0, 1]);
+}
+
+#[test]
+fn test_insert_before_wrong_origin() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let user_sheet = || MockSheet::new(10, Origin::User);
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(None, user_sheet(), &guard);
+    set.flush_without_invalidation();
+
+    match set.insert_stylesheet_before(None, MockSheet::author(0), user_sheet(), &guard) {
+        Err(InsertError::OriginMismatch(sheet)) => assert_eq!(sheet, MockSheet::author(0)),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+    assert_eq!(set.len(), 1);
+    assert!(!set.has_changed());
+}
+
+#[test]
+fn test_insert_author_sheet_before_ua_sheet() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let ua_sheet = || MockSheet::new(20, Origin::UserAgent);
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(None, ua_sheet(), &guard);
+    set.append_stylesheet(None, MockSheet::author(1), &guard);
+    set.flush_without_invalidation();
+
+    let result = set.insert_stylesheet_before(None, MockSheet::author(0), ua_sheet(), &guard);
+    assert!(result.is_err());
+    assert_eq!(
+        set.iter().map(|(s, _)| s.id).collect::<Vec<_>>(),
+        vec![20, 1]
+    );
+    assert!(!set.has_changed());
+}
+
+#[test]
+fn test_invalidation_buffers_are_reused() {
+    let lock = SharedRwLock::new();
+    let sheet = sheet_from_css(".a, #b { color: red }", "", Origin::Author, &lock);
+    let css = (0..300)
+        .map(|i| format!(".c{} {{ color: red }}", i))
+        .collect::<String>();
+    let large_sheet = sheet_from_css(&css, "", Origin::Author, &lock);
+    let guard = lock.read();
+    let device = device_with_width(800.);
+    let invalidations = SharedStylesheetInvalidationSet::new();
+    let mut set = DocumentStylesheetSet::new_with_shared_invalidations(invalidations.clone());
+
+    // Flushing keeps the buffers around for the next change.
+    set.append_stylesheet(Some(&device), sheet.clone(), &guard);
+    let capacity = invalidations.borrow().capacity();
+    assert!(capacity > 0);
+    set.flush_without_invalidation();
+    assert!(invalidations.borrow().is_empty());
+    assert_eq!(invalidations.borrow().capacity(), capacity);
+    set.remove_stylesheet(Some(&device), sheet.clone(), &guard);
+    assert_eq!(invalidations.borrow().capacity(), capacity);
+    set.flush_without_invalidation();
+
+    // Unless they grew too large, or memory is tight.
+    set.append_stylesheet(Some(&device), large_sheet, &guard);
+    assert!(invalidations.borrow().capacity() > capacity);
+    set.flush_without_invalidation();
+    assert_eq!(invalidations.borrow().capacity(), 0);
+    set.append_stylesheet(Some(&device), sheet, &guard);
+    invalidations.borrow_mut().clear_and_release();
+    assert_eq!(invalidations.borrow().capacity(), 0);
+}
+
+#[test]
+fn test_reserve() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+
+    // Reserving space doesn't have any other effect.
+    set.reserve(Origin::Author, 100);
+    assert_eq!(set.sheet_count(Origin::Author), 0);
+    assert!(set.dirty_origins().is_empty());
+    set.append_stylesheets(None, (0..100).map(MockSheet::author), &guard);
+    assert_eq!(set.sheet_count(Origin::Author), 100);
+    assert!(set.is_consistent_for_testing());
+}
+
+#[test]
+fn test_attribute_selector_invalidations_are_scoped() {
+    let lock = SharedRwLock::new();
+    let hidden = sheet_from_css("[hidden] { color: red }", "", Origin::Author, &lock);
+    let themed = sheet_from_css(
+        "[data-theme=dark] .card { color: red }",
+        "",
+        Origin::Author,
+        &lock,
+    );
+    let guard = lock.read();
+    let device = device_with_width(800.);
+
+    let mut invalidations = StylesheetInvalidationSet::new();
+    invalidations.collect_invalidations_for(&device, &hidden, &guard);
+    assert!(!invalidations.is_empty());
+    assert!(!invalidations.is_fully_invalid());
+    invalidations.collect_invalidations_for(&device, &themed, &guard);
+    assert!(!invalidations.is_fully_invalid());
+}
+
+#[test]
+fn test_type_selector_invalidations_are_scoped() {
+    let lock = SharedRwLock::new();
+    let video = sheet_from_css(
+        "video { color: red } custom-element > span { color: red }",
+        "",
+        Origin::Author,
+        &lock,
+    );
+    let svg = sheet_from_css(
+        "@namespace svg url(http://www.w3.org/2000/svg); \
+         svg|rect { color: red } |rect { color: red }",
+        "",
+        Origin::Author,
+        &lock,
+    );
+    let guard = lock.read();
+    let device = device_with_width(800.);
+
+    let mut invalidations = StylesheetInvalidationSet::new();
+    invalidations.collect_invalidations_for(&device, &video, &guard);
+    assert!(!invalidations.is_empty());
+    assert!(!invalidations.is_fully_invalid());
+    invalidations.collect_invalidations_for(&device, &svg, &guard);
+    assert!(!invalidations.is_fully_invalid());
+}
+
+#[test]
+fn test_iter_with_state() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+    assert_eq!(
+        set.iter_with_state()
+            .map(|(s, origin, committed)| (s.id, origin, committed))
+            .collect::<Vec<_>>(),
+        vec![(0, Origin::Author, false)]
+    );
+
+    set.flush_without_invalidation();
+    set.append_stylesheet(None, MockSheet::author(1), &guard);
+    let state = set
+        .iter_with_state()
+        .map(|(s, _, committed)| (s.id, committed))
+        .collect::<Vec<_>>();
+    assert_eq!(state, vec![(0, true), (1, false)]);
+
+    // Looking at the state doesn't commit anything.
+    assert_eq!(set.is_committed(&MockSheet::author(1), &guard), Some(false));
+}
+
+#[test]
+fn test_max_scoped_invalidations() {
+    let lock = SharedRwLock::new();
+    let classes = sheet_from_css(
+        ".a { color: red } .b { color: red } .c { color: red }",
+        "",
+        Origin::Author,
+        &lock,
+    );
+    let universal = sheet_from_css("* { color: red }", "", Origin::Author, &lock);
+    let guard = lock.read();
+    let device = device_with_width(800.);
+
+    let mut invalidations = StylesheetInvalidationSet::new();
+    invalidations.set_max_scoped_invalidations(3);
+    invalidations.collect_invalidations_for(&device, &classes, &guard);
+    assert!(!invalidations.is_fully_invalid());
+    assert_eq!(invalidations.full_invalidation_reason(), None);
+
+    invalidations.clear();
+    invalidations.set_max_scoped_invalidations(2);
+    invalidations.collect_invalidations_for(&device, &classes, &guard);
+    assert_eq!(
+        invalidations.full_invalidation_reason(),
+        Some(FullInvalidationReason::TooManyInvalidations)
+    );
+
+    // The first reason sticks.
+    invalidations.collect_invalidations_for(&device, &universal, &guard);
+    assert_eq!(
+        invalidations.full_invalidation_reason(),
+        Some(FullInvalidationReason::TooManyInvalidations)
+    );
+
+    invalidations.clear();
+    invalidations.collect_invalidations_for(&device, &universal, &guard);
+    assert_eq!(
+        invalidations.full_invalidation_reason(),
+        Some(FullInvalidationReason::Selector)
+    );
+}
+
+#[test]
+fn test_invalidation_stats() {
+    assert!(!InvalidationStats::default().had_invalidations());
+    let elements = InvalidationStats {
+        elements_invalidated: 12431,
+        ..Default::default()
+    };
+    assert!(elements.had_invalidations());
+    let subtrees = InvalidationStats {
+        subtrees_invalidated: 1,
+        ..Default::default()
+    };
+    assert!(subtrees.had_invalidations());
+    let full = InvalidationStats {
+        fully_invalidated: true,
+        ..Default::default()
+    };
+    assert!(full.had_invalidations());
+}
+
+#[test]
+fn test_data_validity_merge() {
+    use self::DataValidity::*;
+
+    let expected = [
+        (Valid, Valid, Valid),
+        (Valid, CascadeInvalid, CascadeInvalid),
+        (Valid, InvalidationInvalid, InvalidationInvalid),
+        (Valid, FullyInvalid, FullyInvalid),
+        (CascadeInvalid, CascadeInvalid, CascadeInvalid),
+        (CascadeInvalid, InvalidationInvalid, FullyInvalid),
+        (CascadeInvalid, FullyInvalid, FullyInvalid),
+        (
+            InvalidationInvalid,
+            InvalidationInvalid,
+            InvalidationInvalid,
+        ),
+        (InvalidationInvalid, FullyInvalid, FullyInvalid),
+        (FullyInvalid, FullyInvalid, FullyInvalid),
+    ];
+    for &(a, b, merged) in &expected {
+        assert_eq!(a.merge(b), merged, "{:?} + {:?}", a, b);
+        assert_eq!(b.merge(a), merged, "{:?} + {:?}", b, a);
+    }
+
+    for &cascade in &[false, true] {
+        for &invalidation in &[false, true] {
+            let validity = DataValidity::from_rebuilds(cascade, invalidation);
+            assert_eq!(validity.needs_cascade_rebuild(), cascade);
+            assert_eq!(validity.needs_invalidation_rebuild(), invalidation);
+            assert_eq!(validity.needs_clear(), cascade || invalidation);
+        }
+    }
+}
+
+#[test]
+fn test_rebuild_kind_for_validity() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = AuthorStylesheetSet::new();
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+    complete_author_flush(&mut set);
+
+    let cases = [
+        (DataValidity::Valid, None),
+        (
+            DataValidity::CascadeInvalid,
+            Some(SheetRebuildKind::CascadeOnly),
+        ),
+        (
+            DataValidity::InvalidationInvalid,
+            Some(SheetRebuildKind::InvalidationOnly),
+        ),
+        (DataValidity::FullyInvalid, Some(SheetRebuildKind::Full)),
+    ];
+    for &(validity, expected_kind) in &cases {
+        set.force_dirty_with_validity(validity);
+        let mut flusher = set.flush_without_invalidation();
+        let sheets = flusher.sheets();
+        assert_eq!(sheets.data_validity(), validity);
+        let kinds = sheets
+            .map(|(s, kind)| {
+                assert_eq!(s.id, 0);
+                kind
+            })
+            .collect::<Vec<_>>();
+        flusher.complete();
+        let expected = expected_kind.into_iter().collect::<Vec<_>>();
+        assert_eq!(kinds, expected, "{:?}", validity);
+    }
+
+    assert!(SheetRebuildKind::Full.should_rebuild_cascade());
+    assert!(SheetRebuildKind::Full.should_rebuild_invalidation());
+    assert!(SheetRebuildKind::CascadeOnly.should_rebuild_cascade());
+    assert!(!SheetRebuildKind::CascadeOnly.should_rebuild_invalidation());
+    assert!(!SheetRebuildKind::InvalidationOnly.should_rebuild_cascade());
+    assert!(SheetRebuildKind::InvalidationOnly.should_rebuild_invalidation());
+}
+
+#[test]
+fn test_root_selector_invalidations_are_scoped() {
+    let lock = SharedRwLock::new();
+    let root = sheet_from_css(
+        ":root { --brand: blue } html { color: red } :root .card { color: red }",
+        "",
+        Origin::Author,
+        &lock,
+    );
+    let guard = lock.read();
+    let device = device_with_width(800.);
+
+    let mut invalidations = StylesheetInvalidationSet::new();
+    invalidations.collect_invalidations_for(&device, &root, &guard);
+    assert!(!invalidations.is_empty());
+    assert!(!invalidations.is_fully_invalid());
+}
+
+#[test]
+fn test_is_empty() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let mut set = DocumentStylesheetSet::new();
+    assert!(set.is_empty());
+    assert!(set.read_view().is_empty());
+
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+    assert!(!set.is_empty());
+    assert!(!set.read_view().is_empty());
+    assert_eq!(set.sheet_count(Origin::Author), 1);
+    assert_eq!(set.sheet_count(Origin::UserAgent), 0);
+
+    // Disabled sheets are still part of the set.
+    set.set_stylesheet_enabled(None, &MockSheet::author(0), false, &guard);
+    assert!(!set.is_empty());
+
+    set.remove_stylesheet(None, MockSheet::author(0), &guard);
+    assert!(set.is_empty());
+}
+
+#[test]
+fn test_pseudo_element_invalidations_are_scoped() {
+    let lock = SharedRwLock::new();
+    let pseudos = sheet_from_css(
+        ".card::before { content: \"*\" } \
+         .card::before:hover { color: red } \
+         .card:hover::after { color: red }",
+        "",
+        Origin::Author,
+        &lock,
+    );
+    let guard = lock.read();
+    let device = device_with_width(800.);
+
+    let mut invalidations = StylesheetInvalidationSet::new();
+    invalidations.collect_invalidations_for(&device, &pseudos, &guard);
+    assert!(!invalidations.is_empty());
+    assert!(!invalidations.is_fully_invalid());
+}
+
+#[test]
+fn test_at_rule_only_invalidations_are_scoped() {
+    let lock = SharedRwLock::new();
+    let font_face = sheet_from_css(
+        "@font-face { font-family: foo; src: local(foo) }",
+        "",
+        Origin::Author,
+        &lock,
+    );
+    let keyframes = sheet_from_css(
+        "@keyframes spin { to { opacity: 0 } }",
+        "",
+        Origin::Author,
+        &lock,
+    );
+    let guard = lock.read();
+    let device = device_with_width(800.);
+
+    // @font-face rules don't affect computed styles.
+    let mut invalidations = StylesheetInvalidationSet::new();
+    invalidations.collect_invalidations_for(&device, &font_face, &guard);
+    assert!(invalidations.is_empty());
+
+    // @keyframes rules only affect the elements that reference them.
+    invalidations.collect_invalidations_for(&device, &keyframes, &guard);
+    assert!(!invalidations.is_empty());
+    assert!(!invalidations.is_fully_invalid());
+
+    let mut invalidations = StylesheetInvalidationSet::new();
+    invalidations.set_max_scoped_invalidations(0);
+    invalidations.collect_invalidations_for(&device, &keyframes, &guard);
+    assert_eq!(
+        invalidations.full_invalidation_reason(),
+        Some(FullInvalidationReason::TooManyInvalidations)
+    );
+}
+
+#[test]
+fn test_lookups_dont_create_collections() {
+    let lock = SharedRwLock::new();
+    let guard = lock.read();
+    let user_sheet = || MockSheet::new(1, Origin::User);
+    let mut set = DocumentStylesheetSet::new();
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+
+    // Duplicates and missing sheets are found in the index of their origin,
+    // without creating a collection for origins that have none.
+    set.append_stylesheet(None, MockSheet::author(0), &guard);
+    assert!(!set.remove_stylesheet(None, user_sheet(), &guard));
+    assert!(!set.move_stylesheet(&user_sheet(), 0, &guard));
+    set.set_stylesheet_enabled(None, &user_sheet(), false, &guard);
+
+    // Forcing an origin without a collection dirty is a no-op.
+    set.force_dirty_origin(Origin::User);
+    assert_eq!(set.dirty_origins(), OriginSet::ORIGIN_AUTHOR);
+    assert_eq!(set.len(), 1);
+}